@@ -0,0 +1,445 @@
+//! What each operation actually cost, worked out from the journal. Gas is paid in ETH on Eth and
+//! in xDai on xDai, so gas costs are given in both ETH and Dai terms using the ETH price the
+//! journal kept for the operation.
+
+use crate::amount::EthAmount;
+use crate::journal::{format_hash, Journal, JournalEntry, Operation, TransferStatus, TxCosts};
+use crate::price::PRICE_SCALE;
+use crate::TokenBridge;
+use clarity::Address;
+use failure::bail;
+use failure::Error;
+use futures::Future;
+use num256::Uint256;
+use std::collections::BTreeMap;
+use web30::client::Web3;
+
+const BASIS_POINTS: u64 = 10_000;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct OperationCost {
+    pub id: u64,
+    pub operation: Operation,
+    /// The token involved if it isn't Dai
+    pub token: Option<Address>,
+    pub tx_hash: Option<Uint256>,
+    /// Unix timestamp in seconds
+    pub finished_at: u64,
+    /// What went into the operation
+    pub amount: Uint256,
+    pub quote: Option<Uint256>,
+    pub received: Uint256,
+    /// How far short of the quote we came, in the units received
+    pub slippage: Option<Uint256>,
+    pub slippage_basis_points: Option<u64>,
+    pub gas_cost_eth: Option<Uint256>,
+    pub gas_cost_dai: Option<Uint256>,
+    /// What the bridge keeps of a Dai deposit or withdrawal or a token deposit at its configured
+    /// fee, in the units bridged. The amount credited on the other chain isn't journaled so this can't be
+    /// read off the entry.
+    pub bridge_fee: Option<Uint256>,
+}
+
+impl OperationCost {
+    /// The costs of a finished operation, `None` for ones that didn't succeed and for plain ETH
+    /// transfers, which aren't conversions
+    pub fn from_entry(entry: &JournalEntry, bridge_fee_basis_points: u64) -> Option<OperationCost> {
+        if entry.status != TransferStatus::Succeeded || entry.operation == Operation::EthTransfer {
+            return None;
+        }
+        let received = entry.received.clone()?;
+        let zero: Uint256 = 0u8.into();
+
+        let (slippage, slippage_basis_points) = match entry.quote {
+            Some(ref quote) if *quote > zero => {
+                let slippage = if received < *quote {
+                    quote.clone() - received.clone()
+                } else {
+                    zero.clone()
+                };
+                let basis_points = slippage.clone() * BASIS_POINTS.into() / quote.clone();
+                (
+                    Some(slippage),
+                    Some(basis_points.to_string().parse().unwrap_or(u64::MAX)),
+                )
+            }
+            _ => (None, None),
+        };
+
+        let bridge_fee = match entry.operation {
+            Operation::DaiToXdaiDeposit
+            | Operation::TokenToXdaiDeposit
+            | Operation::XdaiToDaiWithdrawal => {
+                Some(entry.amount.clone() * bridge_fee_basis_points.into() / BASIS_POINTS.into())
+            }
+            _ => None,
+        };
+
+        let (gas_cost_eth, gas_cost_dai) = gas_costs(entry);
+
+        Some(OperationCost {
+            id: entry.id,
+            operation: entry.operation,
+            token: entry.token,
+            tx_hash: entry.tx_hash.clone(),
+            finished_at: entry.updated_at,
+            amount: entry.amount.clone(),
+            quote: entry.quote.clone(),
+            received,
+            slippage,
+            slippage_basis_points,
+            gas_cost_eth,
+            gas_cost_dai,
+            bridge_fee,
+        })
+    }
+}
+
+/// Gas used times gas price in ETH and in Dai, whichever chain it was paid on
+fn gas_costs(entry: &JournalEntry) -> (Option<Uint256>, Option<Uint256>) {
+    let paid = match (&entry.gas_used, &entry.gas_price) {
+        (Some(gas_used), Some(gas_price)) => gas_used.clone() * gas_price.clone(),
+        _ => return (None, None),
+    };
+    let zero: Uint256 = 0u8.into();
+    let price = entry.eth_price.clone().filter(|price| *price > zero);
+
+    match entry.operation.chain() {
+        "xdai" => (
+            price.map(|price| paid.clone() * PRICE_SCALE.into() / price),
+            Some(paid),
+        ),
+        _ => (
+            Some(paid.clone()),
+            price.map(|price| paid * price / PRICE_SCALE.into()),
+        ),
+    }
+}
+
+/// Sums for one kind of operation on one token, amounts are only added up where they are in
+/// the same units. Approvals only add up their gas.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct OperationTotals {
+    pub operation: Operation,
+    pub token: Option<Address>,
+    pub count: u64,
+    pub amount: Uint256,
+    pub received: Uint256,
+    pub slippage: Uint256,
+    pub gas_cost_eth: Uint256,
+    pub gas_cost_dai: Uint256,
+    pub bridge_fees: Uint256,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CostReport {
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+    pub operations: Vec<OperationCost>,
+    pub totals: Vec<OperationTotals>,
+    pub total_gas_cost_eth: Uint256,
+    pub total_gas_cost_dai: Uint256,
+}
+
+impl CostReport {
+    /// Every operation started between `since` and `until`, both inclusive
+    pub fn from_journal(
+        journal: &Journal,
+        since: Option<u64>,
+        until: Option<u64>,
+        bridge_fee_basis_points: u64,
+    ) -> Result<CostReport, Error> {
+        let entries = journal.query(Some(TransferStatus::Succeeded), since, until)?;
+        Ok(CostReport::from_entries(
+            &entries,
+            since,
+            until,
+            bridge_fee_basis_points,
+        ))
+    }
+
+    pub fn from_entries(
+        entries: &[JournalEntry],
+        since: Option<u64>,
+        until: Option<u64>,
+        bridge_fee_basis_points: u64,
+    ) -> CostReport {
+        let operations: Vec<OperationCost> = entries
+            .iter()
+            .filter_map(|entry| OperationCost::from_entry(entry, bridge_fee_basis_points))
+            .collect();
+
+        let zero = || -> Uint256 { 0u8.into() };
+        let mut totals: BTreeMap<(String, Option<Address>), OperationTotals> = BTreeMap::new();
+        for cost in operations.iter() {
+            let key = (format!("{:?}", cost.operation), cost.token);
+            let total = totals.entry(key).or_insert_with(|| OperationTotals {
+                operation: cost.operation,
+                token: cost.token,
+                count: 0,
+                amount: zero(),
+                received: zero(),
+                slippage: zero(),
+                gas_cost_eth: zero(),
+                gas_cost_dai: zero(),
+                bridge_fees: zero(),
+            });
+            total.count += 1;
+            // An approval's amount is often the max allowance and would overflow the sum, only
+            // its gas counts
+            if !cost.operation.is_approval() {
+                total.amount = total.amount.clone() + cost.amount.clone();
+                total.received = total.received.clone() + cost.received.clone();
+            }
+            total.slippage = total.slippage.clone() + cost.slippage.clone().unwrap_or_else(zero);
+            total.gas_cost_eth =
+                total.gas_cost_eth.clone() + cost.gas_cost_eth.clone().unwrap_or_else(zero);
+            total.gas_cost_dai =
+                total.gas_cost_dai.clone() + cost.gas_cost_dai.clone().unwrap_or_else(zero);
+            total.bridge_fees =
+                total.bridge_fees.clone() + cost.bridge_fee.clone().unwrap_or_else(zero);
+        }
+        let totals: Vec<OperationTotals> = totals.into_values().collect();
+
+        CostReport {
+            since,
+            until,
+            total_gas_cost_eth: totals
+                .iter()
+                .fold(zero(), |sum, total| sum + total.gas_cost_eth.clone()),
+            total_gas_cost_dai: totals
+                .iter()
+                .fold(zero(), |sum, total| sum + total.gas_cost_dai.clone()),
+            operations,
+            totals,
+        }
+    }
+
+    /// One line per operation, amounts in wei
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from(
+            "id,operation,token,tx_hash,finished_at,amount,quote,received,slippage,\
+             slippage_basis_points,gas_cost_eth,gas_cost_dai,bridge_fee\n",
+        );
+        for cost in self.operations.iter() {
+            let fields = [
+                cost.id.to_string(),
+                format!("{:?}", cost.operation),
+                optional(&cost.token),
+                cost.tx_hash.as_ref().map(format_hash).unwrap_or_default(),
+                cost.finished_at.to_string(),
+                cost.amount.to_string(),
+                optional(&cost.quote),
+                cost.received.to_string(),
+                optional(&cost.slippage),
+                optional(&cost.slippage_basis_points),
+                optional(&cost.gas_cost_eth),
+                optional(&cost.gas_cost_dai),
+                optional(&cost.bridge_fee),
+            ];
+            csv.push_str(&fields.join(","));
+            csv.push('\n');
+        }
+        csv
+    }
+}
+
+fn optional<T: ToString>(value: &Option<T>) -> String {
+    value.as_ref().map(ToString::to_string).unwrap_or_default()
+}
+
+impl TokenBridge {
+    /// Costs of the operations in the journal started between `since` and `until`
+    pub fn get_cost_report(
+        &self,
+        since: Option<u64>,
+        until: Option<u64>,
+    ) -> Result<CostReport, Error> {
+        match self.journal {
+            Some(ref journal) => {
+                CostReport::from_journal(journal, since, until, self.bridge_fee_basis_points)
+            }
+            None => bail!("Costs are worked out from the journal and there isn't one"),
+        }
+    }
+
+    /// Looks up what a mined transaction cost, anything that can't be found is left out rather
+    /// than failing the operation that sent it. The ETH price is the Uniswap price of one ETH.
+    pub(crate) fn get_tx_costs(
+        &self,
+        web3: Web3,
+        tx_hash: Uint256,
+        with_prices: bool,
+    ) -> Box<dyn Future<Item = TxCosts, Error = Error>> {
+        let gas_used = self
+            .retry_read("eth_getTransactionReceipt", {
+                let web3 = web3.clone();
+                let tx_hash = tx_hash.clone();
+                move || web3.eth_get_transaction_receipt(tx_hash.clone())
+            })
+            .then(|receipt| -> Result<Option<Uint256>, Error> {
+                match receipt {
+                    Ok(Some(receipt)) => Ok(Some(receipt.gas_used)),
+                    _ => Ok(None),
+                }
+            });
+        if !with_prices {
+            return Box::new(gas_used.map(|gas_used| TxCosts {
+                gas_used,
+                ..Default::default()
+            }));
+        }
+
+        let gas_price = self
+            .retry_read("eth_getTransactionByHash", move || {
+                web3.eth_get_transaction_by_hash(tx_hash.clone())
+            })
+            .then(|tx| -> Result<Option<Uint256>, Error> {
+                match tx {
+                    Ok(Some(tx)) => Ok(Some(tx.gas_price)),
+                    _ => Ok(None),
+                }
+            });
+        let eth_price = self
+            .eth_to_dai_price(EthAmount::from_wei(PRICE_SCALE.into()))
+            .then(|price| -> Result<Option<Uint256>, Error> { Ok(price.ok()) });
+
+        Box::new(
+            gas_used
+                .join3(gas_price, eth_price)
+                .map(|(gas_used, gas_price, eth_price)| TxCosts {
+                    gas_used,
+                    gas_price,
+                    eth_price,
+                }),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use num::Bounded;
+
+    fn entry(operation: Operation, amount: u64, quote: Option<u64>, received: u64) -> JournalEntry {
+        JournalEntry {
+            id: 1,
+            operation,
+            status: TransferStatus::Succeeded,
+            amount: amount.into(),
+            recipient: None,
+            token: None,
+            quote: quote.map(Uint256::from),
+            received: Some(received.into()),
+            tx_hash: Some(1u8.into()),
+            gas_used: Some(50_000u64.into()),
+            gas_price: Some(20_000_000_000u64.into()),
+            // 200 Dai per ETH
+            eth_price: Some(Uint256::from(200u64) * PRICE_SCALE.into()),
+            error: None,
+            started_at: 100,
+            updated_at: 160,
+        }
+    }
+
+    #[test]
+    fn test_operation_cost() {
+        let swap =
+            OperationCost::from_entry(&entry(Operation::EthToDaiSwap, 1000, Some(2000), 1990), 10)
+                .unwrap();
+        assert_eq!(swap.slippage, Some(10u64.into()));
+        assert_eq!(swap.slippage_basis_points, Some(50));
+        assert_eq!(swap.gas_cost_eth, Some(1_000_000_000_000_000u64.into()));
+        assert_eq!(swap.gas_cost_dai, Some(200_000_000_000_000_000u64.into()));
+        assert_eq!(swap.bridge_fee, None);
+
+        let withdrawal =
+            OperationCost::from_entry(&entry(Operation::XdaiToDaiWithdrawal, 1000, None, 1000), 10)
+                .unwrap();
+        assert_eq!(withdrawal.slippage, None);
+        // 10 basis points of 1000, whatever the journal says was received
+        assert_eq!(withdrawal.bridge_fee, Some(1u64.into()));
+        assert_eq!(
+            withdrawal.gas_cost_dai,
+            Some(1_000_000_000_000_000u64.into())
+        );
+        assert_eq!(withdrawal.gas_cost_eth, Some(5_000_000_000_000u64.into()));
+
+        let mut failed = entry(Operation::EthToDaiSwap, 1000, None, 0);
+        failed.status = TransferStatus::Failed;
+        assert_eq!(OperationCost::from_entry(&failed, 10), None);
+        assert_eq!(
+            OperationCost::from_entry(&entry(Operation::EthTransfer, 1000, None, 1000), 10),
+            None
+        );
+    }
+
+    #[test]
+    fn test_bridge_fee() {
+        let mut deposit = entry(Operation::TokenToXdaiDeposit, 20_000, None, 20_000);
+        deposit.token = Some(Address::default());
+        let deposit = OperationCost::from_entry(&deposit, 25).unwrap();
+        assert_eq!(deposit.bridge_fee, Some(50u64.into()));
+        assert_eq!(deposit.gas_cost_eth, Some(1_000_000_000_000_000u64.into()));
+
+        // The receipt of the withdrawal on xDai, its gas was paid in xDai at 10 gwei
+        let mut withdrawal = entry(Operation::XdaiToDaiWithdrawal, 20_000, None, 19_950);
+        withdrawal.tx_hash = Some(2u8.into());
+        withdrawal.gas_used = Some(100_000u64.into());
+        withdrawal.gas_price = Some(10_000_000_000u64.into());
+        let withdrawal = OperationCost::from_entry(&withdrawal, 25).unwrap();
+        assert_eq!(withdrawal.tx_hash, Some(2u8.into()));
+        assert_eq!(withdrawal.received, 19_950u64.into());
+        assert_eq!(withdrawal.bridge_fee, Some(50u64.into()));
+        assert_eq!(
+            withdrawal.gas_cost_dai,
+            Some(1_000_000_000_000_000u64.into())
+        );
+        assert_eq!(withdrawal.gas_cost_eth, Some(5_000_000_000_000u64.into()));
+    }
+
+    #[test]
+    fn test_cost_report() {
+        let entries = vec![
+            entry(Operation::EthToDaiSwap, 1000, Some(2000), 1990),
+            entry(Operation::EthToDaiSwap, 1000, Some(2000), 2000),
+            entry(Operation::DaiToXdaiDeposit, 3990, None, 3990),
+        ];
+        let mut approvals = vec![
+            entry(Operation::ApproveUniswapDai, 0, None, 0),
+            entry(Operation::ApproveUniswapDai, 0, None, 0),
+        ];
+        for approval in approvals.iter_mut() {
+            approval.amount = Uint256::max_value();
+            approval.received = Some(Uint256::max_value());
+        }
+        let approval_report = CostReport::from_entries(&approvals, None, None, 0);
+        assert_eq!(approval_report.totals[0].count, 2);
+        assert_eq!(approval_report.totals[0].amount, 0u8.into());
+        assert_eq!(
+            approval_report.total_gas_cost_eth,
+            2_000_000_000_000_000u64.into()
+        );
+
+        let report = CostReport::from_entries(&entries, Some(0), None, 0);
+
+        assert_eq!(report.operations.len(), 3);
+        assert_eq!(report.totals.len(), 2);
+        let swaps = report
+            .totals
+            .iter()
+            .find(|total| total.operation == Operation::EthToDaiSwap)
+            .unwrap();
+        assert_eq!(swaps.count, 2);
+        assert_eq!(swaps.received, 3990u64.into());
+        assert_eq!(swaps.slippage, 10u64.into());
+        assert_eq!(report.total_gas_cost_eth, 3_000_000_000_000_000u64.into());
+
+        let csv = report.to_csv();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].starts_with("id,operation,token,tx_hash"));
+        assert_eq!(lines[1].split(',').count(), 13);
+        assert!(lines[1].contains(",EthToDaiSwap,,0x0000"));
+    }
+}
@@ -0,0 +1,149 @@
+//! How much Dai Uniswap is allowed to move out of our account. By default it gets an unlimited
+//! allowance the first time we sell Dai, which leaves every Dai we hold exposed to the Uniswap
+//! contract, so this can be narrowed to just what each swap needs or to a fixed cap.
+
+use crate::amount::DaiAmount;
+use crate::journal::Operation;
+use crate::TokenBridge;
+use clarity::Address;
+use failure::bail;
+use failure::Error;
+use futures::Future;
+use num::Bounded;
+use num256::Uint256;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum AllowancePolicy {
+    /// Approve the maximum amount once and never again
+    #[default]
+    Unlimited,
+    /// Approve exactly what each swap sells
+    Exact,
+    /// Approve up to `cap` at a time, swaps larger than the cap are refused
+    Capped { cap: Uint256 },
+}
+
+impl AllowancePolicy {
+    /// What to approve when the current allowance doesn't cover `amount`
+    pub fn allowance_for(&self, amount: &Uint256) -> Result<Uint256, Error> {
+        match self {
+            AllowancePolicy::Unlimited => Ok(Uint256::max_value()),
+            AllowancePolicy::Exact => Ok(amount.clone()),
+            AllowancePolicy::Capped { cap } => {
+                if amount > cap {
+                    bail!(
+                        "Selling {} needs more than the Uniswap allowance cap of {}",
+                        amount,
+                        cap
+                    );
+                }
+                Ok(cap.clone())
+            }
+        }
+    }
+}
+
+impl TokenBridge {
+    /// True if Uniswap is allowed to move at least `amount` of our Dai
+    pub fn uniswap_dai_allowance_covers(
+        &self,
+        amount: DaiAmount,
+    ) -> Box<dyn Future<Item = bool, Error = Error>> {
+        Box::new(
+            self.get_dai_allowance(self.uniswap_address)
+                .map(move |allowance| &allowance >= amount.wei()),
+        )
+    }
+
+    /// Sets the Uniswap allowance to exactly `amount`
+    pub fn approve_uniswap_dai_amount(
+        &self,
+        amount: DaiAmount,
+        timeout: Duration,
+    ) -> Box<dyn Future<Item = (), Error = Error>> {
+        self.set_dai_allowance(
+            Operation::ApproveUniswapDai,
+            self.uniswap_address,
+            amount.into_wei(),
+            timeout,
+        )
+    }
+
+    /// Takes away Uniswap's allowance to move our Dai
+    pub fn revoke_uniswap_dai_transfers(
+        &self,
+        timeout: Duration,
+    ) -> Box<dyn Future<Item = (), Error = Error>> {
+        self.approve_uniswap_dai_amount(DaiAmount::zero(), timeout)
+    }
+
+    /// Makes sure Uniswap can move `amount` of our Dai, approving more according to
+    /// `uniswap_allowance` if it can't
+    pub(crate) fn ensure_uniswap_dai_allowance(
+        &self,
+        amount: Uint256,
+        timeout: Duration,
+    ) -> Box<dyn Future<Item = (), Error = Error>> {
+        let target = match self.uniswap_allowance.allowance_for(&amount) {
+            Ok(target) => target,
+            Err(e) => return Box::new(futures::future::err(e)),
+        };
+        let salf = self.clone();
+
+        Box::new(
+            self.get_dai_allowance(self.uniswap_address)
+                .and_then(move |allowance| {
+                    trace!("uniswap allowance {}", allowance);
+                    if allowance >= amount {
+                        Box::new(futures::future::ok(()))
+                            as Box<dyn Future<Item = (), Error = Error>>
+                    } else if salf.dai_permit
+                        && salf.uniswap_allowance == AllowancePolicy::Unlimited
+                    {
+                        // Permits can only grant an unlimited allowance
+                        salf.permit_uniswap_dai_transfers(timeout)
+                    } else {
+                        salf.approve_uniswap_dai_amount(DaiAmount::from_wei(target), timeout)
+                    }
+                }),
+        )
+    }
+
+    /// `set_token_allowance` for Dai
+    pub(crate) fn set_dai_allowance(
+        &self,
+        operation: Operation,
+        spender: Address,
+        amount: Uint256,
+        timeout: Duration,
+    ) -> Box<dyn Future<Item = (), Error = Error>> {
+        self.set_token_allowance(&self.dai_token(), operation, spender, amount, timeout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allowance_for() {
+        let amount: Uint256 = 1000u64.into();
+
+        assert_eq!(
+            AllowancePolicy::Unlimited.allowance_for(&amount).unwrap(),
+            Uint256::max_value()
+        );
+        assert_eq!(
+            AllowancePolicy::Exact.allowance_for(&amount).unwrap(),
+            amount
+        );
+
+        let capped = AllowancePolicy::Capped {
+            cap: 5000u64.into(),
+        };
+        assert_eq!(capped.allowance_for(&amount).unwrap(), 5000u64.into());
+        assert!(capped.allowance_for(&6000u64.into()).is_err());
+    }
+}
@@ -0,0 +1,96 @@
+//! Restricts who the bridge can send funds to other than itself, so a bad payout request can't
+//! send anything to an address we don't know.
+
+use crate::metrics::FailureReason;
+use crate::TokenBridge;
+use clarity::Address;
+use failure::format_err;
+use failure::Error;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AllowedRecipient {
+    pub address: Address,
+    /// Who the address belongs to, only used in logs and errors
+    pub label: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RecipientAllowlist {
+    pub recipients: Vec<AllowedRecipient>,
+}
+
+impl RecipientAllowlist {
+    pub fn new(recipients: Vec<AllowedRecipient>) -> RecipientAllowlist {
+        RecipientAllowlist { recipients }
+    }
+
+    /// `None` if the address is not on the allowlist
+    pub fn label(&self, address: Address) -> Option<&str> {
+        self.recipients
+            .iter()
+            .find(|recipient| recipient.address == address)
+            .map(|recipient| recipient.label.as_str())
+    }
+}
+
+impl TokenBridge {
+    /// Fails if there is an allowlist and `to` isn't on it. Sending to `own_address` is always
+    /// allowed.
+    pub(crate) fn check_recipient(&self, to: Address) -> Result<(), Error> {
+        let allowlist = match self.recipient_allowlist {
+            Some(ref allowlist) => allowlist,
+            None => return Ok(()),
+        };
+        if to == self.own_address {
+            return Ok(());
+        }
+
+        match allowlist.label(to) {
+            Some(label) => {
+                info!("Sending to {} ({})", label, to);
+                Ok(())
+            }
+            None => Err(FailureReason::RecipientNotAllowed
+                .refuse(format_err!("{} is not on the recipient allowlist", to))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clarity::PrivateKey;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_allowlist() {
+        let customer = Address::from_str("0x79AE13432950bF5CDC3499f8d4Cf5963c3F0d42c").unwrap();
+        let stranger = Address::from_str("0x4aa42145Aa6Ebf72e164C9bBC74fbD3788045016").unwrap();
+        let allowlist = RecipientAllowlist::new(vec![AllowedRecipient {
+            address: customer,
+            label: "customer".to_string(),
+        }]);
+
+        assert_eq!(allowlist.label(customer), Some("customer"));
+        assert_eq!(allowlist.label(stranger), None);
+
+        let own_address = Address::from_str("0x6d943740746934b2f5D9c9E6Cb1908758A42452f").unwrap();
+        let mut bridge = TokenBridge::new(
+            Address::from_str("0x09cabEC1eAd1c0Ba254B09efb3EE13841712bE14").unwrap(),
+            Address::from_str("0x7301CFA0e1756B71869E93d4e4Dca5c7d0eb0AA6").unwrap(),
+            Address::from_str("0x4aa42145Aa6Ebf72e164C9bBC74fbD3788045016").unwrap(),
+            Address::from_str("0x89d24A6b4CcB1B6fAA2625fE562bDD9a23260359").unwrap(),
+            own_address,
+            PrivateKey::from_str(&"11".repeat(32)).unwrap(),
+            "http://localhost:8545".to_string(),
+            "http://localhost:8546".to_string(),
+        );
+        // Anywhere is allowed without an allowlist
+        assert!(bridge.check_recipient(stranger).is_ok());
+
+        bridge.recipient_allowlist = Some(allowlist);
+        assert!(bridge.check_recipient(own_address).is_ok());
+        assert!(bridge.check_recipient(customer).is_ok());
+        assert!(bridge.check_recipient(stranger).is_err());
+    }
+}
@@ -0,0 +1,290 @@
+//! Amounts that know which asset they are in, so wei of ETH can't be passed where wei of Dai is
+//! expected. They parse from and display as decimal strings without going through floats.
+
+use crate::price::PRICE_SCALE;
+use failure::bail;
+use failure::Error;
+use num256::Uint256;
+use std::cmp::Ordering;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::marker::PhantomData;
+use std::ops::{Add, Sub};
+use std::str::FromStr;
+
+/// An asset amounts can be denominated in
+pub trait Denomination {
+    const SYMBOL: &'static str;
+    const DECIMALS: usize;
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Eth;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Dai;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Xdai;
+
+impl Denomination for Eth {
+    const SYMBOL: &'static str = "ETH";
+    const DECIMALS: usize = 18;
+}
+
+impl Denomination for Dai {
+    const SYMBOL: &'static str = "DAI";
+    const DECIMALS: usize = 18;
+}
+
+impl Denomination for Xdai {
+    const SYMBOL: &'static str = "xDAI";
+    const DECIMALS: usize = 18;
+}
+
+pub type EthAmount = Amount<Eth>;
+pub type DaiAmount = Amount<Dai>;
+pub type XdaiAmount = Amount<Xdai>;
+
+#[derive(Serialize, Deserialize)]
+#[serde(transparent, bound = "")]
+pub struct Amount<D: Denomination> {
+    wei: Uint256,
+    #[serde(skip)]
+    denomination: PhantomData<D>,
+}
+
+impl<D: Denomination> Amount<D> {
+    pub fn from_wei(wei: Uint256) -> Amount<D> {
+        Amount {
+            wei,
+            denomination: PhantomData,
+        }
+    }
+
+    pub fn zero() -> Amount<D> {
+        Amount::from_wei(0u8.into())
+    }
+
+    pub fn wei(&self) -> &Uint256 {
+        &self.wei
+    }
+
+    pub fn into_wei(self) -> Uint256 {
+        self.wei
+    }
+
+    /// `None` if `other` is larger
+    pub fn checked_sub(&self, other: &Amount<D>) -> Option<Amount<D>> {
+        if other.wei > self.wei {
+            None
+        } else {
+            Some(Amount::from_wei(self.wei.clone() - other.wei.clone()))
+        }
+    }
+
+    /// Formats with at most `precision` decimal places, anything past that is cut off rather
+    /// than rounded so an amount is never shown as more than it is
+    pub fn format(&self, precision: usize) -> String {
+        let digits = self.wei.to_string();
+        let digits = if digits.len() <= D::DECIMALS {
+            format!("{}{}", "0".repeat(D::DECIMALS + 1 - digits.len()), digits)
+        } else {
+            digits
+        };
+        let (whole, fraction) = digits.split_at(digits.len() - D::DECIMALS);
+        let fraction = fraction[..precision.min(D::DECIMALS)].trim_end_matches('0');
+        if fraction.is_empty() {
+            whole.to_string()
+        } else {
+            format!("{}.{}", whole, fraction)
+        }
+    }
+
+    /// Formats with the symbol, for example `1.5 ETH`
+    pub fn format_with_symbol(&self, precision: usize) -> String {
+        format!("{} {}", self.format(precision), D::SYMBOL)
+    }
+}
+
+impl EthAmount {
+    /// What this much ETH is worth in Dai at `price`, Dai per ETH scaled by `PRICE_SCALE`
+    pub fn to_dai(&self, price: &Uint256) -> DaiAmount {
+        Amount::from_wei(self.wei.clone() * price.clone() / PRICE_SCALE.into())
+    }
+}
+
+impl DaiAmount {
+    /// What this much Dai is worth in ETH at `price`, Dai per ETH scaled by `PRICE_SCALE`
+    pub fn to_eth(&self, price: &Uint256) -> EthAmount {
+        Amount::from_wei(self.wei.clone() * PRICE_SCALE.into() / price.clone())
+    }
+
+    /// The bridge moves Dai and xDai one to one
+    pub fn to_xdai(&self) -> XdaiAmount {
+        Amount::from_wei(self.wei.clone())
+    }
+}
+
+impl XdaiAmount {
+    /// The bridge moves Dai and xDai one to one
+    pub fn to_dai(&self) -> DaiAmount {
+        Amount::from_wei(self.wei.clone())
+    }
+}
+
+/// Parses a decimal string like `1.5` or `0.000000000000000001`, more decimal places than the
+/// asset has are an error rather than being rounded away
+impl<D: Denomination> FromStr for Amount<D> {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Amount<D>, Error> {
+        let s = s.trim();
+        let (whole, fraction) = match s.find('.') {
+            Some(point) => (&s[..point], &s[point + 1..]),
+            None => (s, ""),
+        };
+        if whole.is_empty() && fraction.is_empty() {
+            bail!("No amount given");
+        }
+        if !whole
+            .chars()
+            .chain(fraction.chars())
+            .all(|c| c.is_ascii_digit())
+        {
+            bail!("Invalid amount {}", s);
+        }
+        if fraction.len() > D::DECIMALS {
+            bail!(
+                "{} has more than the {} decimal places {} has",
+                s,
+                D::DECIMALS,
+                D::SYMBOL
+            );
+        }
+
+        let digits = format!(
+            "{}{}{}",
+            whole,
+            fraction,
+            "0".repeat(D::DECIMALS - fraction.len())
+        );
+        match Uint256::from_str(&digits) {
+            Ok(wei) => Ok(Amount::from_wei(wei)),
+            Err(_) => bail!("Amount {} is too large", s),
+        }
+    }
+}
+
+impl<D: Denomination> fmt::Display for Amount<D> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.format(D::DECIMALS))
+    }
+}
+
+impl<D: Denomination> fmt::Debug for Amount<D> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.format_with_symbol(D::DECIMALS))
+    }
+}
+
+impl<D: Denomination> Clone for Amount<D> {
+    fn clone(&self) -> Amount<D> {
+        Amount::from_wei(self.wei.clone())
+    }
+}
+
+impl<D: Denomination> PartialEq for Amount<D> {
+    fn eq(&self, other: &Amount<D>) -> bool {
+        self.wei == other.wei
+    }
+}
+
+impl<D: Denomination> Eq for Amount<D> {}
+
+impl<D: Denomination> PartialOrd for Amount<D> {
+    fn partial_cmp(&self, other: &Amount<D>) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<D: Denomination> Ord for Amount<D> {
+    fn cmp(&self, other: &Amount<D>) -> Ordering {
+        self.wei.cmp(&other.wei)
+    }
+}
+
+impl<D: Denomination> Hash for Amount<D> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.wei.hash(state)
+    }
+}
+
+impl<D: Denomination> Add for Amount<D> {
+    type Output = Amount<D>;
+
+    fn add(self, other: Amount<D>) -> Amount<D> {
+        Amount::from_wei(self.wei + other.wei)
+    }
+}
+
+/// Panics on underflow like `Uint256` does, use `checked_sub` if that's possible
+impl<D: Denomination> Sub for Amount<D> {
+    type Output = Amount<D>;
+
+    fn sub(self, other: Amount<D>) -> Amount<D> {
+        Amount::from_wei(self.wei - other.wei)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_amount() {
+        let amount: EthAmount = "1.5".parse().unwrap();
+        assert_eq!(amount.wei(), &1_500_000_000_000_000_000u64.into());
+
+        let amount: DaiAmount = "0.000000000000000001".parse().unwrap();
+        assert_eq!(amount.wei(), &1u8.into());
+        assert_eq!("42".parse::<DaiAmount>().unwrap().format(2), "42");
+        assert_eq!(".5".parse::<XdaiAmount>().unwrap().format(2), "0.5");
+
+        assert!("0.0000000000000000001".parse::<EthAmount>().is_err());
+        assert!("1.2.3".parse::<EthAmount>().is_err());
+        assert!("-1".parse::<EthAmount>().is_err());
+        assert!("".parse::<EthAmount>().is_err());
+    }
+
+    #[test]
+    fn test_format_amount() {
+        let amount: EthAmount = "1234.567890123456789".parse().unwrap();
+        assert_eq!(amount.to_string(), "1234.567890123456789");
+        assert_eq!(amount.format(2), "1234.56");
+        assert_eq!(amount.format(0), "1234");
+        assert_eq!(amount.format_with_symbol(3), "1234.567 ETH");
+
+        assert_eq!(EthAmount::zero().to_string(), "0");
+        assert_eq!(DaiAmount::from_wei(1u8.into()).format(2), "0");
+        assert_eq!(
+            DaiAmount::from_wei(1u8.into()).to_string(),
+            "0.000000000000000001"
+        );
+    }
+
+    #[test]
+    fn test_convert_amount() {
+        let price: Uint256 = "200".parse::<DaiAmount>().unwrap().into_wei();
+        let eth: EthAmount = "1.5".parse().unwrap();
+        let dai = eth.to_dai(&price);
+        assert_eq!(dai, "300".parse().unwrap());
+        assert_eq!(dai.to_eth(&price), eth);
+        assert_eq!(dai.to_xdai().to_dai(), dai);
+
+        let less: DaiAmount = "100".parse().unwrap();
+        assert_eq!(dai.checked_sub(&less), Some("200".parse().unwrap()));
+        assert_eq!(less.checked_sub(&dai), None);
+        assert_eq!(less.clone() + less, "200".parse().unwrap());
+    }
+}
@@ -0,0 +1,165 @@
+use crate::allowance::AllowancePolicy;
+use crate::allowlist::{AllowedRecipient, RecipientAllowlist};
+use crate::journal::Journal;
+use crate::limits::{SpendingGuard, SpendingLimits};
+use crate::omnibridge::OmniBridge;
+use crate::price::PriceSanityCheck;
+use crate::retry::RetryPolicy;
+use crate::token::Token;
+use crate::twap::TwapConfig;
+use crate::TokenBridge;
+use clarity::{Address, PrivateKey};
+use failure::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Everything needed to construct a `TokenBridge`, in a form that can be read from a TOML file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TokenBridgeConfig {
+    pub uniswap_address: Address,
+    /// This is the address of the xDai bridge on xDai
+    pub xdai_home_bridge_address: Address,
+    /// This is the address of the xDai bridge on Eth
+    pub xdai_foreign_bridge_address: Address,
+    /// This is the address of the Dai token contract on Eth
+    pub foreign_dai_contract_address: Address,
+    pub own_address: Address,
+    pub secret: PrivateKey,
+    pub eth_full_node_url: String,
+    pub xdai_full_node_url: String,
+    /// Where to keep the transfer journal, no journal is kept if this is not set
+    #[serde(default)]
+    pub journal_path: Option<PathBuf>,
+    /// Where to keep jobs queued with `schedule`, needed to queue or run jobs
+    #[serde(default)]
+    pub job_queue_path: Option<PathBuf>,
+    /// Refuse swaps that move the Uniswap price by more than this many basis points
+    #[serde(default)]
+    pub max_price_impact: Option<u64>,
+    /// Abort swaps when the Uniswap quote disagrees with an independent price source
+    #[serde(default)]
+    pub price_check: Option<PriceSanityCheck>,
+    /// Abort swaps when the Uniswap quote is too far from its recent time weighted average,
+    /// only the scheduler samples the price this is checked against
+    #[serde(default)]
+    pub twap_check: Option<TwapConfig>,
+    /// Caps on how much can be sent and when to stop sending altogether
+    #[serde(default)]
+    pub spending_limits: Option<SpendingLimits>,
+    /// Where to keep what has been spent against `spending_limits` and whether the circuit
+    /// breaker tripped, they are forgotten on restart if this is not set
+    #[serde(default)]
+    pub spending_state_path: Option<PathBuf>,
+    /// Addresses other than `own_address` funds may be sent to, anywhere is allowed if not set
+    #[serde(default)]
+    pub allowed_recipients: Option<Vec<AllowedRecipient>>,
+    /// How much of our Dai Uniswap is approved to spend, unlimited if not set. Other tokens
+    /// set their own in `tokens`.
+    #[serde(default)]
+    pub uniswap_allowance: AllowancePolicy,
+    /// Approve Uniswap with a signed Dai permit, only used with an unlimited allowance
+    #[serde(default)]
+    pub dai_permit: bool,
+    /// ERC20 tokens other than Dai to support
+    #[serde(default)]
+    pub tokens: Vec<Token>,
+    /// The token bridge mediators for bridging `tokens` to xDai
+    #[serde(default)]
+    pub omnibridge: Option<OmniBridge>,
+    /// What the xDai bridge keeps of each transfer in basis points, for estimating costs
+    #[serde(default)]
+    pub bridge_fee_basis_points: u64,
+    /// Retry reads and waits for transactions that fail because of the node
+    #[serde(default)]
+    pub retry_policy: Option<RetryPolicy>,
+}
+
+impl TokenBridgeConfig {
+    /// Reads and parses a TOML config file
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<TokenBridgeConfig, Error> {
+        let contents = fs::read_to_string(path.as_ref())?;
+        TokenBridgeConfig::parse(&contents)
+    }
+
+    pub fn parse(contents: &str) -> Result<TokenBridgeConfig, Error> {
+        Ok(toml::from_str(contents)?)
+    }
+}
+
+impl TokenBridge {
+    pub fn from_config(config: &TokenBridgeConfig) -> TokenBridge {
+        let mut bridge = TokenBridge::new(
+            config.uniswap_address,
+            config.xdai_home_bridge_address,
+            config.xdai_foreign_bridge_address,
+            config.foreign_dai_contract_address,
+            config.own_address,
+            config.secret,
+            config.eth_full_node_url.clone(),
+            config.xdai_full_node_url.clone(),
+        );
+        bridge.journal = config.journal_path.clone().map(Journal::new);
+        bridge.max_price_impact = config.max_price_impact;
+        bridge.price_check = config.price_check.clone();
+        bridge.twap_check = config.twap_check.as_ref().map(TwapConfig::check);
+        bridge.spending_guard =
+            config
+                .spending_limits
+                .clone()
+                .map(|limits| match config.spending_state_path {
+                    Some(ref path) => SpendingGuard::with_state_file(limits, path),
+                    None => SpendingGuard::new(limits),
+                });
+        bridge.uniswap_allowance = config.uniswap_allowance.clone();
+        bridge.dai_permit = config.dai_permit;
+        bridge.tokens = config.tokens.clone();
+        bridge.omnibridge = config.omnibridge.clone();
+        bridge.bridge_fee_basis_points = config.bridge_fee_basis_points;
+        bridge.retry_policy = config.retry_policy.clone();
+        bridge.recipient_allowlist = config
+            .allowed_recipients
+            .clone()
+            .map(RecipientAllowlist::new);
+        bridge
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_config() {
+        let config = TokenBridgeConfig::parse(
+            r#"
+            uniswap_address = "0x09cabEC1eAd1c0Ba254B09efb3EE13841712bE14"
+            xdai_home_bridge_address = "0x7301CFA0e1756B71869E93d4e4Dca5c7d0eb0AA6"
+            xdai_foreign_bridge_address = "0x4aa42145Aa6Ebf72e164C9bBC74fbD3788045016"
+            foreign_dai_contract_address = "0x89d24A6b4CcB1B6fAA2625fE562bDD9a23260359"
+            own_address = "0x79AE13432950bF5CDC3499f8d4Cf5963c3F0d42c"
+            secret = "1111111111111111111111111111111111111111111111111111111111111111"
+            eth_full_node_url = "https://eth.althea.org"
+            xdai_full_node_url = "https://dai.althea.org"
+
+            [twap_check]
+            window = 3600
+            max_deviation = 200
+            sample_interval = 60
+            sample_amount = "1000000000000000000"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.eth_full_node_url, "https://eth.althea.org");
+        assert_eq!(config.journal_path, None);
+        let twap = TokenBridge::from_config(&config).twap_check.unwrap();
+        assert_eq!(twap.window, std::time::Duration::from_secs(3600));
+        assert_eq!(twap.max_deviation, 200);
+        assert_eq!(
+            config.own_address,
+            "0x79AE13432950bF5CDC3499f8d4Cf5963c3F0d42c"
+                .parse()
+                .unwrap()
+        );
+    }
+}
@@ -0,0 +1,468 @@
+//! What moving funds between ETH on Eth and xDai would cost right now, to decide whether it is
+//! worth doing before anything is sent. Costs are the Uniswap fee and price impact against the
+//! pool's spot price, the bridge fee and the gas for every transaction involved, all added up in
+//! Dai. Gas is what the node estimates for each transaction, or the step's budget if it can't.
+
+use crate::amount::{DaiAmount, EthAmount};
+use crate::journal::Operation;
+use crate::price::{PoolReserves, PRICE_SCALE};
+use crate::TokenBridge;
+use clarity::abi::encode_call;
+use clarity::Address;
+use failure::bail;
+use failure::Error;
+use futures::Future;
+use num256::Uint256;
+use web30::client::Web3;
+use web30::types::TransactionRequest;
+
+const BASIS_POINTS: u64 = 10_000;
+
+/// Gas budgeted for each step, the gas limits the bridge sends them with where it sets one. Only
+/// used when the node can't estimate a step.
+pub const SWAP_GAS: u64 = 80_000;
+pub const APPROVAL_GAS: u64 = 60_000;
+/// A deposit that is a plain Dai transfer to the bridge
+pub const DEPOSIT_GAS: u64 = 80_000;
+/// A deposit through the bridge's `relayTokens`, which needs an approval first
+pub const RELAY_GAS: u64 = 120_000;
+pub const WITHDRAWAL_GAS: u64 = 100_000;
+/// xDai transactions are always sent at this price
+pub const XDAI_GAS_PRICE: u64 = 10_000_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TransferDirection {
+    /// Swap ETH for Dai on Uniswap and bridge the Dai to xDai
+    EthToXdai,
+    /// Bridge xDai to Dai and swap the Dai for ETH on Uniswap
+    XdaiToEth,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StepEstimate {
+    pub operation: Operation,
+    pub gas: Uint256,
+    pub gas_price: Uint256,
+    /// In ETH for steps on Eth and in xDai for steps on xDai
+    pub gas_cost: Uint256,
+}
+
+impl StepEstimate {
+    pub fn new(operation: Operation, gas: u64, gas_price: &Uint256) -> StepEstimate {
+        StepEstimate::with_gas(operation, gas.into(), gas_price)
+    }
+
+    pub fn with_gas(operation: Operation, gas: Uint256, gas_price: &Uint256) -> StepEstimate {
+        StepEstimate {
+            operation,
+            gas_cost: gas.clone() * gas_price.clone(),
+            gas,
+            gas_price: gas_price.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TransferEstimate {
+    pub direction: TransferDirection,
+    /// ETH going to xDai or xDai coming back
+    pub amount: Uint256,
+    /// What Uniswap quotes for the swap, Dai going to xDai and ETH coming back
+    pub swap_output: Uint256,
+    /// What the bridge keeps, in Dai
+    pub bridge_fee: Uint256,
+    /// What arrives before paying for gas, xDai going to xDai and ETH coming back
+    pub net_output: Uint256,
+    pub steps: Vec<StepEstimate>,
+    pub gas_cost_eth: Uint256,
+    pub gas_cost_xdai: Uint256,
+    /// Dai per ETH scaled by `PRICE_SCALE`, the Uniswap spot price everything is valued at
+    pub eth_price: Uint256,
+    /// The Uniswap fee and price impact, in Dai
+    pub swap_cost_dai: Uint256,
+    pub gas_cost_dai: Uint256,
+    /// Swap, bridge and gas costs together, in Dai
+    pub total_cost_dai: Uint256,
+    /// The total cost as a share of what is being moved
+    pub cost_basis_points: u64,
+}
+
+impl TransferEstimate {
+    /// Adds up the costs, `swap_cost_dai` is what the swap loses against the spot price
+    pub fn new(
+        direction: TransferDirection,
+        amount: Uint256,
+        swap_output: Uint256,
+        bridge_fee: Uint256,
+        steps: Vec<StepEstimate>,
+        eth_price: Uint256,
+        swap_cost_dai: Uint256,
+    ) -> TransferEstimate {
+        let zero = || -> Uint256 { 0u8.into() };
+        let scale: Uint256 = PRICE_SCALE.into();
+
+        let gas_cost_on = |chain: &str| {
+            steps
+                .iter()
+                .filter(|step| step.operation.chain() == chain)
+                .fold(zero(), |sum, step| sum + step.gas_cost.clone())
+        };
+        let gas_cost_eth = gas_cost_on("eth");
+        let gas_cost_xdai = gas_cost_on("xdai");
+        let gas_cost_dai =
+            gas_cost_xdai.clone() + gas_cost_eth.clone() * eth_price.clone() / scale.clone();
+        let total_cost_dai = swap_cost_dai.clone() + bridge_fee.clone() + gas_cost_dai.clone();
+
+        let (net_output, value_dai) = match direction {
+            TransferDirection::EthToXdai => (
+                saturating_sub(&swap_output, &bridge_fee),
+                amount.clone() * eth_price.clone() / scale,
+            ),
+            TransferDirection::XdaiToEth => (swap_output.clone(), amount.clone()),
+        };
+        let cost_basis_points = if value_dai == zero() {
+            0
+        } else {
+            (total_cost_dai.clone() * BASIS_POINTS.into() / value_dai)
+                .to_string()
+                .parse()
+                .unwrap_or(u64::MAX)
+        };
+
+        TransferEstimate {
+            direction,
+            amount,
+            swap_output,
+            bridge_fee,
+            net_output,
+            steps,
+            gas_cost_eth,
+            gas_cost_xdai,
+            eth_price,
+            swap_cost_dai,
+            gas_cost_dai,
+            total_cost_dai,
+            cost_basis_points,
+        }
+    }
+}
+
+impl TokenBridge {
+    /// What the bridge keeps of `amount` Dai
+    pub fn bridge_fee(&self, amount: &Uint256) -> Uint256 {
+        amount.clone() * self.bridge_fee_basis_points.into() / BASIS_POINTS.into()
+    }
+
+    /// Quotes moving `amount` in `direction` without sending anything. Approval steps are only
+    /// included if the current allowance wouldn't cover the transfer.
+    pub fn estimate_transfer(
+        &self,
+        direction: TransferDirection,
+        amount: Uint256,
+    ) -> Box<dyn Future<Item = TransferEstimate, Error = Error>> {
+        let salf = self.clone();
+
+        Box::new(
+            self.get_uniswap_reserves()
+                .join(self.read_gas_price(&self.eth_web3))
+                .and_then(move |(reserves, eth_gas_price)| {
+                    let eth_price = match spot_eth_price(&reserves) {
+                        Ok(price) => price,
+                        Err(e) => {
+                            return Box::new(futures::future::err(e))
+                                as Box<dyn Future<Item = TransferEstimate, Error = Error>>
+                        }
+                    };
+                    match direction {
+                        TransferDirection::EthToXdai => {
+                            salf.estimate_eth_to_xdai(amount, eth_gas_price, eth_price)
+                        }
+                        TransferDirection::XdaiToEth => {
+                            salf.estimate_xdai_to_eth(amount, eth_gas_price, eth_price)
+                        }
+                    }
+                }),
+        )
+    }
+
+    fn estimate_eth_to_xdai(
+        &self,
+        amount: Uint256,
+        eth_gas_price: Uint256,
+        eth_price: Uint256,
+    ) -> Box<dyn Future<Item = TransferEstimate, Error = Error>> {
+        let salf = self.clone();
+        let swap_amount = amount.clone();
+
+        // The deposit is a plain transfer to the bridge, so unlike a top up it needs no approval
+        Box::new(
+            self.eth_to_dai_price(EthAmount::from_wei(amount.clone()))
+                .and_then(move |dai| {
+                    let web3 = &salf.eth_web3;
+                    let bridge = salf.xdai_foreign_bridge_address;
+                    let mut steps = vec![salf.estimate_step(
+                        web3,
+                        Operation::EthToDaiSwap,
+                        salf.uniswap_address,
+                        encode_call(
+                            "ethToTokenSwapInput(uint256,uint256)",
+                            &[Uint256::from(1u8).into(), far_deadline().into()],
+                        ),
+                        swap_amount,
+                        SWAP_GAS,
+                    )];
+                    steps.push(salf.estimate_step(
+                        web3,
+                        Operation::DaiToXdaiDeposit,
+                        salf.foreign_dai_contract_address,
+                        encode_call(
+                            "transfer(address,uint256)",
+                            &[bridge.into(), dai.clone().into()],
+                        ),
+                        0u8.into(),
+                        DEPOSIT_GAS,
+                    ));
+
+                    let bridge_fee = salf.bridge_fee(&dai);
+                    futures::future::join_all(steps).map(move |steps| (dai, bridge_fee, steps))
+                })
+                .map(move |(dai, bridge_fee, steps)| {
+                    let steps = steps
+                        .into_iter()
+                        .map(|(operation, gas)| {
+                            StepEstimate::with_gas(operation, gas, &eth_gas_price)
+                        })
+                        .collect();
+
+                    let spot_value = amount.clone() * eth_price.clone() / PRICE_SCALE.into();
+                    let swap_cost_dai = saturating_sub(&spot_value, &dai);
+                    TransferEstimate::new(
+                        TransferDirection::EthToXdai,
+                        amount,
+                        dai.clone(),
+                        bridge_fee,
+                        steps,
+                        eth_price,
+                        swap_cost_dai,
+                    )
+                }),
+        )
+    }
+
+    fn estimate_xdai_to_eth(
+        &self,
+        amount: Uint256,
+        eth_gas_price: Uint256,
+        eth_price: Uint256,
+    ) -> Box<dyn Future<Item = TransferEstimate, Error = Error>> {
+        let bridge_fee = self.bridge_fee(&amount);
+        let dai = saturating_sub(&amount, &bridge_fee);
+        let salf = self.clone();
+
+        Box::new(
+            self.dai_to_eth_price(DaiAmount::from_wei(dai.clone()))
+                .join(self.get_dai_allowance(self.uniswap_address))
+                .and_then(move |(eth, allowance)| {
+                    let mut steps = vec![salf.estimate_step(
+                        &salf.xdai_web3,
+                        Operation::XdaiToDaiWithdrawal,
+                        salf.xdai_home_bridge_address,
+                        Vec::new(),
+                        amount.clone(),
+                        WITHDRAWAL_GAS,
+                    )];
+                    if allowance < dai {
+                        steps.push(salf.estimate_step(
+                            &salf.eth_web3,
+                            Operation::ApproveUniswapDai,
+                            salf.foreign_dai_contract_address,
+                            encode_call(
+                                "approve(address,uint256)",
+                                &[salf.uniswap_address.into(), dai.clone().into()],
+                            ),
+                            0u8.into(),
+                            APPROVAL_GAS,
+                        ));
+                    }
+                    steps.push(salf.estimate_step(
+                        &salf.eth_web3,
+                        Operation::DaiToEthSwap,
+                        salf.uniswap_address,
+                        encode_call(
+                            "tokenToEthSwapInput(uint256,uint256,uint256)",
+                            &[
+                                dai.clone().into(),
+                                Uint256::from(1u8).into(),
+                                far_deadline().into(),
+                            ],
+                        ),
+                        0u8.into(),
+                        SWAP_GAS,
+                    ));
+
+                    futures::future::join_all(steps).map(move |steps| (amount, dai, eth, steps))
+                })
+                .map(move |(amount, dai, eth, steps)| {
+                    let steps = steps
+                        .into_iter()
+                        .map(|(operation, gas)| {
+                            let gas_price = match operation.chain() {
+                                "xdai" => XDAI_GAS_PRICE.into(),
+                                _ => eth_gas_price.clone(),
+                            };
+                            StepEstimate::with_gas(operation, gas, &gas_price)
+                        })
+                        .collect();
+
+                    let eth_value = eth.clone() * eth_price.clone() / PRICE_SCALE.into();
+                    let swap_cost_dai = saturating_sub(&dai, &eth_value);
+                    TransferEstimate::new(
+                        TransferDirection::XdaiToEth,
+                        amount,
+                        eth,
+                        bridge_fee,
+                        steps,
+                        eth_price,
+                        swap_cost_dai,
+                    )
+                }),
+        )
+    }
+
+    /// Asks the node how much gas a step would use, or falls back to `budget` if it can't say.
+    /// A step that needs an earlier one to have happened, like depositing Dai the swap before it
+    /// hasn't bought yet, reverts when estimated on its own and gets its budget.
+    fn estimate_step(
+        &self,
+        web3: &Web3,
+        operation: Operation,
+        to: Address,
+        data: Vec<u8>,
+        value: Uint256,
+        budget: u64,
+    ) -> Box<dyn Future<Item = (Operation, Uint256), Error = Error>> {
+        let web3 = web3.clone();
+        let request = TransactionRequest {
+            from: self.own_address,
+            to: Some(to),
+            gas: None,
+            gas_price: None,
+            value: Some(value.into()),
+            data: Some(data.into()),
+            nonce: None,
+        };
+
+        Box::new(
+            self.retry_read("eth_estimateGas", move || {
+                web3.eth_estimate_gas(request.clone())
+            })
+            .then(move |gas| -> Result<(Operation, Uint256), Error> {
+                match gas {
+                    Ok(gas) => Ok((operation, gas)),
+                    Err(e) => {
+                        trace!(
+                            "Using the gas budget for {:?}, estimate failed {:?}",
+                            operation,
+                            e
+                        );
+                        Ok((operation, budget.into()))
+                    }
+                }
+            }),
+        )
+    }
+}
+
+/// A swap deadline for estimates, which are never mined
+fn far_deadline() -> Uint256 {
+    u64::MAX.into()
+}
+
+/// Dai per ETH in the pool before any trade, scaled by `PRICE_SCALE`
+fn spot_eth_price(reserves: &PoolReserves) -> Result<Uint256, Error> {
+    let zero: Uint256 = 0u8.into();
+    if reserves.eth == zero || reserves.dai == zero {
+        bail!("The Uniswap pool is empty");
+    }
+    Ok(reserves.dai.clone() * PRICE_SCALE.into() / reserves.eth.clone())
+}
+
+fn saturating_sub(a: &Uint256, b: &Uint256) -> Uint256 {
+    if a > b {
+        a.clone() - b.clone()
+    } else {
+        0u8.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_transfer_estimate() {
+        let eth: Uint256 = PRICE_SCALE.into();
+        // 200 Dai per ETH
+        let eth_price = eth.clone() * 200u64.into();
+        let gas_price: Uint256 = 10_000_000_000u64.into();
+        let steps = vec![
+            StepEstimate::new(Operation::EthToDaiSwap, SWAP_GAS, &gas_price),
+            StepEstimate::new(Operation::DaiToXdaiDeposit, RELAY_GAS, &gas_price),
+        ];
+        let dai: Uint256 = eth.clone() * 199u64.into();
+
+        let estimate = TransferEstimate::new(
+            TransferDirection::EthToXdai,
+            eth.clone(),
+            dai.clone(),
+            0u8.into(),
+            steps,
+            eth_price.clone(),
+            eth.clone(),
+        );
+
+        assert_eq!(estimate.net_output, dai);
+        assert_eq!(estimate.gas_cost_eth, 2_000_000_000_000_000u64.into());
+        assert_eq!(estimate.gas_cost_xdai, 0u8.into());
+        // 0.002 ETH at 200 Dai
+        assert_eq!(estimate.gas_cost_dai, 400_000_000_000_000_000u64.into());
+        assert_eq!(estimate.total_cost_dai, 1_400_000_000_000_000_000u64.into());
+        // 1.4 Dai of 200 Dai moved
+        assert_eq!(estimate.cost_basis_points, 70);
+
+        let back = TransferEstimate::new(
+            TransferDirection::XdaiToEth,
+            dai,
+            1u8.into(),
+            2u8.into(),
+            vec![StepEstimate::new(
+                Operation::XdaiToDaiWithdrawal,
+                WITHDRAWAL_GAS,
+                &XDAI_GAS_PRICE.into(),
+            )],
+            eth_price,
+            0u8.into(),
+        );
+        assert_eq!(back.net_output, 1u8.into());
+        assert_eq!(back.gas_cost_xdai, 1_000_000_000_000_000u64.into());
+        assert_eq!(back.gas_cost_dai, 1_000_000_000_000_000u64.into());
+        assert_eq!(back.total_cost_dai, 1_000_000_000_000_002u64.into());
+    }
+
+    #[test]
+    fn test_spot_eth_price() {
+        let reserves = PoolReserves {
+            eth: 10u64.into(),
+            dai: 2000u64.into(),
+        };
+        assert_eq!(
+            spot_eth_price(&reserves).unwrap(),
+            Uint256::from(200u64) * PRICE_SCALE.into()
+        );
+        assert!(spot_eth_price(&PoolReserves {
+            eth: 0u8.into(),
+            dai: 1u8.into(),
+        })
+        .is_err());
+    }
+}
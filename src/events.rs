@@ -0,0 +1,124 @@
+//! Notifications about operations as they make progress, for reacting to them without polling.
+//! Subscribers get an unbounded channel each, a subscriber that drops its receiver is forgotten
+//! the next time something is sent.
+
+use crate::journal::Operation;
+use crate::TokenBridge;
+use clarity::Address;
+use futures::sync::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use num256::Uint256;
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// A transaction was sent, `amount` is what went into the operation
+    TxSubmitted {
+        operation: Operation,
+        tx_hash: Uint256,
+        amount: Uint256,
+    },
+    /// An operation's transaction was mined successfully
+    TxMined {
+        operation: Operation,
+        tx_hash: Uint256,
+        gas_used: Option<Uint256>,
+    },
+    SwapCompleted {
+        operation: Operation,
+        tx_hash: Option<Uint256>,
+        /// The token sold if it isn't ETH or Dai
+        token: Option<Address>,
+        sold: Uint256,
+        bought: Uint256,
+    },
+    /// A deposit was mined on Eth. It hasn't been credited yet, the validators credit
+    /// `recipient` on xDai once they see it.
+    DepositSubmitted {
+        operation: Operation,
+        tx_hash: Option<Uint256>,
+        /// The token deposited if it isn't Dai
+        token: Option<Address>,
+        recipient: Address,
+        amount: Uint256,
+    },
+    /// A withdrawal from xDai was paid out on Eth
+    WithdrawalExecuted {
+        tx_hash: Option<Uint256>,
+        /// The token withdrawn if it isn't Dai
+        token: Option<Address>,
+        amount: Uint256,
+    },
+    OperationFailed {
+        operation: Operation,
+        tx_hash: Option<Uint256>,
+        error: String,
+    },
+}
+
+#[derive(Clone, Default)]
+pub struct EventBus {
+    subscribers: Arc<Mutex<Vec<UnboundedSender<Event>>>>,
+}
+
+impl EventBus {
+    pub fn new() -> EventBus {
+        EventBus::default()
+    }
+
+    /// Every event sent from now on is also sent to the returned receiver
+    pub fn subscribe(&self) -> UnboundedReceiver<Event> {
+        let (sender, receiver) = unbounded();
+        self.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    pub fn has_subscribers(&self) -> bool {
+        !self.subscribers.lock().unwrap().is_empty()
+    }
+
+    pub fn emit(&self, event: Event) {
+        trace!("bridge event {:?}", event);
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|subscriber| subscriber.unbounded_send(event.clone()).is_ok());
+    }
+}
+
+impl TokenBridge {
+    /// A stream of every event from operations started on this bridge or its clones
+    pub fn subscribe(&self) -> UnboundedReceiver<Event> {
+        self.events.subscribe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{Future, Stream};
+
+    #[test]
+    fn test_event_bus() {
+        let bus = EventBus::new();
+        assert!(!bus.has_subscribers());
+
+        let first = bus.subscribe();
+        let second = bus.subscribe();
+        let event = Event::TxSubmitted {
+            operation: Operation::EthToDaiSwap,
+            tx_hash: 1u8.into(),
+            amount: 100u8.into(),
+        };
+        bus.emit(event.clone());
+
+        let (received, first) = first.into_future().wait().ok().unwrap();
+        assert_eq!(received, Some(event.clone()));
+        drop(second);
+
+        // The dropped subscriber is forgotten, the other one still hears about everything
+        bus.emit(event.clone());
+        assert_eq!(bus.subscribers.lock().unwrap().len(), 1);
+        let (received, _) = first.into_future().wait().ok().unwrap();
+        assert_eq!(received, Some(event));
+    }
+}
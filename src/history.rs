@@ -0,0 +1,556 @@
+//! Reconstructs the bridge activity of `own_address` from chain logs, used when an existing
+//! wallet is handed to the bridge and we have no journal of what it did before.
+
+use crate::retry::RetryPolicy;
+use crate::TokenBridge;
+use clarity::abi::derive_signature;
+use clarity::utils::bytes_to_hex_str;
+use clarity::Address;
+use failure::bail;
+use failure::Error;
+use futures::stream;
+use futures::{Future, Stream};
+use num256::Uint256;
+use std::collections::HashMap;
+use web30::client::Web3;
+use web30::types::{Log, NewFilter};
+
+/// Most nodes refuse to return logs for very large block ranges, so ranges are queried in
+/// chunks of this many blocks
+const LOG_CHUNK_SIZE: u64 = 10_000;
+
+const TRANSFER_EVENT: &str = "Transfer(address,address,uint256)";
+/// Emitted by the home bridge when it receives xDai to withdraw
+const USER_REQUEST_FOR_SIGNATURE_EVENT: &str = "UserRequestForSignature(address,uint256)";
+/// Emitted by the home bridge when a deposit has been credited on xDai
+const AFFIRMATION_COMPLETED_EVENT: &str = "AffirmationCompleted(address,uint256,bytes32)";
+/// Emitted by the foreign bridge when a withdrawal has been paid out on Eth
+const RELAYED_MESSAGE_EVENT: &str = "RelayedMessage(address,uint256,bytes32)";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BridgeDirection {
+    /// Dai to xDai
+    Deposit,
+    /// xDai to Dai
+    Withdrawal,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BridgeTransferStatus {
+    /// The transfer was sent to the bridge but not yet completed on the other chain
+    Pending,
+    Completed,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BridgeTransfer {
+    pub direction: BridgeDirection,
+    pub status: BridgeTransferStatus,
+    pub amount: Uint256,
+    /// The transaction that sent the funds to the bridge
+    pub tx_hash: Uint256,
+    pub block_number: Uint256,
+    /// The transaction on the other chain that completed the transfer
+    pub completion_tx_hash: Option<Uint256>,
+    pub completion_block_number: Option<Uint256>,
+}
+
+/// A bridge event decoded down to the fields we use for pairing
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct BridgeEvent {
+    pub amount: Uint256,
+    /// Who the funds go to on the other chain
+    pub recipient: Address,
+    pub tx_hash: Uint256,
+    pub block_number: Uint256,
+    /// For completion events, the hash of the transaction on the other chain they complete
+    pub source_tx_hash: Option<Uint256>,
+}
+
+impl TokenBridge {
+    /// Finds every Dai to xDai deposit and xDai to Dai withdrawal made by `own_address` in the
+    /// given block ranges, oldest first within each direction. Completion events can land well
+    /// after the transfer they complete so the ranges on each chain should extend past the
+    /// last transfer of interest.
+    pub fn get_bridge_transfers(
+        &self,
+        eth_from_block: Uint256,
+        eth_to_block: Uint256,
+        xdai_from_block: Uint256,
+        xdai_to_block: Uint256,
+    ) -> Box<dyn Future<Item = Vec<BridgeTransfer>, Error = Error>> {
+        Box::new(
+            self.get_deposits(
+                eth_from_block.clone(),
+                eth_to_block.clone(),
+                xdai_from_block.clone(),
+                xdai_to_block.clone(),
+            )
+            .join(self.get_withdrawals(
+                eth_from_block,
+                eth_to_block,
+                xdai_from_block,
+                xdai_to_block,
+            ))
+            .map(|(mut deposits, withdrawals)| {
+                deposits.extend(withdrawals);
+                deposits
+            }),
+        )
+    }
+
+    /// Dai transfers from `own_address` to the foreign bridge, paired with the home bridge
+    /// affirmations that credited them on xDai
+    pub fn get_deposits(
+        &self,
+        eth_from_block: Uint256,
+        eth_to_block: Uint256,
+        xdai_from_block: Uint256,
+        xdai_to_block: Uint256,
+    ) -> Box<dyn Future<Item = Vec<BridgeTransfer>, Error = Error>> {
+        let transfers = get_logs_chunked(
+            self.eth_web3.clone(),
+            self.retry_policy.clone(),
+            self.foreign_dai_contract_address,
+            TRANSFER_EVENT,
+            vec![
+                Some(address_topic(self.own_address)),
+                Some(address_topic(self.xdai_foreign_bridge_address)),
+            ],
+            eth_from_block,
+            eth_to_block,
+        );
+        let affirmations = get_logs_chunked(
+            self.xdai_web3.clone(),
+            self.retry_policy.clone(),
+            self.xdai_home_bridge_address,
+            AFFIRMATION_COMPLETED_EVENT,
+            Vec::new(),
+            xdai_from_block,
+            xdai_to_block,
+        );
+
+        Box::new(
+            transfers
+                .join(affirmations)
+                .and_then(move |(transfers, affirmations)| {
+                    let transfers = transfers
+                        .iter()
+                        .map(decode_transfer)
+                        .collect::<Result<Vec<_>, Error>>()?;
+                    let affirmations = decode_bridge_events(&affirmations)?;
+                    Ok(pair_transfers(
+                        BridgeDirection::Deposit,
+                        transfers,
+                        affirmations,
+                    ))
+                }),
+        )
+    }
+
+    /// xDai sent from `own_address` to the home bridge, paired with the foreign bridge relays
+    /// that paid them out as Dai on Eth
+    pub fn get_withdrawals(
+        &self,
+        eth_from_block: Uint256,
+        eth_to_block: Uint256,
+        xdai_from_block: Uint256,
+        xdai_to_block: Uint256,
+    ) -> Box<dyn Future<Item = Vec<BridgeTransfer>, Error = Error>> {
+        let requests = self.get_withdrawal_requests(xdai_from_block, xdai_to_block);
+        let relays = get_logs_chunked(
+            self.eth_web3.clone(),
+            self.retry_policy.clone(),
+            self.xdai_foreign_bridge_address,
+            RELAYED_MESSAGE_EVENT,
+            Vec::new(),
+            eth_from_block,
+            eth_to_block,
+        );
+
+        Box::new(requests.join(relays).and_then(move |(requests, relays)| {
+            let relays = decode_bridge_events(&relays)?;
+            Ok(pair_transfers(
+                BridgeDirection::Withdrawal,
+                requests,
+                relays,
+            ))
+        }))
+    }
+
+    /// The xDai sent from `own_address` to the home bridge to be withdrawn
+    pub(crate) fn get_withdrawal_requests(
+        &self,
+        xdai_from_block: Uint256,
+        xdai_to_block: Uint256,
+    ) -> Box<dyn Future<Item = Vec<BridgeEvent>, Error = Error>> {
+        let own_address = self.own_address;
+
+        Box::new(
+            get_logs_chunked(
+                self.xdai_web3.clone(),
+                self.retry_policy.clone(),
+                self.xdai_home_bridge_address,
+                USER_REQUEST_FOR_SIGNATURE_EVENT,
+                Vec::new(),
+                xdai_from_block,
+                xdai_to_block,
+            )
+            .and_then(move |requests| {
+                let mut requests = decode_bridge_events(&requests)?;
+                requests.retain(|request| request.recipient == own_address);
+                Ok(requests)
+            }),
+        )
+    }
+}
+
+/// Matches each transfer with the completion event that references its tx hash. The
+/// completions are for every user of the bridge, so this is what ties a deposit sent to another
+/// recipient with `relayTokens` to its completion. Completions that don't carry a tx hash fall
+/// back to the oldest transfer of the same amount to the same recipient.
+fn pair_transfers(
+    direction: BridgeDirection,
+    transfers: Vec<BridgeEvent>,
+    completions: Vec<BridgeEvent>,
+) -> Vec<BridgeTransfer> {
+    let mut by_source = HashMap::new();
+    let mut unsourced = Vec::new();
+    for completion in completions {
+        match completion.source_tx_hash.clone() {
+            Some(source) => {
+                by_source.insert(source, completion);
+            }
+            None => unsourced.push(completion),
+        }
+    }
+
+    transfers
+        .into_iter()
+        .map(|transfer| {
+            let completion = by_source.remove(&transfer.tx_hash).or_else(|| {
+                unsourced
+                    .iter()
+                    .position(|completion| {
+                        completion.amount == transfer.amount
+                            && completion.recipient == transfer.recipient
+                    })
+                    .map(|index| unsourced.remove(index))
+            });
+            BridgeTransfer {
+                direction,
+                status: match completion {
+                    Some(_) => BridgeTransferStatus::Completed,
+                    None => BridgeTransferStatus::Pending,
+                },
+                amount: transfer.amount,
+                tx_hash: transfer.tx_hash,
+                block_number: transfer.block_number,
+                completion_tx_hash: completion.as_ref().map(|c| c.tx_hash.clone()),
+                completion_block_number: completion.map(|c| c.block_number),
+            }
+        })
+        .collect()
+}
+
+/// Gets the logs for `event` emitted by `contract` over a block range of any size. `topics` are
+/// the indexed arguments to filter on after the event signature, `None` matches anything. Each
+/// chunk is retried under `retry_policy` if there is one.
+pub(crate) fn get_logs_chunked(
+    web3: Web3,
+    retry_policy: Option<RetryPolicy>,
+    contract: Address,
+    event: &str,
+    topics: Vec<Option<String>>,
+    from_block: Uint256,
+    to_block: Uint256,
+) -> Box<dyn Future<Item = Vec<Log>, Error = Error>> {
+    let mut filter_topics = vec![Some(vec![Some(format!(
+        "0x{}",
+        bytes_to_hex_str(&derive_signature(event))
+    ))])];
+    for topic in topics {
+        filter_topics.push(topic.map(|topic| vec![Some(topic)]));
+    }
+
+    let requests = block_chunks(from_block, to_block, LOG_CHUNK_SIZE.into())
+        .into_iter()
+        .map(move |(from, to)| NewFilter {
+            address: vec![contract],
+            from_block: Some(quantity(&from)),
+            to_block: Some(quantity(&to)),
+            topics: Some(filter_topics.clone()),
+        })
+        .collect::<Vec<_>>();
+
+    Box::new(
+        stream::iter_ok(requests)
+            .and_then(move |filter| match retry_policy {
+                Some(ref policy) => {
+                    let web3 = web3.clone();
+                    policy.retry("eth_getLogs", move || web3.eth_get_logs(filter.clone()))
+                }
+                None => web3.eth_get_logs(filter),
+            })
+            .concat2(),
+    )
+}
+
+/// Splits an inclusive block range into inclusive chunks of at most `size` blocks
+fn block_chunks(from_block: Uint256, to_block: Uint256, size: Uint256) -> Vec<(Uint256, Uint256)> {
+    let one: Uint256 = 1u8.into();
+    let mut chunks = Vec::new();
+    let mut start = from_block;
+    while start <= to_block {
+        let mut end = start.clone() + size.clone() - one.clone();
+        if end > to_block {
+            end = to_block.clone();
+        }
+        chunks.push((start, end.clone()));
+        start = end + one.clone();
+    }
+    chunks
+}
+
+/// Formats a number as a JSON-RPC quantity, hex without leading zeros
+pub(crate) fn quantity(value: &Uint256) -> String {
+    let hex = bytes_to_hex_str(&value.to_bytes_be());
+    let hex = hex.trim_start_matches('0');
+    if hex.is_empty() {
+        "0x0".to_string()
+    } else {
+        format!("0x{}", hex)
+    }
+}
+
+/// An address left padded to 32 bytes, the way it appears as an indexed event argument
+pub(crate) fn address_topic(address: Address) -> String {
+    let mut topic = [0u8; 32];
+    topic[12..].copy_from_slice(address.as_bytes());
+    format!("0x{}", bytes_to_hex_str(&topic))
+}
+
+pub(crate) fn log_tx_hash(log: &Log) -> Result<Uint256, Error> {
+    match log.transaction_hash {
+        Some(ref hash) => Ok(Uint256::from_bytes_be(hash)),
+        None => bail!("Log without a transaction hash {:?}", log),
+    }
+}
+
+fn log_block_number(log: &Log) -> Result<Uint256, Error> {
+    match log.block_number {
+        Some(ref number) => Ok(number.clone()),
+        None => bail!("Log without a block number {:?}", log),
+    }
+}
+
+pub(crate) fn decode_address(word: &[u8]) -> Result<Address, Error> {
+    match word.get(12..32) {
+        Some(bytes) => Address::from_slice(bytes),
+        None => bail!("Malformed address in event {:?}", word),
+    }
+}
+
+fn decode_word(data: &[u8], index: usize) -> Result<&[u8], Error> {
+    match data.get(index * 32..(index + 1) * 32) {
+        Some(word) => Ok(word),
+        None => bail!("Event data too short {:?}", data),
+    }
+}
+
+/// A Dai `Transfer`, the amount is the only argument that is not indexed. A plain transfer to
+/// the foreign bridge credits the sender, one made by `relayTokens` credits whoever it names,
+/// which only its completion tells us.
+fn decode_transfer(log: &Log) -> Result<BridgeEvent, Error> {
+    let sender = match log.topics.get(1) {
+        Some(topic) => decode_address(topic)?,
+        None => bail!("Transfer event without a sender {:?}", log),
+    };
+    Ok(BridgeEvent {
+        amount: Uint256::from_bytes_be(decode_word(&log.data, 0)?),
+        recipient: sender,
+        tx_hash: log_tx_hash(log)?,
+        block_number: log_block_number(log)?,
+        source_tx_hash: None,
+    })
+}
+
+/// The bridge events all start with `(address recipient, uint256 value ...)`, the completion
+/// events are followed by the hash of the transaction they complete. Depending on the bridge
+/// version the recipient may or may not be indexed.
+fn decode_bridge_events(logs: &[Log]) -> Result<Vec<BridgeEvent>, Error> {
+    let mut events = Vec::new();
+    for log in logs {
+        let (recipient, args) = match log.topics.get(1) {
+            Some(topic) => (decode_address(topic)?, &log.data[..]),
+            None => (decode_address(decode_word(&log.data, 0)?)?, &log.data[32..]),
+        };
+        events.push(BridgeEvent {
+            amount: Uint256::from_bytes_be(decode_word(args, 0)?),
+            recipient,
+            tx_hash: log_tx_hash(log)?,
+            block_number: log_block_number(log)?,
+            source_tx_hash: args.get(32..64).map(Uint256::from_bytes_be),
+        });
+    }
+    Ok(events)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+    use web30::types::Data;
+
+    fn word(value: u64) -> Vec<u8> {
+        let mut word = vec![0u8; 24];
+        word.extend_from_slice(&value.to_be_bytes());
+        word
+    }
+
+    fn address_word(address: Address) -> Vec<u8> {
+        let mut word = vec![0u8; 12];
+        word.extend_from_slice(address.as_bytes());
+        word
+    }
+
+    fn log(topics: Vec<Vec<u8>>, data: Vec<u8>, tx_hash: u64, block: u64) -> Log {
+        Log {
+            removed: None,
+            log_index: None,
+            transaction_index: None,
+            transaction_hash: Some(Data(word(tx_hash))),
+            block_hash: None,
+            block_number: Some(block.into()),
+            address: Address::default(),
+            data: Data(data),
+            topics: topics.into_iter().map(Data).collect(),
+        }
+    }
+
+    #[test]
+    fn test_pair_withdrawals() {
+        let own_address = Address::from_str("0x79AE13432950bF5CDC3499f8d4Cf5963c3F0d42c").unwrap();
+        let other_address =
+            Address::from_str("0x6d943740746934b2f5D9c9E6Cb1908758A42452f").unwrap();
+
+        let requests = vec![
+            log(
+                vec![],
+                [address_word(own_address), word(100)].concat(),
+                1,
+                10,
+            ),
+            log(
+                vec![],
+                [address_word(own_address), word(200)].concat(),
+                2,
+                11,
+            ),
+            log(
+                vec![],
+                [address_word(other_address), word(300)].concat(),
+                3,
+                12,
+            ),
+        ];
+        // An indexed recipient and a non indexed one, only the first request has been relayed
+        let relays = vec![
+            log(
+                vec![vec![0u8; 32], address_word(own_address)],
+                [word(100), word(1)].concat(),
+                50,
+                1000,
+            ),
+            log(
+                vec![],
+                [address_word(other_address), word(300), word(3)].concat(),
+                51,
+                1001,
+            ),
+        ];
+
+        let mut requests = decode_bridge_events(&requests).unwrap();
+        requests.retain(|request| request.recipient == own_address);
+        let transfers = pair_transfers(
+            BridgeDirection::Withdrawal,
+            requests,
+            decode_bridge_events(&relays).unwrap(),
+        );
+
+        assert_eq!(transfers.len(), 2);
+        assert_eq!(transfers[0].status, BridgeTransferStatus::Completed);
+        assert_eq!(transfers[0].amount, 100u64.into());
+        assert_eq!(transfers[0].completion_tx_hash, Some(50u64.into()));
+        assert_eq!(transfers[0].completion_block_number, Some(1000u64.into()));
+        assert_eq!(transfers[1].status, BridgeTransferStatus::Pending);
+        assert_eq!(transfers[1].tx_hash, 2u64.into());
+    }
+
+    #[test]
+    fn test_pair_deposits() {
+        let own_address = Address::from_str("0x79AE13432950bF5CDC3499f8d4Cf5963c3F0d42c").unwrap();
+        let other_address =
+            Address::from_str("0x6d943740746934b2f5D9c9E6Cb1908758A42452f").unwrap();
+        let bridge_address =
+            Address::from_str("0x4aa42145Aa6Ebf72e164C9bBC74fbD3788045016").unwrap();
+        let transfer_topics = vec![
+            vec![0u8; 32],
+            address_word(own_address),
+            address_word(bridge_address),
+        ];
+
+        // A plain deposit and a relayTokens deposit crediting someone else
+        let transfers = [
+            log(transfer_topics.clone(), word(100), 1, 10),
+            log(transfer_topics, word(200), 2, 11),
+        ];
+        // The relayTokens deposit is matched by its tx hash even though we are not the
+        // recipient, the plain one by amount and recipient as its affirmation has no tx hash
+        let affirmations = vec![
+            log(
+                vec![],
+                [address_word(other_address), word(200), word(2)].concat(),
+                50,
+                1000,
+            ),
+            log(
+                vec![],
+                [address_word(own_address), word(100)].concat(),
+                51,
+                1001,
+            ),
+        ];
+
+        let transfers = pair_transfers(
+            BridgeDirection::Deposit,
+            transfers
+                .iter()
+                .map(decode_transfer)
+                .collect::<Result<_, _>>()
+                .unwrap(),
+            decode_bridge_events(&affirmations).unwrap(),
+        );
+
+        assert_eq!(transfers.len(), 2);
+        assert_eq!(transfers[0].status, BridgeTransferStatus::Completed);
+        assert_eq!(transfers[0].completion_tx_hash, Some(51u64.into()));
+        assert_eq!(transfers[1].status, BridgeTransferStatus::Completed);
+        assert_eq!(transfers[1].completion_tx_hash, Some(50u64.into()));
+    }
+
+    #[test]
+    fn test_block_chunks() {
+        let chunks = block_chunks(5u64.into(), 25u64.into(), 10u64.into());
+        assert_eq!(
+            chunks,
+            vec![
+                (5u64.into(), 14u64.into()),
+                (15u64.into(), 24u64.into()),
+                (25u64.into(), 25u64.into()),
+            ]
+        );
+        assert_eq!(quantity(&0u64.into()), "0x0");
+        assert_eq!(quantity(&4096u64.into()), "0x1000");
+    }
+}
@@ -0,0 +1,464 @@
+//! An append-only record of every transfer the bridge makes. Each line of the journal file is a
+//! JSON encoded `JournalEntry`, a new line is written every time an operation makes progress so
+//! the latest line for a given id is the current state of that operation.
+
+use crate::events::{Event, EventBus};
+use clarity::utils::bytes_to_hex_str;
+use clarity::Address;
+use failure::Error;
+use futures::Future;
+use num256::Uint256;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::field;
+use tracing::Span;
+use tracing_futures::Instrument;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Operation {
+    EthToDaiSwap,
+    DaiToEthSwap,
+    ApproveUniswapDai,
+    ApproveBridgeDai,
+    EthToTokenSwap,
+    TokenToEthSwap,
+    ApproveToken,
+    TokenToXdaiDeposit,
+    XdaiToTokenWithdrawal,
+    DaiToXdaiDeposit,
+    XdaiToDaiWithdrawal,
+    EthTransfer,
+    /// Paying out a Dai or token withdrawal on Eth
+    ExecuteWithdrawal,
+}
+
+impl Operation {
+    /// The chain the operation's transaction is sent on
+    pub fn chain(self) -> &'static str {
+        match self {
+            Operation::XdaiToDaiWithdrawal | Operation::XdaiToTokenWithdrawal => "xdai",
+            _ => "eth",
+        }
+    }
+
+    /// Approvals only set an allowance, their amount isn't anything that moved
+    pub fn is_approval(self) -> bool {
+        matches!(
+            self,
+            Operation::ApproveUniswapDai | Operation::ApproveBridgeDai | Operation::ApproveToken
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum TransferStatus {
+    /// The operation has started but has not finished yet, it may or may not have a tx hash
+    Pending,
+    Succeeded,
+    Failed,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JournalEntry {
+    pub id: u64,
+    pub operation: Operation,
+    pub status: TransferStatus,
+    /// The amount that went into the operation, in wei of whatever is being sold or bridged
+    pub amount: Uint256,
+    /// Where the proceeds go if that isn't `own_address`
+    #[serde(default)]
+    pub recipient: Option<Address>,
+    /// The token involved if it isn't Dai
+    #[serde(default)]
+    pub token: Option<Address>,
+    /// What we expected to get out of the operation, if it has a quote
+    pub quote: Option<Uint256>,
+    /// What we actually got out of the operation
+    pub received: Option<Uint256>,
+    pub tx_hash: Option<Uint256>,
+    pub gas_used: Option<Uint256>,
+    /// In wei of whatever the transaction's chain pays gas in
+    #[serde(default)]
+    pub gas_price: Option<Uint256>,
+    /// Dai per ETH scaled by `PRICE_SCALE` when the operation finished, for putting costs in
+    /// both ETH and Dai terms
+    #[serde(default)]
+    pub eth_price: Option<Uint256>,
+    pub error: Option<String>,
+    /// Unix timestamp in seconds
+    pub started_at: u64,
+    /// Unix timestamp in seconds
+    pub updated_at: u64,
+}
+
+#[derive(Clone)]
+pub struct Journal {
+    path: PathBuf,
+    lock: Arc<Mutex<()>>,
+}
+
+impl Journal {
+    /// The file is created the first time something is written to it
+    pub fn new<P: Into<PathBuf>>(path: P) -> Journal {
+        Journal {
+            path: path.into(),
+            lock: Arc::new(Mutex::new(())),
+        }
+    }
+
+    pub fn append(&self, entry: &JournalEntry) -> Result<(), Error> {
+        let mut line = serde_json::to_string(entry)?;
+        line.push('\n');
+
+        let _guard = self.lock.lock().unwrap();
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        file.write_all(line.as_bytes())?;
+        Ok(())
+    }
+
+    /// The current state of every operation in the journal, oldest first. Lines that can't be
+    /// decoded, such as one cut short by a crash mid-write, are skipped with a warning.
+    pub fn entries(&self) -> Result<Vec<JournalEntry>, Error> {
+        let _guard = self.lock.lock().unwrap();
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut order = Vec::new();
+        let mut latest: HashMap<u64, JournalEntry> = HashMap::new();
+        for (index, line) in BufReader::new(file).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let entry: JournalEntry = match serde_json::from_str(&line) {
+                Ok(entry) => entry,
+                Err(e) => {
+                    tracing::warn!(
+                        path = %self.path.display(),
+                        line = index + 1,
+                        error = %e,
+                        "skipping undecodable journal line"
+                    );
+                    continue;
+                }
+            };
+            if !latest.contains_key(&entry.id) {
+                order.push(entry.id);
+            }
+            latest.insert(entry.id, entry);
+        }
+
+        Ok(order
+            .into_iter()
+            .filter_map(|id| latest.remove(&id))
+            .collect())
+    }
+
+    /// Operations with the given status (or any status) that were started within the given
+    /// range of unix timestamps, both ends are inclusive
+    pub fn query(
+        &self,
+        status: Option<TransferStatus>,
+        since: Option<u64>,
+        until: Option<u64>,
+    ) -> Result<Vec<JournalEntry>, Error> {
+        Ok(self
+            .entries()?
+            .into_iter()
+            .filter(|entry| match status {
+                Some(status) => entry.status == status,
+                None => true,
+            })
+            .filter(|entry| match since {
+                Some(since) => entry.started_at >= since,
+                None => true,
+            })
+            .filter(|entry| match until {
+                Some(until) => entry.started_at <= until,
+                None => true,
+            })
+            .collect())
+    }
+}
+
+/// What an operation's transaction cost, as much of it as could be found out
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct TxCosts {
+    pub gas_used: Option<Uint256>,
+    pub gas_price: Option<Uint256>,
+    pub eth_price: Option<Uint256>,
+}
+
+/// Follows a single operation through the futures that make it up, writing a new journal line
+/// each time something is learned about it. Only keeps track in memory if the bridge has no
+/// journal. Each operation also gets a tracing span, `finish_record` runs the operation's
+/// futures inside it so everything they log can be tied back to the operation.
+#[derive(Clone)]
+pub(crate) struct JournalRecorder {
+    journal: Option<Journal>,
+    events: Option<EventBus>,
+    span: Span,
+    entry: Arc<Mutex<JournalEntry>>,
+}
+
+impl JournalRecorder {
+    pub fn new(
+        journal: Option<Journal>,
+        operation: Operation,
+        amount: Uint256,
+        recipient: Option<Address>,
+        token: Option<Address>,
+    ) -> JournalRecorder {
+        let now = unix_time();
+        let id = rand::random();
+        let span = tracing::info_span!(
+            "bridge_operation",
+            id,
+            operation = ?operation,
+            chain = operation.chain(),
+            amount = %amount,
+            recipient = field::Empty,
+            token = field::Empty,
+            quote = field::Empty,
+            tx_hash = field::Empty,
+            attempt = 1u32,
+        );
+        if let Some(recipient) = recipient {
+            span.record("recipient", field::display(recipient));
+        }
+        if let Some(token) = token {
+            span.record("token", field::display(token));
+        }
+
+        let recorder = JournalRecorder {
+            journal,
+            events: None,
+            span,
+            entry: Arc::new(Mutex::new(JournalEntry {
+                id,
+                operation,
+                status: TransferStatus::Pending,
+                amount,
+                recipient,
+                token,
+                quote: None,
+                received: None,
+                tx_hash: None,
+                gas_used: None,
+                gas_price: None,
+                eth_price: None,
+                error: None,
+                started_at: now,
+                updated_at: now,
+            })),
+        };
+        recorder.update(|_| ());
+        recorder
+    }
+
+    /// Also announces submitted transactions on `events`
+    pub fn with_events(mut self, events: EventBus) -> JournalRecorder {
+        self.events = Some(events);
+        self
+    }
+
+    pub fn span(&self) -> Span {
+        self.span.clone()
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.journal.is_some()
+    }
+
+    pub fn operation(&self) -> Operation {
+        self.entry.lock().unwrap().operation
+    }
+
+    pub fn entry(&self) -> JournalEntry {
+        self.entry.lock().unwrap().clone()
+    }
+
+    pub fn tx_hash(&self) -> Option<Uint256> {
+        self.entry.lock().unwrap().tx_hash.clone()
+    }
+
+    pub fn quote(&self) -> Option<Uint256> {
+        self.entry.lock().unwrap().quote.clone()
+    }
+
+    pub fn quoted(&self, quote: Uint256) {
+        self.span.record("quote", field::display(&quote));
+        tracing::debug!(parent: &self.span, quote = %quote, "quoted");
+        self.update(|entry| entry.quote = Some(quote))
+    }
+
+    pub fn submitted(&self, tx_hash: Uint256) {
+        let hash = format_hash(&tx_hash);
+        self.span.record("tx_hash", field::display(&hash));
+        tracing::info!(parent: &self.span, tx_hash = %hash, "transaction submitted");
+        self.update(|entry| entry.tx_hash = Some(tx_hash.clone()));
+
+        if let Some(ref events) = self.events {
+            let entry = self.entry();
+            events.emit(Event::TxSubmitted {
+                operation: entry.operation,
+                tx_hash,
+                amount: entry.amount,
+            });
+        }
+    }
+
+    pub fn succeeded(&self, received: Uint256, costs: TxCosts) {
+        tracing::info!(
+            parent: &self.span,
+            received = %received,
+            gas_used = ?costs.gas_used.as_ref().map(|gas| gas.to_string()),
+            "operation succeeded"
+        );
+        self.update(|entry| {
+            entry.status = TransferStatus::Succeeded;
+            entry.received = Some(received);
+            entry.gas_used = costs.gas_used;
+            entry.gas_price = costs.gas_price;
+            entry.eth_price = costs.eth_price;
+        })
+    }
+
+    pub fn failed(&self, error: &Error) {
+        tracing::warn!(parent: &self.span, error = %error, "operation failed");
+        self.update(|entry| {
+            entry.status = TransferStatus::Failed;
+            entry.error = Some(error.to_string());
+        })
+    }
+
+    fn update<F: FnOnce(&mut JournalEntry)>(&self, change: F) {
+        let mut entry = self.entry.lock().unwrap();
+        change(&mut entry);
+        entry.updated_at = unix_time();
+
+        if let Some(ref journal) = self.journal {
+            // The journal is for bookkeeping, failing to write it should not fail the transfer
+            if let Err(e) = journal.append(&entry) {
+                error!("Failed to write to the transfer journal {:?}", e);
+            }
+        }
+    }
+}
+
+/// Runs something made of several operations under `span`. The operations' own spans nest under
+/// it whether they are started while building the future or later on while it runs.
+pub(crate) fn in_span<T, F>(span: Span, make: F) -> Box<dyn Future<Item = T, Error = Error>>
+where
+    T: 'static,
+    F: FnOnce() -> Box<dyn Future<Item = T, Error = Error>>,
+{
+    let future = span.in_scope(make);
+    Box::new(future.instrument(span))
+}
+
+pub(crate) fn format_hash(hash: &Uint256) -> String {
+    let bytes: [u8; 32] = hash.clone().into();
+    format!("0x{}", bytes_to_hex_str(&bytes))
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn test_journal_query() {
+        let path = env::temp_dir().join(format!("auto_bridge_journal_{}", rand::random::<u64>()));
+        let journal = Journal::new(path.clone());
+
+        let swap = JournalRecorder::new(
+            Some(journal.clone()),
+            Operation::EthToDaiSwap,
+            1000u64.into(),
+            None,
+            None,
+        );
+        swap.quoted(900u64.into());
+        swap.submitted(1u64.into());
+        swap.succeeded(
+            950u64.into(),
+            TxCosts {
+                gas_used: Some(50_000u64.into()),
+                ..Default::default()
+            },
+        );
+
+        let deposit = JournalRecorder::new(
+            Some(journal.clone()),
+            Operation::DaiToXdaiDeposit,
+            500u64.into(),
+            None,
+            None,
+        );
+        deposit.failed(&failure::err_msg("node went away"));
+
+        let entries = journal.entries().unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].operation, Operation::EthToDaiSwap);
+        assert_eq!(entries[0].received, Some(950u64.into()));
+        assert_eq!(entries[1].error, Some("node went away".to_string()));
+
+        let failed = journal
+            .query(Some(TransferStatus::Failed), None, None)
+            .unwrap();
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].operation, Operation::DaiToXdaiDeposit);
+        assert!(journal
+            .query(None, Some(entries[0].started_at + 3600), None)
+            .unwrap()
+            .is_empty());
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_journal_truncated_line() {
+        let path = env::temp_dir().join(format!("auto_bridge_journal_{}", rand::random::<u64>()));
+        let journal = Journal::new(path.clone());
+
+        let swap = JournalRecorder::new(
+            Some(journal.clone()),
+            Operation::EthToDaiSwap,
+            1000u64.into(),
+            None,
+            None,
+        );
+        swap.submitted(1u64.into());
+
+        // A write cut short by a crash leaves a partial line at the end of the file
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"{\"id\":12,\"operation\":\"EthToDa")
+            .unwrap();
+
+        let entries = journal.entries().unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].operation, Operation::EthToDaiSwap);
+        assert_eq!(entries[0].tx_hash, Some(1u64.into()));
+
+        fs::remove_file(path).unwrap();
+    }
+}
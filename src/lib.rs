@@ -1,5 +1,30 @@
 #[macro_use]
 extern crate log;
+#[macro_use]
+extern crate serde_derive;
+
+pub mod accounting;
+pub mod allowance;
+pub mod allowlist;
+pub mod amount;
+mod config;
+pub mod estimate;
+pub mod events;
+pub mod history;
+pub mod journal;
+pub mod limits;
+pub mod metrics;
+pub mod omnibridge;
+pub mod permit;
+pub mod plan;
+pub mod price;
+pub mod receipt;
+pub mod recovery;
+pub mod retry;
+pub mod scheduler;
+pub mod split;
+pub mod token;
+pub mod twap;
 
 use clarity::abi::encode_call;
 use clarity::{Address, PrivateKey};
@@ -9,10 +34,26 @@ use futures::Future;
 use futures_timer::FutureExt;
 use num::Bounded;
 use num256::Uint256;
-use std::time::Duration;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing_futures::Instrument;
 use web30::client::Web3;
 use web30::types::SendTxOption;
 
+use crate::allowance::AllowancePolicy;
+use crate::allowlist::RecipientAllowlist;
+use crate::amount::{DaiAmount, EthAmount, XdaiAmount};
+pub use crate::config::TokenBridgeConfig;
+use crate::events::{Event, EventBus};
+use crate::journal::{in_span, Journal, JournalRecorder, Operation, TxCosts};
+use crate::limits::{Asset, SpendingGuard};
+use crate::metrics::{FailureReason, MetricsSink};
+use crate::omnibridge::OmniBridge;
+use crate::price::{PriceSanityCheck, SwapDirection};
+use crate::retry::RetryPolicy;
+use crate::token::Token;
+use crate::twap::TwapCheck;
+
 #[derive(Clone)]
 pub struct TokenBridge {
     pub xdai_web3: Web3,
@@ -26,6 +67,37 @@ pub struct TokenBridge {
     pub foreign_dai_contract_address: Address,
     pub own_address: Address,
     pub secret: PrivateKey,
+    /// If set every swap, approval, deposit and withdrawal is recorded here
+    pub journal: Option<Journal>,
+    /// Swaps that would move the Uniswap price by more than this many basis points are refused
+    pub max_price_impact: Option<u64>,
+    /// If set swaps are aborted when the Uniswap quote is too far from an independent price
+    pub price_check: Option<PriceSanityCheck>,
+    /// If set swaps are aborted when the Uniswap quote is too far from the recent average price,
+    /// something has to be running `run_price_sampler` to fill in its history or every swap is
+    /// refused
+    pub twap_check: Option<TwapCheck>,
+    /// If set sends are capped and stop altogether once the circuit breaker trips
+    pub spending_guard: Option<SpendingGuard>,
+    /// If set funds can only be sent to `own_address` and the addresses listed here
+    pub recipient_allowlist: Option<RecipientAllowlist>,
+    /// How much Uniswap is approved to spend when a Dai swap needs more allowance
+    pub uniswap_allowance: AllowancePolicy,
+    /// Give Uniswap its unlimited allowance with a signed Dai permit instead of an approval
+    pub dai_permit: bool,
+    /// ERC20 tokens other than Dai that can be looked up with `token`
+    pub tokens: Vec<Token>,
+    /// The token bridge mediators, needed to bridge tokens other than Dai
+    pub omnibridge: Option<OmniBridge>,
+    /// What the xDai bridge keeps of each transfer, only used for estimates and cost reports
+    pub bridge_fee_basis_points: u64,
+    /// How reads and waits for transactions are retried when the node fails, they are tried once
+    /// if not set
+    pub retry_policy: Option<RetryPolicy>,
+    /// If set every operation recorded in the journal is also counted here
+    pub metrics: Option<Arc<dyn MetricsSink>>,
+    /// Where `subscribe` gets its events from, shared between clones of the bridge
+    pub events: EventBus,
 }
 
 impl TokenBridge {
@@ -48,45 +120,93 @@ impl TokenBridge {
             secret,
             xdai_web3: Web3::new(&xdai_full_node_url, Duration::from_secs(10)),
             eth_web3: Web3::new(&eth_full_node_url, Duration::from_secs(10)),
+            journal: None,
+            max_price_impact: None,
+            price_check: None,
+            twap_check: None,
+            spending_guard: None,
+            recipient_allowlist: None,
+            uniswap_allowance: AllowancePolicy::Unlimited,
+            dai_permit: false,
+            tokens: Vec::new(),
+            omnibridge: None,
+            bridge_fee_basis_points: 0,
+            retry_policy: None,
+            metrics: None,
+            events: EventBus::new(),
         }
     }
 
+    fn record(&self, operation: Operation, amount: Uint256) -> JournalRecorder {
+        JournalRecorder::new(self.journal.clone(), operation, amount, None, None)
+            .with_events(self.events.clone())
+    }
+
+    /// Only keeps the recipient in the journal if it isn't `own_address`
+    fn record_to(
+        &self,
+        operation: Operation,
+        amount: Uint256,
+        recipient: Address,
+    ) -> JournalRecorder {
+        let recipient = if recipient == self.own_address {
+            None
+        } else {
+            Some(recipient)
+        };
+        JournalRecorder::new(self.journal.clone(), operation, amount, recipient, None)
+            .with_events(self.events.clone())
+    }
+
     /// This just sends some Eth. Returns the tx hash.
     pub fn eth_transfer(
         &self,
         to: Address,
-        amount: Uint256,
+        amount: EthAmount,
         timeout: u64,
     ) -> Box<dyn Future<Item = (), Error = Error>> {
+        let amount = amount.into_wei();
+        if let Err(e) = self
+            .check_recipient(to)
+            .and_then(|_| self.authorize_spend(Asset::Eth, &amount))
+        {
+            return self.refuse_operation(Operation::EthTransfer, e);
+        }
         let web3 = self.eth_web3.clone();
         let own_address = self.own_address.clone();
         let secret = self.secret.clone();
+        let record = self.record_to(Operation::EthTransfer, amount.clone(), to);
+
+        let transfer = web3
+            .send_transaction(to, Vec::new(), amount.clone(), own_address, secret, vec![])
+            .and_then({
+                let salf = self.clone();
+                let record = record.clone();
+                move |tx_hash| {
+                    record.submitted(tx_hash.clone());
+                    salf.wait_for(&web3, tx_hash)
+                        .timeout(Duration::from_secs(timeout))
+                        .map(move |_| amount)
+                }
+            });
 
         Box::new(
-            web3.send_transaction(to, Vec::new(), amount, own_address, secret, vec![])
-                .and_then(move |tx_hash| {
-                    web3.wait_for_transaction(tx_hash.into())
-                        .timeout(Duration::from_secs(timeout));
-                    Ok(())
-                }),
+            self.finish_record(self.eth_web3.clone(), record, transfer)
+                .map(|_| ()),
         )
     }
 
     /// Price of ETH in Dai
     pub fn eth_to_dai_price(
         &self,
-        amount: Uint256,
+        amount: EthAmount,
     ) -> Box<dyn Future<Item = Uint256, Error = Error>> {
-        let web3 = self.eth_web3.clone();
-        let uniswap_address = self.uniswap_address.clone();
-        let own_address = self.own_address.clone();
-
         Box::new(
-            web3.contract_call(
-                uniswap_address,
+            self.read_call(
+                &self.eth_web3,
+                self.uniswap_address,
                 "getEthToTokenInputPrice(uint256)",
-                &[amount.into()],
-                own_address,
+                &[amount.into_wei().into()],
             )
             .and_then(move |tokens_bought| {
                 Ok(Uint256::from_bytes_be(match tokens_bought.get(0..32) {
@@ -103,18 +223,14 @@ impl TokenBridge {
     /// Price of Dai in Eth
     pub fn dai_to_eth_price(
         &self,
-        amount: Uint256,
+        amount: DaiAmount,
     ) -> Box<dyn Future<Item = Uint256, Error = Error>> {
-        let web3 = self.eth_web3.clone();
-        let uniswap_address = self.uniswap_address.clone();
-        let own_address = self.own_address.clone();
-
         Box::new(
-            web3.contract_call(
-                uniswap_address,
+            self.read_call(
+                &self.eth_web3,
+                self.uniswap_address,
                 "getTokenToEthInputPrice(uint256)",
-                &[amount.into()],
-                own_address,
+                &[amount.into_wei().into()],
             )
             .and_then(move |eth_bought| {
                 Ok(Uint256::from_bytes_be(match eth_bought.get(0..32) {
@@ -133,25 +249,74 @@ impl TokenBridge {
     /// to be accepted on the blockchain after this time.
     pub fn eth_to_dai_swap(
         &self,
-        eth_amount: Uint256,
+        eth_amount: EthAmount,
         timeout: u64,
     ) -> Box<dyn Future<Item = Uint256, Error = Error>> {
+        self.eth_to_dai_swap_to(self.own_address, eth_amount, timeout)
+    }
+
+    /// Sell `eth_amount` ETH for Dai that Uniswap sends straight to `recipient`, otherwise the
+    /// same as `eth_to_dai_swap`
+    pub fn eth_to_dai_swap_to(
+        &self,
+        recipient: Address,
+        eth_amount: EthAmount,
+        timeout: u64,
+    ) -> Box<dyn Future<Item = Uint256, Error = Error>> {
+        let eth_amount = eth_amount.into_wei();
+        if let Err(e) = self
+            .check_recipient(recipient)
+            .and_then(|_| self.authorize_spend(Asset::Eth, &eth_amount))
+        {
+            return self.refuse_operation(Operation::EthToDaiSwap, e);
+        }
         let uniswap_address = self.uniswap_address.clone();
         let own_address = self.own_address.clone();
         let secret = self.secret.clone();
         let web3 = self.eth_web3.clone();
-
-        Box::new(
-            web3.eth_get_latest_block()
-                .join(self.eth_to_dai_price(eth_amount.clone()))
-                .and_then(move |(block, expected_dai)| {
+        let record = self.record_to(Operation::EthToDaiSwap, eth_amount.clone(), recipient);
+
+        let swap = self
+            .check_swap(SwapDirection::EthToDai, eth_amount.clone())
+            .and_then({
+                let salf = self.clone();
+                let eth_amount = eth_amount.clone();
+                move |_| {
+                    salf.read_latest_block(&salf.eth_web3)
+                        .join(salf.eth_to_dai_price(EthAmount::from_wei(eth_amount.clone())))
+                        .and_then(move |(block, expected_dai)| {
+                            salf.check_quote(
+                                SwapDirection::EthToDai,
+                                eth_amount,
+                                expected_dai.clone(),
+                            )
+                            .map(move |_| (block, expected_dai))
+                        })
+                }
+            })
+            .and_then({
+                let salf = self.clone();
+                let record = record.clone();
+                move |(block, expected_dai)| {
+                    record.quoted(expected_dai.clone());
                     // Equivalent to `amount * (1 - 0.025)` without using decimals
                     let expected_dai = (expected_dai / 40u64.into()) * 39u64.into();
                     let deadline = block.timestamp + timeout.into();
-                    let payload = encode_call(
-                        "ethToTokenSwapInput(uint256,uint256)",
-                        &[expected_dai.clone().into(), deadline.into()],
-                    );
+                    let payload = if recipient == own_address {
+                        encode_call(
+                            "ethToTokenSwapInput(uint256,uint256)",
+                            &[expected_dai.clone().into(), deadline.into()],
+                        )
+                    } else {
+                        encode_call(
+                            "ethToTokenTransferInput(uint256,uint256,address)",
+                            &[
+                                expected_dai.clone().into(),
+                                deadline.into(),
+                                recipient.into(),
+                            ],
+                        )
+                    };
 
                     web3.send_transaction(
                         uniswap_address,
@@ -161,38 +326,25 @@ impl TokenBridge {
                         secret,
                         vec![SendTxOption::GasLimit(80_000u64.into())],
                     )
-                    .join(
-                        web3.wait_for_event_alt(
-                            uniswap_address,
-                            "TokenPurchase(address,uint256,uint256)",
-                            Some(vec![own_address.into()]),
-                            None,
-                            None,
-                            |_| true,
-                        )
-                        .timeout(Duration::from_secs(timeout)),
-                    )
-                    .and_then(move |(_tx, response)| {
-                        let transfered_dai = Uint256::from_bytes_be(&response.topics[3]);
-                        Ok(transfered_dai)
+                    .and_then(move |tx_hash| {
+                        record.submitted(tx_hash.clone());
+                        salf.wait_for_swap(SwapDirection::EthToDai, tx_hash, timeout)
                     })
-                }),
-        )
+                    .map(|result| result.bought)
+                }
+            });
+
+        self.finish_record(self.eth_web3.clone(), record, swap)
     }
 
     /// Checks if the uniswap contract has been approved to spend dai from our account.
     pub fn check_if_uniswap_dai_approved(&self) -> Box<dyn Future<Item = bool, Error = Error>> {
-        let web3 = self.eth_web3.clone();
-        let uniswap_address = self.uniswap_address.clone();
-        let dai_address = self.foreign_dai_contract_address.clone();
-        let own_address = self.own_address.clone();
-
         Box::new(
-            web3.contract_call(
-                dai_address,
+            self.read_call(
+                &self.eth_web3,
+                self.foreign_dai_contract_address,
                 "allowance(address,address)",
-                &[own_address.into(), uniswap_address.into()],
-                own_address,
+                &[self.own_address.into(), self.uniswap_address.into()],
             )
             .and_then(move |allowance| {
                 let allowance = Uint256::from_bytes_be(match allowance.get(0..32) {
@@ -216,37 +368,31 @@ impl TokenBridge {
         &self,
         timeout: Duration,
     ) -> Box<dyn Future<Item = (), Error = Error>> {
-        let dai_address = self.foreign_dai_contract_address.clone();
-        let own_address = self.own_address.clone();
-        let uniswap_address = self.uniswap_address.clone();
-        let secret = self.secret.clone();
-        let web3 = self.eth_web3.clone();
+        self.approve_dai_transfers(
+            Operation::ApproveUniswapDai,
+            self.uniswap_address,
+            Uint256::max_value(),
+            timeout,
+        )
+    }
 
-        let payload = encode_call(
-            "approve(address,uint256)",
-            &[uniswap_address.into(), Uint256::max_value().into()],
-        );
+    /// How much Dai `spender` is allowed to move out of our account
+    pub fn get_dai_allowance(
+        &self,
+        spender: Address,
+    ) -> Box<dyn Future<Item = Uint256, Error = Error>> {
+        self.get_token_allowance(&self.dai_token(), spender)
+    }
 
-        Box::new(
-            web3.send_transaction(
-                dai_address,
-                payload,
-                0u32.into(),
-                own_address,
-                secret,
-                vec![],
-            )
-            .join(web3.wait_for_event_alt(
-                dai_address,
-                "Approval(address,address,uint256)",
-                Some(vec![own_address.into()]),
-                Some(vec![uniswap_address.into()]),
-                None,
-                |_| true,
-            ))
-            .timeout(timeout)
-            .and_then(move |_| Ok(())),
-        )
+    /// Sets the allowance of `spender` to `amount` and waits for the Approval event
+    fn approve_dai_transfers(
+        &self,
+        operation: Operation,
+        spender: Address,
+        amount: Uint256,
+        timeout: Duration,
+    ) -> Box<dyn Future<Item = (), Error = Error>> {
+        self.send_approval(&self.dai_token(), operation, spender, amount, timeout)
     }
 
     /// Sell `dai_amount` Dai for ETH
@@ -254,44 +400,89 @@ impl TokenBridge {
     /// to be accepted on the blockchain after this time.
     pub fn dai_to_eth_swap(
         &self,
-        dai_amount: Uint256,
+        dai_amount: DaiAmount,
+        timeout: u64,
+    ) -> Box<dyn Future<Item = Uint256, Error = Error>> {
+        self.dai_to_eth_swap_to(self.own_address, dai_amount, timeout)
+    }
+
+    /// Sell `dai_amount` Dai for ETH that Uniswap sends straight to `recipient`, otherwise the
+    /// same as `dai_to_eth_swap`
+    pub fn dai_to_eth_swap_to(
+        &self,
+        recipient: Address,
+        dai_amount: DaiAmount,
         timeout: u64,
     ) -> Box<dyn Future<Item = Uint256, Error = Error>> {
+        let dai_amount = dai_amount.into_wei();
+        if let Err(e) = self
+            .check_recipient(recipient)
+            .and_then(|_| self.authorize_spend(Asset::Dai, &dai_amount))
+        {
+            return self.refuse_operation(Operation::DaiToEthSwap, e);
+        }
         let uniswap_address = self.uniswap_address.clone();
         let own_address = self.own_address.clone();
         let secret = self.secret.clone();
         let web3 = self.eth_web3.clone();
         let salf = self.clone();
-
-        Box::new(
-            self.check_if_uniswap_dai_approved()
-                .and_then({
-                    let salf = self.clone();
-                    move |is_approved| {
-                        trace!("uniswap approved {}", is_approved);
-                        if is_approved {
-                            Box::new(futures::future::ok(()))
-                                as Box<dyn Future<Item = (), Error = Error>>
-                        } else {
-                            salf.approve_uniswap_dai_transfers(Duration::from_secs(600))
-                        }
-                    }
-                })
-                .and_then(move |_| {
-                    web3.eth_get_latest_block()
-                        .join(salf.dai_to_eth_price(dai_amount.clone()))
+        let record = self.record_to(Operation::DaiToEthSwap, dai_amount.clone(), recipient);
+
+        let swap = self
+            .check_swap(SwapDirection::DaiToEth, dai_amount.clone())
+            .and_then({
+                let salf = self.clone();
+                let dai_amount = dai_amount.clone();
+                // The approval is part of the swap, so its span goes under the swap's
+                let span = record.span();
+                move |_| {
+                    in_span(span, || {
+                        salf.ensure_uniswap_dai_allowance(dai_amount, Duration::from_secs(600))
+                    })
+                }
+            })
+            .and_then({
+                let record = record.clone();
+                move |_| {
+                    salf.read_latest_block(&web3)
+                        .join(salf.dai_to_eth_price(DaiAmount::from_wei(dai_amount.clone())))
+                        .and_then({
+                            let salf = salf.clone();
+                            let dai_amount = dai_amount.clone();
+                            move |(block, expected_eth)| {
+                                salf.check_quote(
+                                    SwapDirection::DaiToEth,
+                                    dai_amount,
+                                    expected_eth.clone(),
+                                )
+                                .map(move |_| (block, expected_eth))
+                            }
+                        })
                         .and_then(move |(block, expected_eth)| {
+                            record.quoted(expected_eth.clone());
                             // Equivalent to `amount * (1 - 0.025)` without using decimals
                             let expected_eth = (expected_eth / 40u64.into()) * 39u64.into();
                             let deadline = block.timestamp + timeout.into();
-                            let payload = encode_call(
-                                "tokenToEthSwapInput(uint256,uint256,uint256)",
-                                &[
-                                    dai_amount.into(),
-                                    expected_eth.clone().into(),
-                                    deadline.into(),
-                                ],
-                            );
+                            let payload = if recipient == own_address {
+                                encode_call(
+                                    "tokenToEthSwapInput(uint256,uint256,uint256)",
+                                    &[
+                                        dai_amount.into(),
+                                        expected_eth.clone().into(),
+                                        deadline.into(),
+                                    ],
+                                )
+                            } else {
+                                encode_call(
+                                    "tokenToEthTransferInput(uint256,uint256,uint256,address)",
+                                    &[
+                                        dai_amount.into(),
+                                        expected_eth.clone().into(),
+                                        deadline.into(),
+                                        recipient.into(),
+                                    ],
+                                )
+                            };
 
                             web3.send_transaction(
                                 uniswap_address,
@@ -301,121 +492,339 @@ impl TokenBridge {
                                 secret,
                                 vec![SendTxOption::GasLimit(80_000u64.into())],
                             )
-                            .join(
-                                web3.wait_for_event_alt(
-                                    uniswap_address,
-                                    "EthPurchase(address,uint256,uint256)",
-                                    Some(vec![own_address.into()]),
-                                    None,
-                                    None,
-                                    |_| true,
-                                )
-                                .timeout(Duration::from_secs(timeout)),
-                            )
-                            .and_then(move |(_tx, response)| {
-                                let transfered_eth = Uint256::from_bytes_be(&response.topics[3]);
-                                Ok(transfered_eth)
+                            .and_then(move |tx_hash| {
+                                record.submitted(tx_hash.clone());
+                                salf.wait_for_swap(SwapDirection::DaiToEth, tx_hash, timeout)
                             })
+                            .map(|result| result.bought)
                         })
-                }),
-        )
+                }
+            });
+
+        self.finish_record(self.eth_web3.clone(), record, swap)
     }
 
     /// Bridge `dai_amount` dai to xdai
     pub fn dai_to_xdai_bridge(
         &self,
-        dai_amount: Uint256,
+        dai_amount: DaiAmount,
         timeout: u64,
     ) -> Box<dyn Future<Item = Uint256, Error = Error>> {
+        let dai_amount = dai_amount.into_wei();
+        if let Err(e) = self.authorize_spend(Asset::Dai, &dai_amount) {
+            return self.refuse_operation(Operation::DaiToXdaiDeposit, e);
+        }
         let eth_web3 = self.eth_web3.clone();
         let foreign_dai_contract_address = self.foreign_dai_contract_address.clone();
         let xdai_foreign_bridge_address = self.xdai_foreign_bridge_address.clone();
         let own_address = self.own_address.clone();
         let secret = self.secret.clone();
+        let record = self.record(Operation::DaiToXdaiDeposit, dai_amount.clone());
 
         // You basically just send it some coins
         // We have no idea when this has succeeded since the events are not indexed
-        Box::new(
-            eth_web3
-                .send_transaction(
-                    foreign_dai_contract_address,
-                    encode_call(
-                        "transfer(address,uint256)",
-                        &[
-                            xdai_foreign_bridge_address.into(),
-                            dai_amount.clone().into(),
-                        ],
-                    ),
-                    0u32.into(),
-                    own_address,
-                    secret,
-                    vec![SendTxOption::GasLimit(80_000u64.into())],
-                )
-                .and_then(move |tx_hash| {
+        let deposit = eth_web3
+            .send_transaction(
+                foreign_dai_contract_address,
+                encode_call(
+                    "transfer(address,uint256)",
+                    &[
+                        xdai_foreign_bridge_address.into(),
+                        dai_amount.clone().into(),
+                    ],
+                ),
+                0u32.into(),
+                own_address,
+                secret,
+                vec![SendTxOption::GasLimit(80_000u64.into())],
+            )
+            .and_then({
+                let salf = self.clone();
+                let record = record.clone();
+                move |tx_hash| {
+                    record.submitted(tx_hash.clone());
+                    salf.wait_for(&eth_web3, tx_hash)
+                        .timeout(Duration::from_secs(timeout))
+                        .map(move |_| dai_amount)
+                }
+            });
+
+        self.finish_record(self.eth_web3.clone(), record, deposit)
+    }
+
+    /// Bridge `dai_amount` Dai to xDai that is credited to `recipient` on the xDai chain. Unlike
+    /// `dai_to_xdai_bridge` this goes through the bridge's `relayTokens`, so the bridge is
+    /// approved to move the Dai first if it isn't already.
+    pub fn dai_to_xdai_bridge_to(
+        &self,
+        recipient: Address,
+        dai_amount: DaiAmount,
+        timeout: u64,
+    ) -> Box<dyn Future<Item = Uint256, Error = Error>> {
+        let dai_amount = dai_amount.into_wei();
+        if let Err(e) = self
+            .check_recipient(recipient)
+            .and_then(|_| self.authorize_spend(Asset::Dai, &dai_amount))
+        {
+            return self.refuse_operation(Operation::DaiToXdaiDeposit, e);
+        }
+        let eth_web3 = self.eth_web3.clone();
+        let xdai_foreign_bridge_address = self.xdai_foreign_bridge_address;
+        let own_address = self.own_address;
+        let secret = self.secret;
+        let record = self.record_to(Operation::DaiToXdaiDeposit, dai_amount.clone(), recipient);
+
+        let deposit = self
+            .get_dai_allowance(xdai_foreign_bridge_address)
+            .and_then({
+                let salf = self.clone();
+                let dai_amount = dai_amount.clone();
+                move |allowance| {
+                    if allowance >= dai_amount {
+                        Box::new(futures::future::ok(()))
+                            as Box<dyn Future<Item = (), Error = Error>>
+                    } else {
+                        salf.set_dai_allowance(
+                            Operation::ApproveBridgeDai,
+                            xdai_foreign_bridge_address,
+                            dai_amount,
+                            Duration::from_secs(timeout),
+                        )
+                    }
+                }
+            })
+            .and_then({
+                let salf = self.clone();
+                let record = record.clone();
+                move |_| {
                     eth_web3
-                        .wait_for_transaction(tx_hash.into())
-                        .timeout(Duration::from_secs(timeout));
-                    Ok(dai_amount)
-                }),
-        )
+                        .send_transaction(
+                            xdai_foreign_bridge_address,
+                            encode_call(
+                                "relayTokens(address,uint256)",
+                                &[recipient.into(), dai_amount.clone().into()],
+                            ),
+                            0u32.into(),
+                            own_address,
+                            secret,
+                            vec![SendTxOption::GasLimit(120_000u64.into())],
+                        )
+                        .and_then(move |tx_hash| {
+                            record.submitted(tx_hash.clone());
+                            salf.wait_for(&eth_web3, tx_hash)
+                                .timeout(Duration::from_secs(timeout))
+                                .map(move |_| dai_amount)
+                        })
+                }
+            });
+
+        self.finish_record(self.eth_web3.clone(), record, deposit)
     }
 
-    /// Bridge `xdai_amount` xdai to dai
+    /// Bridge `xdai_amount` xdai to dai, resolving to the tx hash once the withdrawal is mined
+    /// on xDai. The Dai is paid out on Eth later, once the validators have signed it.
     pub fn xdai_to_dai_bridge(
         &self,
-        xdai_amount: Uint256,
+        xdai_amount: XdaiAmount,
+        timeout: u64,
     ) -> Box<dyn Future<Item = Uint256, Error = Error>> {
+        let xdai_amount = xdai_amount.into_wei();
+        if let Err(e) = self.authorize_spend(Asset::Xdai, &xdai_amount) {
+            return self.refuse_operation(Operation::XdaiToDaiWithdrawal, e);
+        }
         let xdai_web3 = self.xdai_web3.clone();
 
         let xdai_home_bridge_address = self.xdai_home_bridge_address.clone();
 
         let own_address = self.own_address.clone();
         let secret = self.secret.clone();
+        let record = self.record(Operation::XdaiToDaiWithdrawal, xdai_amount.clone());
 
         // You basically just send it some coins
-        Box::new(xdai_web3.send_transaction(
-            xdai_home_bridge_address,
-            Vec::new(),
-            xdai_amount,
-            own_address,
-            secret,
-            vec![
-                SendTxOption::GasPrice(10_000_000_000u128.into()),
-                SendTxOption::NetworkId(100u64),
-            ],
-        ))
+        let withdrawal = xdai_web3
+            .send_transaction(
+                xdai_home_bridge_address,
+                Vec::new(),
+                xdai_amount.clone(),
+                own_address,
+                secret,
+                vec![
+                    SendTxOption::GasPrice(10_000_000_000u128.into()),
+                    SendTxOption::NetworkId(100u64),
+                ],
+            )
+            .and_then({
+                let salf = self.clone();
+                let record = record.clone();
+                move |tx_hash| {
+                    record.submitted(tx_hash.clone());
+                    salf.wait_for(&xdai_web3, tx_hash)
+                        .timeout(Duration::from_secs(timeout))
+                        .map(move |_| xdai_amount)
+                }
+            });
+
+        // The journal records the amount withdrawn rather than the tx hash this returns
+        Box::new(
+            self.finish_record(self.xdai_web3.clone(), record.clone(), withdrawal)
+                .and_then(move |_| match record.tx_hash() {
+                    Some(tx_hash) => Ok(tx_hash),
+                    None => bail!("Withdrawal finished without a tx hash"),
+                }),
+        )
+    }
+
+    /// Writes the outcome of an operation to the journal once it finishes, looking up the gas
+    /// used by its transaction if it got far enough to send one. The outcome also goes to the
+    /// circuit breaker.
+    fn finish_record<F>(
+        &self,
+        web3: Web3,
+        record: JournalRecorder,
+        operation: F,
+    ) -> Box<dyn Future<Item = Uint256, Error = Error>>
+    where
+        F: Future<Item = Uint256, Error = Error> + 'static,
+    {
+        let guard = self.spending_guard.clone();
+        let operation_kind = record.operation();
+        let started = Instant::now();
+        self.metrics_attempted(operation_kind);
+        let salf = self.clone();
+
+        let span = record.span();
+
+        Box::new(
+            operation
+                .then(
+                    move |res| -> Box<dyn Future<Item = Uint256, Error = Error>> {
+                        if let Some(guard) = guard {
+                            match res {
+                                Ok(ref received) => {
+                                    guard.record_success(record.quote().as_ref(), received)
+                                }
+                                Err(ref e) => guard.record_failure(e),
+                            }
+                        }
+                        match (res, record.tx_hash()) {
+                            (Ok(received), Some(tx_hash))
+                                if record.is_enabled()
+                                    || salf.metrics.is_some()
+                                    || salf.events.has_subscribers() =>
+                            {
+                                // Only the journal keeps the gas and ETH prices
+                                let costs = salf.get_tx_costs(web3, tx_hash, record.is_enabled());
+                                Box::new(costs.map(move |costs| {
+                                    salf.metrics_succeeded(
+                                        operation_kind,
+                                        started.elapsed(),
+                                        costs.gas_used.as_ref(),
+                                        record.quote().as_ref(),
+                                        &received,
+                                    );
+                                    record.succeeded(received.clone(), costs);
+                                    salf.emit_completed(&record);
+                                    received
+                                }))
+                            }
+                            (Ok(received), _) => {
+                                salf.metrics_succeeded(
+                                    operation_kind,
+                                    started.elapsed(),
+                                    None,
+                                    record.quote().as_ref(),
+                                    &received,
+                                );
+                                record.succeeded(received.clone(), TxCosts::default());
+                                salf.emit_completed(&record);
+                                Box::new(futures::future::ok(received))
+                            }
+                            (Err(e), tx_hash) => {
+                                salf.metrics_failed(
+                                    operation_kind,
+                                    FailureReason::classify(&e, tx_hash.is_some()),
+                                );
+                                record.failed(&e);
+                                salf.events.emit(Event::OperationFailed {
+                                    operation: operation_kind,
+                                    tx_hash,
+                                    error: e.to_string(),
+                                });
+                                Box::new(futures::future::err(e))
+                            }
+                        }
+                    },
+                )
+                .instrument(span),
+        )
+    }
+
+    /// Announces a finished operation, every mined transaction gets a `TxMined` and swaps,
+    /// deposits and withdrawals get an event saying what they did
+    fn emit_completed(&self, record: &JournalRecorder) {
+        let entry = record.entry();
+        if let Some(ref tx_hash) = entry.tx_hash {
+            self.events.emit(Event::TxMined {
+                operation: entry.operation,
+                tx_hash: tx_hash.clone(),
+                gas_used: entry.gas_used.clone(),
+            });
+        }
+
+        let received = entry.received.unwrap_or_else(|| 0u8.into());
+        match entry.operation {
+            Operation::EthToDaiSwap
+            | Operation::DaiToEthSwap
+            | Operation::EthToTokenSwap
+            | Operation::TokenToEthSwap => self.events.emit(Event::SwapCompleted {
+                operation: entry.operation,
+                tx_hash: entry.tx_hash,
+                token: entry.token,
+                sold: entry.amount,
+                bought: received,
+            }),
+            Operation::DaiToXdaiDeposit | Operation::TokenToXdaiDeposit => {
+                self.events.emit(Event::DepositSubmitted {
+                    operation: entry.operation,
+                    tx_hash: entry.tx_hash,
+                    token: entry.token,
+                    recipient: entry.recipient.unwrap_or(self.own_address),
+                    amount: received,
+                })
+            }
+            Operation::ExecuteWithdrawal => self.events.emit(Event::WithdrawalExecuted {
+                tx_hash: entry.tx_hash,
+                token: entry.token,
+                amount: received,
+            }),
+            _ => {}
+        }
+    }
+
+    pub fn get_eth_balance(&self) -> Box<dyn Future<Item = Uint256, Error = Error>> {
+        let web3 = self.eth_web3.clone();
+        let own_address = self.own_address;
+        self.retry_read("eth_getBalance", move || web3.eth_get_balance(own_address))
+    }
+
+    pub fn get_xdai_balance(&self) -> Box<dyn Future<Item = Uint256, Error = Error>> {
+        let web3 = self.xdai_web3.clone();
+        let own_address = self.own_address;
+        self.retry_read("eth_getBalance", move || web3.eth_get_balance(own_address))
     }
 
     pub fn get_dai_balance(
         &self,
         address: Address,
     ) -> Box<dyn Future<Item = Uint256, Error = Error>> {
-        let web3 = self.eth_web3.clone();
-        let dai_address = self.foreign_dai_contract_address;
-        let own_address = self.own_address;
-        Box::new(
-            web3.contract_call(
-                dai_address,
-                "balanceOf(address)",
-                &[address.into()],
-                own_address,
-            )
-            .and_then(|balance| {
-                Ok(Uint256::from_bytes_be(match balance.get(0..32) {
-                    Some(val) => val,
-                    None => bail!(
-                        "Got bad output for DAI balance from the full node {:?}",
-                        balance
-                    ),
-                }))
-            }),
-        )
+        self.get_token_balance(&self.dai_token(), address)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::amount::{DaiAmount, EthAmount, XdaiAmount};
     use actix;
     use std::str::FromStr;
 
@@ -438,11 +847,6 @@ mod tests {
         )
     }
 
-    fn eth_to_wei(eth: f64) -> Uint256 {
-        let wei = (eth * 1000000000000000000f64) as u64;
-        wei.into()
-    }
-
     #[test]
     fn test_is_approved() {
         let pk = PrivateKey::from_str(&format!(
@@ -495,9 +899,9 @@ mod tests {
 
         actix::spawn(
             token_bridge
-                .dai_to_eth_price(eth_to_wei(0.01f64))
+                .dai_to_eth_price(DaiAmount::from_str("0.01").unwrap())
                 .and_then(move |one_cent_in_eth| {
-                    token_bridge.eth_to_dai_swap(one_cent_in_eth.clone(), 600)
+                    token_bridge.eth_to_dai_swap(EthAmount::from_wei(one_cent_in_eth), 600)
                 })
                 .then(|res| {
                     res.unwrap();
@@ -517,7 +921,9 @@ mod tests {
         actix::spawn(
             token_bridge
                 .approve_uniswap_dai_transfers(Duration::from_secs(600))
-                .and_then(move |_| token_bridge.dai_to_eth_swap(eth_to_wei(0.01f64), 600))
+                .and_then(move |_| {
+                    token_bridge.dai_to_eth_swap(DaiAmount::from_str("0.01").unwrap(), 600)
+                })
                 .then(|res| {
                     res.unwrap();
                     actix::System::current().stop();
@@ -538,7 +944,7 @@ mod tests {
             token_bridge
                 // All we can really do here is test that it doesn't throw. Check your balances in
                 // 5-10 minutes to see if the money got transferred.
-                .dai_to_xdai_bridge(eth_to_wei(0.01f64), 600)
+                .dai_to_xdai_bridge(DaiAmount::from_str("0.01").unwrap(), 600)
                 .then(|res| {
                     res.unwrap();
                     actix::System::current().stop();
@@ -559,7 +965,7 @@ mod tests {
             token_bridge
                 // All we can really do here is test that it doesn't throw. Check your balances in
                 // 5-10 minutes to see if the money got transferred.
-                .xdai_to_dai_bridge(eth_to_wei(0.01f64))
+                .xdai_to_dai_bridge(XdaiAmount::from_str("0.01").unwrap(), 600)
                 .then(|res| {
                     res.unwrap();
                     actix::System::current().stop();
@@ -0,0 +1,533 @@
+//! Caps on how much the bridge can send and a circuit breaker that stops all sends after repeated
+//! failures or a suspicious loss, so a bug in whatever drives the bridge can't drain the wallet.
+//! What was spent and whether the breaker tripped can be kept in a file so a restart doesn't
+//! start the day's limits over or reopen a tripped breaker, and so every process sending from
+//! the same wallet shares them.
+
+use crate::metrics::FailureReason;
+use crate::TokenBridge;
+use clarity::Address;
+use failure::bail;
+use failure::Error;
+use fs2::FileExt;
+use num256::Uint256;
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+const HOUR: Duration = Duration::from_secs(60 * 60);
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Asset {
+    Eth,
+    Dai,
+    Xdai,
+    /// Any other ERC20 on Eth, only tokens with limits of their own can be sent
+    Token(Address),
+}
+
+/// Caps in wei, anything left unset is unlimited
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct AssetLimits {
+    #[serde(default)]
+    pub per_operation: Option<Uint256>,
+    #[serde(default)]
+    pub per_hour: Option<Uint256>,
+    #[serde(default)]
+    pub per_day: Option<Uint256>,
+}
+
+/// Caps for an ERC20 other than Dai, in the token's smallest unit
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TokenLimits {
+    pub address: Address,
+    #[serde(flatten)]
+    pub limits: AssetLimits,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SpendingLimits {
+    #[serde(default)]
+    pub eth: AssetLimits,
+    #[serde(default)]
+    pub dai: AssetLimits,
+    #[serde(default)]
+    pub xdai: AssetLimits,
+    /// Tokens that aren't listed here can't be sent at all while limits are set
+    #[serde(default)]
+    pub tokens: Vec<TokenLimits>,
+    /// Trip the circuit breaker after this many operations in a row fail
+    #[serde(default)]
+    pub max_consecutive_failures: Option<u32>,
+    /// Trip the circuit breaker if an operation receives more than this many basis points less
+    /// than it was quoted
+    #[serde(default)]
+    pub max_loss: Option<u64>,
+}
+
+impl SpendingLimits {
+    fn for_asset(&self, asset: Asset) -> Option<&AssetLimits> {
+        match asset {
+            Asset::Eth => Some(&self.eth),
+            Asset::Dai => Some(&self.dai),
+            Asset::Xdai => Some(&self.xdai),
+            Asset::Token(address) => self
+                .tokens
+                .iter()
+                .find(|token| token.address == address)
+                .map(|token| &token.limits),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Spend {
+    asset: Asset,
+    amount: Uint256,
+    at: SystemTime,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct GuardState {
+    /// Everything spent in the last day, oldest first
+    spends: VecDeque<Spend>,
+    consecutive_failures: u32,
+    /// Why the circuit breaker tripped, if it has
+    tripped: Option<String>,
+}
+
+/// Enforces `SpendingLimits`, shared between every clone of the bridge it's set on
+#[derive(Clone)]
+pub struct SpendingGuard {
+    pub limits: SpendingLimits,
+    /// Where the state is kept between runs and shared with other processes, it only lives in
+    /// memory if not set
+    path: Option<PathBuf>,
+    state: Arc<Mutex<GuardState>>,
+}
+
+impl SpendingGuard {
+    pub fn new(limits: SpendingLimits) -> SpendingGuard {
+        SpendingGuard {
+            limits,
+            path: None,
+            state: Arc::new(Mutex::new(GuardState::default())),
+        }
+    }
+
+    /// A guard that keeps its state at `path`. The file is locked, read and rewritten on every
+    /// check and change, so every process using the same file sees the same spends and the
+    /// same circuit breaker.
+    pub fn with_state_file<P: Into<PathBuf>>(limits: SpendingLimits, path: P) -> SpendingGuard {
+        SpendingGuard {
+            path: Some(path.into()),
+            ..SpendingGuard::new(limits)
+        }
+    }
+
+    /// Why the circuit breaker tripped, `None` if sends are allowed. A state file that can't be
+    /// read counts as tripped since what it held is unknown.
+    pub fn tripped(&self) -> Option<String> {
+        match self.modify(|state| Ok(state.tripped.clone())) {
+            Ok(tripped) => tripped,
+            Err(e) => Some(format!("the spending state can't be read {}", e)),
+        }
+    }
+
+    /// Allows sends again after the circuit breaker has tripped
+    pub fn reset(&self) {
+        let reset = self.modify(|state| {
+            if let Some(ref reason) = state.tripped {
+                info!("Circuit breaker reset, it was tripped because {}", reason);
+            }
+            state.tripped = None;
+            state.consecutive_failures = 0;
+            Ok(())
+        });
+        if let Err(e) = reset {
+            error!("Can't reset the circuit breaker {:?}", e);
+        }
+    }
+
+    /// How much of `asset` has been sent within `window`, windows longer than a day only see
+    /// the last day
+    pub fn spent(&self, asset: Asset, window: Duration) -> Result<Uint256, Error> {
+        self.modify(|state| Ok(spent_since(&state.spends, asset, window, SystemTime::now())))
+    }
+
+    /// Fails if the circuit breaker has tripped
+    pub fn check_circuit_breaker(&self) -> Result<(), Error> {
+        match self.tripped() {
+            Some(reason) => bail!(
+                "Circuit breaker tripped because {}, it must be reset before sending anything",
+                reason
+            ),
+            None => Ok(()),
+        }
+    }
+
+    /// Checks that sending `amount` of `asset` is within the limits and counts it against them
+    /// if it is. The amount is counted whether or not the operation ends up succeeding.
+    pub fn authorize(&self, asset: Asset, amount: &Uint256) -> Result<(), Error> {
+        self.authorize_at(asset, amount, SystemTime::now())
+    }
+
+    fn authorize_at(&self, asset: Asset, amount: &Uint256, now: SystemTime) -> Result<(), Error> {
+        let limits = match self.limits.for_asset(asset) {
+            Some(limits) => limits,
+            None => bail!(
+                "There are no spending limits for {:?}, it can't be sent until some are set",
+                asset
+            ),
+        };
+
+        self.modify(|state| {
+            if let Some(ref reason) = state.tripped {
+                bail!(
+                    "Circuit breaker tripped because {}, it must be reset before sending anything",
+                    reason
+                );
+            }
+            while let Some(true) = state
+                .spends
+                .front()
+                .map(|spend| elapsed(spend.at, now) >= DAY)
+            {
+                state.spends.pop_front();
+            }
+
+            if let Some(ref max) = limits.per_operation {
+                if amount > max {
+                    bail!(
+                        "Sending {} {:?} is over the per operation limit of {}",
+                        amount,
+                        asset,
+                        max
+                    );
+                }
+            }
+            for (window, max, name) in [
+                (HOUR, limits.per_hour.as_ref(), "hourly"),
+                (DAY, limits.per_day.as_ref(), "daily"),
+            ] {
+                if let Some(max) = max {
+                    let spent = spent_since(&state.spends, asset, window, now);
+                    if spent.clone() + amount.clone() > *max {
+                        bail!(
+                            "Sending {} {:?} would go over the {} limit of {}, {} has been sent \
+                             already",
+                            amount,
+                            asset,
+                            name,
+                            max,
+                            spent
+                        );
+                    }
+                }
+            }
+
+            state.spends.push_back(Spend {
+                asset,
+                amount: amount.clone(),
+                at: now,
+            });
+            Ok(())
+        })
+    }
+
+    /// Trips the circuit breaker if we got far less than we were quoted
+    pub fn record_success(&self, quote: Option<&Uint256>, received: &Uint256) {
+        let max_loss = self.limits.max_loss;
+        let recorded = self.modify(|state| {
+            state.consecutive_failures = 0;
+
+            if let (Some(max_loss), Some(quote)) = (max_loss, quote) {
+                let minimum =
+                    quote.clone() * (10_000 - max_loss.min(10_000)).into() / 10_000u64.into();
+                if *received < minimum {
+                    let reason = format!("we received {} when we were quoted {}", received, quote);
+                    error!("Tripping circuit breaker, {}", reason);
+                    state.tripped = Some(reason);
+                }
+            }
+            Ok(())
+        });
+        if let Err(e) = recorded {
+            error!("Can't record a success with the circuit breaker {:?}", e);
+        }
+    }
+
+    /// Trips the circuit breaker if too many operations have failed in a row
+    pub fn record_failure(&self, error: &Error) {
+        let max_failures = self.limits.max_consecutive_failures;
+        let recorded = self.modify(|state| {
+            state.consecutive_failures += 1;
+
+            if let Some(max) = max_failures {
+                if state.consecutive_failures >= max && state.tripped.is_none() {
+                    let reason = format!(
+                        "{} operations failed in a row, the last with {}",
+                        state.consecutive_failures, error
+                    );
+                    error!("Tripping circuit breaker, {}", reason);
+                    state.tripped = Some(reason);
+                }
+            }
+            Ok(())
+        });
+        if let Err(e) = recorded {
+            error!("Can't record a failure with the circuit breaker {:?}", e);
+        }
+    }
+
+    /// Runs `change` on the current state. With a state file the file is locked for the whole
+    /// change, read first and written back if `change` succeeds, so changes made by other
+    /// processes aren't lost.
+    fn modify<T, F>(&self, change: F) -> Result<T, Error>
+    where
+        F: FnOnce(&mut GuardState) -> Result<T, Error>,
+    {
+        let mut state = self.state.lock().unwrap();
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return change(&mut state),
+        };
+
+        let lock = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path.with_extension("lock"))?;
+        lock.lock_exclusive()?;
+        *state = load_state(path)?;
+        let result = change(&mut state)?;
+        save_state(path, &state)?;
+        Ok(result)
+    }
+}
+
+/// Writes to a temporary file first so a crash can't leave half written state
+fn save_state(path: &Path, state: &GuardState) -> Result<(), Error> {
+    let temporary = path.with_extension("tmp");
+    fs::write(&temporary, serde_json::to_vec_pretty(state)?)?;
+    fs::rename(&temporary, path)?;
+    Ok(())
+}
+
+fn load_state(path: &Path) -> Result<GuardState, Error> {
+    match File::open(path) {
+        Ok(file) => Ok(serde_json::from_reader(file)?),
+        Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(GuardState::default()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Time from `then` to `now`, zero if the clock went backwards in between
+fn elapsed(then: SystemTime, now: SystemTime) -> Duration {
+    now.duration_since(then).unwrap_or_default()
+}
+
+fn spent_since(
+    spends: &VecDeque<Spend>,
+    asset: Asset,
+    window: Duration,
+    now: SystemTime,
+) -> Uint256 {
+    spends
+        .iter()
+        .filter(|spend| spend.asset == asset && elapsed(spend.at, now) < window)
+        .fold(0u8.into(), |total: Uint256, spend| {
+            total + spend.amount.clone()
+        })
+}
+
+impl TokenBridge {
+    /// Checks `amount` against the spending limits, if there are any
+    pub(crate) fn authorize_spend(&self, asset: Asset, amount: &Uint256) -> Result<(), Error> {
+        match self.spending_guard {
+            Some(ref guard) => guard
+                .authorize(asset, amount)
+                .map_err(|e| FailureReason::SpendingLimit.refuse(e)),
+            None => Ok(()),
+        }
+    }
+
+    /// For sends that don't move any value but still shouldn't happen once the breaker trips
+    pub(crate) fn check_circuit_breaker(&self) -> Result<(), Error> {
+        match self.spending_guard {
+            Some(ref guard) => guard
+                .check_circuit_breaker()
+                .map_err(|e| FailureReason::SpendingLimit.refuse(e)),
+            None => Ok(()),
+        }
+    }
+
+    /// Allows sends again after the circuit breaker has tripped
+    pub fn reset_circuit_breaker(&self) {
+        if let Some(ref guard) = self.spending_guard {
+            guard.reset();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_spending_limits() {
+        let guard = SpendingGuard::new(SpendingLimits {
+            eth: AssetLimits {
+                per_operation: Some(100u64.into()),
+                per_hour: Some(150u64.into()),
+                per_day: Some(250u64.into()),
+            },
+            ..Default::default()
+        });
+        let start = SystemTime::now();
+
+        assert!(guard
+            .authorize_at(Asset::Eth, &101u64.into(), start)
+            .is_err());
+        assert!(guard
+            .authorize_at(Asset::Eth, &100u64.into(), start)
+            .is_ok());
+        assert!(guard
+            .authorize_at(Asset::Eth, &60u64.into(), start)
+            .is_err());
+        assert!(guard.authorize_at(Asset::Eth, &50u64.into(), start).is_ok());
+        // Other assets have no limits
+        assert!(guard
+            .authorize_at(Asset::Dai, &1000u64.into(), start)
+            .is_ok());
+
+        let later = start + HOUR;
+        assert!(guard
+            .authorize_at(Asset::Eth, &100u64.into(), later)
+            .is_ok());
+        // Within the hourly limit but not the daily one
+        assert!(guard.authorize_at(Asset::Eth, &1u64.into(), later).is_err());
+
+        let tomorrow = start + DAY;
+        assert!(guard
+            .authorize_at(Asset::Eth, &100u64.into(), tomorrow)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_token_limits() {
+        let listed = Address::from_str("0x79AE13432950bF5CDC3499f8d4Cf5963c3F0d42c").unwrap();
+        let unlisted = Address::from_str("0x4aa42145Aa6Ebf72e164C9bBC74fbD3788045016").unwrap();
+        let guard = SpendingGuard::new(SpendingLimits {
+            tokens: vec![TokenLimits {
+                address: listed,
+                limits: AssetLimits {
+                    per_operation: Some(100u64.into()),
+                    ..Default::default()
+                },
+            }],
+            ..Default::default()
+        });
+
+        assert!(guard
+            .authorize(Asset::Token(listed), &100u64.into())
+            .is_ok());
+        assert!(guard
+            .authorize(Asset::Token(listed), &101u64.into())
+            .is_err());
+        assert!(guard
+            .authorize(Asset::Token(unlisted), &1u64.into())
+            .is_err());
+    }
+
+    #[test]
+    fn test_circuit_breaker() {
+        let guard = SpendingGuard::new(SpendingLimits {
+            max_consecutive_failures: Some(2),
+            max_loss: Some(500),
+            ..Default::default()
+        });
+        let error = failure::err_msg("tx failed");
+
+        guard.record_failure(&error);
+        guard.record_success(Some(&1000u64.into()), &950u64.into());
+        guard.record_failure(&error);
+        assert!(guard.tripped().is_none());
+        guard.record_failure(&error);
+        assert!(guard.tripped().is_some());
+        assert!(guard.authorize(Asset::Eth, &1u64.into()).is_err());
+
+        guard.reset();
+        assert!(guard.authorize(Asset::Eth, &1u64.into()).is_ok());
+
+        guard.record_success(Some(&1000u64.into()), &949u64.into());
+        assert!(guard.tripped().is_some());
+    }
+
+    #[test]
+    fn test_spending_state_file() {
+        let path = env::temp_dir().join(format!("auto_bridge_spending_{}", rand::random::<u64>()));
+        let limits = SpendingLimits {
+            eth: AssetLimits {
+                per_day: Some(100u64.into()),
+                ..Default::default()
+            },
+            max_consecutive_failures: Some(1),
+            ..Default::default()
+        };
+        let guard = SpendingGuard::with_state_file(limits.clone(), path.clone());
+        guard.authorize(Asset::Eth, &60u64.into()).unwrap();
+        guard.record_failure(&failure::err_msg("tx failed"));
+
+        // A guard started later on the same file remembers the spend and the tripped breaker
+        let restarted = SpendingGuard::with_state_file(limits, path.clone());
+        assert_eq!(restarted.spent(Asset::Eth, DAY).unwrap(), 60u64.into());
+        assert!(restarted.tripped().is_some());
+        restarted.reset();
+        assert!(restarted.authorize(Asset::Eth, &41u64.into()).is_err());
+        assert!(restarted.authorize(Asset::Eth, &40u64.into()).is_ok());
+
+        fs::remove_file(path.with_extension("lock")).unwrap();
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_shared_spending_state() {
+        let path = env::temp_dir().join(format!("auto_bridge_spending_{}", rand::random::<u64>()));
+        let limits = SpendingLimits {
+            eth: AssetLimits {
+                per_day: Some(100u64.into()),
+                ..Default::default()
+            },
+            max_consecutive_failures: Some(1),
+            ..Default::default()
+        };
+        // Two processes running at once, each with its own guard on the same file
+        let cli = SpendingGuard::with_state_file(limits.clone(), path.clone());
+        let scheduler = SpendingGuard::with_state_file(limits, path.clone());
+        assert_eq!(scheduler.spent(Asset::Eth, DAY).unwrap(), 0u8.into());
+
+        cli.authorize(Asset::Eth, &60u64.into()).unwrap();
+        assert_eq!(scheduler.spent(Asset::Eth, DAY).unwrap(), 60u64.into());
+        assert!(scheduler.authorize(Asset::Eth, &41u64.into()).is_err());
+        scheduler.authorize(Asset::Eth, &40u64.into()).unwrap();
+        assert_eq!(cli.spent(Asset::Eth, DAY).unwrap(), 100u64.into());
+
+        // A reset from one isn't undone by the other's next change
+        scheduler.record_failure(&failure::err_msg("tx failed"));
+        assert!(cli.tripped().is_some());
+        cli.reset();
+        scheduler.record_success(None, &1u8.into());
+        assert!(cli.tripped().is_none());
+        assert!(scheduler.tripped().is_none());
+
+        fs::remove_file(path.with_extension("lock")).unwrap();
+        fs::remove_file(path).unwrap();
+    }
+}
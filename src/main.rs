@@ -0,0 +1,575 @@
+//! Command line interface for manual bridge operations, everything is driven by the same config
+//! file format `TokenBridgeConfig` reads. Amounts are given in ETH, Dai or xDai, `1.5` rather
+//! than wei.
+
+#[macro_use]
+extern crate serde_derive;
+#[macro_use]
+extern crate serde_json;
+
+use auto_bridge::amount::{Amount, DaiAmount, Denomination, Eth, EthAmount, Xdai, XdaiAmount};
+use auto_bridge::estimate::TransferDirection;
+use auto_bridge::journal::TransferStatus;
+use auto_bridge::price::SwapDirection;
+use auto_bridge::scheduler::{JobAction, JobQueue, JobRetry, Schedule, Scheduler};
+use auto_bridge::{TokenBridge, TokenBridgeConfig};
+use clarity::utils::{bytes_to_hex_str, hex_str_to_bytes};
+use clarity::Address;
+use docopt::Docopt;
+use failure::bail;
+use failure::Error;
+use futures::{future, Future};
+use num256::Uint256;
+use serde_json::Value;
+use std::io::{self, BufRead, Write};
+use std::process;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const USAGE: &str = "
+Usage:
+  auto-bridge [options] quote (eth-to-dai | dai-to-eth) <amount>
+  auto-bridge [options] swap [--to=<address>] (eth-to-dai | dai-to-eth) <amount>
+  auto-bridge [options] estimate (eth-to-xdai | xdai-to-eth) <amount>
+  auto-bridge [options] approve [<amount>]
+  auto-bridge [options] revoke
+  auto-bridge [options] deposit [--to=<address> | --split] <amount>
+  auto-bridge [options] withdraw [--split] <amount>
+  auto-bridge [options] top-up [--to=<address>] [--dry-run] <amount>
+  auto-bridge [options] balances
+  auto-bridge [options] status [--xdai] <tx-hash>
+  auto-bridge [options] journal [--status=<status>] [--since=<time>] [--until=<time>]
+  auto-bridge [options] costs [--since=<time>] [--until=<time>] [--csv]
+  auto-bridge [options] pending-withdrawals [--complete] <from-block> <to-block>
+  auto-bridge [options] schedule (eth-to-dai | dai-to-eth | deposit | withdraw) [--to=<address>] [--at=<time>] [--every=<sec>] <amount>
+  auto-bridge [options] jobs
+  auto-bridge [options] cancel <job-id>
+  auto-bridge [options] run-scheduler [--interval=<sec>]
+  auto-bridge (-h | --help)
+
+Commands:
+  quote      Get the Uniswap price for selling <amount> ETH or Dai
+  swap       Sell <amount> ETH or Dai on Uniswap
+  estimate   Work out what moving <amount> ETH to xDai or <amount> xDai to
+             ETH would cost in swap fees, bridge fees and gas
+  approve    Approve Uniswap to spend <amount> of our Dai, or any amount if
+             not given
+  revoke     Take away Uniswap's approval to spend our Dai
+  deposit    Bridge <amount> Dai to xDai
+  withdraw   Bridge <amount> xDai to Dai
+  top-up     Buy and bridge enough Dai for <amount> xDai to arrive
+  balances   Show our ETH, Dai, xDai and configured token balances
+  status     Check if a transfer transaction is still pending
+  journal    List operations recorded in the transfer journal
+  costs      Report what the operations in the journal cost in slippage, gas
+             and bridge fees
+  pending-withdrawals
+             List withdrawals sent to the bridge between two xDai blocks that
+             were never paid out on Eth
+  schedule   Queue a swap, deposit or withdrawal of <amount> to run later or
+             repeatedly
+  jobs       List queued jobs
+  cancel     Cancel a queued job
+  run-scheduler
+             Run queued jobs as they fall due
+
+Options:
+  -c, --config=<path>  Path to the config file [default: auto-bridge.toml]
+  -t, --timeout=<sec>  How long to wait for transactions [default: 600]
+  -y, --yes            Do not ask for confirmation before sending transactions
+  --to=<address>       Send the proceeds to this address instead of our own
+  --xdai               Look the transaction up on the xDai chain instead of Eth
+  --status=<status>    Only list Pending, Succeeded or Failed operations
+  --since=<time>       Only list operations started at or after this unix time
+  --until=<time>       Only list operations started at or before this unix time
+  --complete           Execute pending withdrawals the validators have signed
+  --csv                Print one CSV line per operation instead of the report
+  --dry-run            Only show the plan, don't send anything
+  --at=<time>          Unix time to run a scheduled job at, now if not given
+  --every=<sec>        Run a scheduled job again this many seconds after each run
+  --interval=<sec>     How often to check for due jobs [default: 60]
+  --split              Send the amount in as many transfers as the bridge's
+                       limits need, waiting for the daily limit to reset
+  --json               Print results as JSON
+  -h, --help           Show this message
+";
+
+#[derive(Debug, Deserialize)]
+struct Args {
+    cmd_quote: bool,
+    cmd_swap: bool,
+    cmd_eth_to_dai: bool,
+    cmd_estimate: bool,
+    cmd_eth_to_xdai: bool,
+    cmd_approve: bool,
+    cmd_revoke: bool,
+    cmd_deposit: bool,
+    cmd_withdraw: bool,
+    cmd_top_up: bool,
+    cmd_balances: bool,
+    cmd_status: bool,
+    cmd_journal: bool,
+    cmd_costs: bool,
+    cmd_pending_withdrawals: bool,
+    cmd_schedule: bool,
+    cmd_jobs: bool,
+    cmd_cancel: bool,
+    cmd_run_scheduler: bool,
+    arg_amount: Option<String>,
+    arg_tx_hash: Option<String>,
+    arg_from_block: Option<String>,
+    arg_to_block: Option<String>,
+    arg_job_id: Option<u64>,
+    flag_config: String,
+    flag_timeout: u64,
+    flag_yes: bool,
+    flag_to: Option<String>,
+    flag_xdai: bool,
+    flag_status: Option<String>,
+    flag_since: Option<u64>,
+    flag_until: Option<u64>,
+    flag_complete: bool,
+    flag_csv: bool,
+    flag_dry_run: bool,
+    flag_split: bool,
+    flag_at: Option<u64>,
+    flag_every: Option<u64>,
+    flag_interval: u64,
+    flag_json: bool,
+}
+
+fn main() {
+    env_logger::init();
+
+    let args: Args = Docopt::new(USAGE)
+        .and_then(|d| d.deserialize())
+        .unwrap_or_else(|e| e.exit());
+
+    if let Err(e) = run(&args) {
+        eprintln!("Error: {}", e);
+        process::exit(1);
+    }
+}
+
+fn run(args: &Args) -> Result<(), Error> {
+    let config = TokenBridgeConfig::load(&args.flag_config)?;
+    let bridge = TokenBridge::from_config(&config);
+    let timeout = args.flag_timeout;
+
+    if args.cmd_journal {
+        let journal = match bridge.journal {
+            Some(ref journal) => journal,
+            None => bail!("No journal_path set in the config file"),
+        };
+        let status = match args.flag_status {
+            Some(ref status) => Some(match status.as_str() {
+                "Pending" => TransferStatus::Pending,
+                "Succeeded" => TransferStatus::Succeeded,
+                "Failed" => TransferStatus::Failed,
+                other => bail!("Unknown status {}", other),
+            }),
+            None => None,
+        };
+        let entries = journal.query(status, args.flag_since, args.flag_until)?;
+        return print_output(args, &json!({ "entries": entries }));
+    }
+
+    if args.cmd_costs {
+        let report = bridge.get_cost_report(args.flag_since, args.flag_until)?;
+        if args.flag_csv {
+            print!("{}", report.to_csv());
+            return Ok(());
+        }
+        return print_output(args, &serde_json::to_value(report)?);
+    }
+
+    if args.cmd_schedule {
+        let action = if args.cmd_deposit {
+            let recipient = match args.flag_to {
+                Some(_) => Some(parse_recipient(&args.flag_to, &bridge)?),
+                None => None,
+            };
+            JobAction::DaiToXdaiDeposit {
+                amount: parse_amount(&args.arg_amount)?,
+                recipient,
+            }
+        } else if args.cmd_withdraw {
+            JobAction::XdaiToDaiWithdrawal {
+                amount: parse_amount(&args.arg_amount)?,
+            }
+        } else if args.cmd_eth_to_dai {
+            JobAction::EthToDaiSwap {
+                amount: parse_amount(&args.arg_amount)?,
+            }
+        } else {
+            JobAction::DaiToEthSwap {
+                amount: parse_amount(&args.arg_amount)?,
+            }
+        };
+        let at = match args.flag_at {
+            Some(at) => at,
+            None => SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+        };
+        let schedule = match args.flag_every {
+            Some(interval) => Schedule::Every {
+                start: at,
+                interval,
+            },
+            None => Schedule::Once { at },
+        };
+        let job = job_queue(&config)?.enqueue(
+            bridge.own_address,
+            action,
+            schedule,
+            JobRetry::default(),
+        )?;
+        return print_output(args, &serde_json::to_value(job)?);
+    }
+
+    if args.cmd_jobs {
+        let jobs = job_queue(&config)?.jobs()?;
+        return print_output(args, &json!({ "jobs": jobs }));
+    }
+
+    if args.cmd_cancel {
+        let id = match args.arg_job_id {
+            Some(id) => id,
+            None => bail!("No job id given"),
+        };
+        let job = job_queue(&config)?.cancel(id)?;
+        return print_output(args, &serde_json::to_value(job)?);
+    }
+
+    let mut system = actix::System::new("auto-bridge");
+    let action: Box<dyn Future<Item = Value, Error = Error>> = if args.cmd_quote {
+        if args.cmd_eth_to_dai {
+            let amount: EthAmount = parse_amount(&args.arg_amount)?;
+            Box::new(
+                bridge
+                    .eth_to_dai_price(amount.clone())
+                    .join(bridge.get_price_impact(SwapDirection::EthToDai, amount.wei().clone()))
+                    .map(move |(dai, impact)| {
+                        json!({
+                            "eth_sold": amount.wei().to_string(),
+                            "dai_bought": dai.to_string(),
+                            "price_impact_basis_points": impact.impact_basis_points,
+                        })
+                    }),
+            )
+        } else {
+            let amount: DaiAmount = parse_amount(&args.arg_amount)?;
+            Box::new(
+                bridge
+                    .dai_to_eth_price(amount.clone())
+                    .join(bridge.get_price_impact(SwapDirection::DaiToEth, amount.wei().clone()))
+                    .map(move |(eth, impact)| {
+                        json!({
+                            "dai_sold": amount.wei().to_string(),
+                            "eth_bought": eth.to_string(),
+                            "price_impact_basis_points": impact.impact_basis_points,
+                        })
+                    }),
+            )
+        }
+    } else if args.cmd_estimate {
+        let (direction, amount) = if args.cmd_eth_to_xdai {
+            (
+                TransferDirection::EthToXdai,
+                parse_amount::<Eth>(&args.arg_amount)?.into_wei(),
+            )
+        } else {
+            (
+                TransferDirection::XdaiToEth,
+                parse_amount::<Xdai>(&args.arg_amount)?.into_wei(),
+            )
+        };
+        Box::new(
+            bridge
+                .estimate_transfer(direction, amount)
+                .and_then(|estimate| Ok(serde_json::to_value(estimate)?)),
+        )
+    } else if args.cmd_swap {
+        let recipient = parse_recipient(&args.flag_to, &bridge)?;
+        if args.cmd_eth_to_dai {
+            let amount: EthAmount = parse_amount(&args.arg_amount)?;
+            confirm(
+                args,
+                &format!("Sell {} ETH for Dai sent to {}", amount, recipient),
+            )?;
+            let eth_sold = amount.wei().to_string();
+            Box::new(
+                bridge
+                    .eth_to_dai_swap_to(recipient, amount, timeout)
+                    .map(move |dai| json!({ "eth_sold": eth_sold, "dai_bought": dai.to_string() })),
+            )
+        } else {
+            let amount: DaiAmount = parse_amount(&args.arg_amount)?;
+            confirm(
+                args,
+                &format!("Sell {} Dai for ETH sent to {}", amount, recipient),
+            )?;
+            let dai_sold = amount.wei().to_string();
+            Box::new(
+                bridge
+                    .dai_to_eth_swap_to(recipient, amount, timeout)
+                    .map(move |eth| json!({ "dai_sold": dai_sold, "eth_bought": eth.to_string() })),
+            )
+        }
+    } else if args.cmd_approve {
+        let approval = match args.arg_amount {
+            Some(_) => {
+                let amount: DaiAmount = parse_amount(&args.arg_amount)?;
+                confirm(
+                    args,
+                    &format!("Approve Uniswap to spend {} Dai of ours", amount),
+                )?;
+                bridge.approve_uniswap_dai_amount(amount, Duration::from_secs(timeout))
+            }
+            None => {
+                confirm(args, "Approve Uniswap to spend our Dai")?;
+                bridge.approve_uniswap_dai_transfers(Duration::from_secs(timeout))
+            }
+        };
+        Box::new(approval.map(|_| json!({ "approved": true })))
+    } else if args.cmd_revoke {
+        confirm(args, "Revoke Uniswap's approval to spend our Dai")?;
+        Box::new(
+            bridge
+                .revoke_uniswap_dai_transfers(Duration::from_secs(timeout))
+                .map(|_| json!({ "approved": false })),
+        )
+    } else if args.cmd_deposit && args.flag_split {
+        let amount: DaiAmount = parse_amount(&args.arg_amount)?;
+        confirm(args, &format!("Bridge {} Dai to xDai in chunks", amount))?;
+        let (_, deposit) = bridge.dai_to_xdai_bridge_split(amount, timeout);
+        Box::new(deposit.and_then(|progress| Ok(serde_json::to_value(progress)?)))
+    } else if args.cmd_deposit {
+        let amount: DaiAmount = parse_amount(&args.arg_amount)?;
+        let deposit = match args.flag_to {
+            Some(_) => {
+                let recipient = parse_recipient(&args.flag_to, &bridge)?;
+                confirm(
+                    args,
+                    &format!("Bridge {} Dai to xDai at {}", amount, recipient),
+                )?;
+                bridge.dai_to_xdai_bridge_to(recipient, amount, timeout)
+            }
+            None => {
+                confirm(args, &format!("Bridge {} Dai to xDai", amount))?;
+                bridge.dai_to_xdai_bridge(amount, timeout)
+            }
+        };
+        Box::new(deposit.map(|dai| json!({ "dai_deposited": dai.to_string() })))
+    } else if args.cmd_withdraw && args.flag_split {
+        let amount: XdaiAmount = parse_amount(&args.arg_amount)?;
+        confirm(args, &format!("Bridge {} xDai to Dai in chunks", amount))?;
+        let (_, withdrawal) = bridge.xdai_to_dai_bridge_split(amount, timeout);
+        Box::new(withdrawal.and_then(|progress| Ok(serde_json::to_value(progress)?)))
+    } else if args.cmd_withdraw {
+        let amount: XdaiAmount = parse_amount(&args.arg_amount)?;
+        confirm(args, &format!("Bridge {} xDai to Dai", amount))?;
+        let xdai_withdrawn = amount.wei().to_string();
+        Box::new(
+            bridge
+                .xdai_to_dai_bridge(amount, timeout)
+                .map(move |tx_hash| {
+                    json!({ "xdai_withdrawn": xdai_withdrawn, "tx_hash": format_hash(tx_hash) })
+                }),
+        )
+    } else if args.cmd_top_up {
+        let amount: XdaiAmount = parse_amount(&args.arg_amount)?;
+        let recipient = parse_recipient(&args.flag_to, &bridge)?;
+        let plan = system.block_on(bridge.plan_xdai_top_up(recipient, amount))?;
+        if args.flag_dry_run {
+            return print_output(args, &serde_json::to_value(plan)?);
+        }
+        confirm(
+            args,
+            &format!(
+                "Sell {} ETH and bridge {} Dai to {}, spending {} ETH with gas",
+                EthAmount::from_wei(plan.eth_to_sell.clone()),
+                DaiAmount::from_wei(plan.dai_to_bridge.clone()),
+                recipient,
+                EthAmount::from_wei(plan.total_eth.clone())
+            ),
+        )?;
+        Box::new(
+            bridge
+                .execute_top_up(plan, timeout)
+                .and_then(|result| Ok(serde_json::to_value(result)?)),
+        )
+    } else if args.cmd_balances {
+        let tokens = bridge.tokens.clone();
+        Box::new(
+            bridge
+                .get_eth_balance()
+                .join4(
+                    bridge.get_dai_balance(bridge.own_address),
+                    bridge.get_xdai_balance(),
+                    future::join_all(
+                        bridge
+                            .tokens
+                            .iter()
+                            .map(|token| bridge.get_token_balance(token, bridge.own_address))
+                            .collect::<Vec<_>>(),
+                    ),
+                )
+                .map(move |(eth, dai, xdai, token_balances)| {
+                    let mut balances = json!({
+                        "eth": eth.to_string(),
+                        "dai": dai.to_string(),
+                        "xdai": xdai.to_string(),
+                    });
+                    for (token, balance) in tokens.iter().zip(token_balances) {
+                        balances[token.symbol.to_lowercase()] = json!(balance.to_string());
+                    }
+                    balances
+                }),
+        )
+    } else if args.cmd_status {
+        let tx_hash = parse_hash(&args.arg_tx_hash)?;
+        let web3 = if args.flag_xdai {
+            bridge.xdai_web3.clone()
+        } else {
+            bridge.eth_web3.clone()
+        };
+        Box::new(
+            web3.eth_get_transaction_by_hash(tx_hash.clone())
+                .map(move |tx| match tx {
+                    Some(tx) => match tx.block_number {
+                        Some(block) => json!({
+                            "tx_hash": format_hash(tx_hash),
+                            "status": "mined",
+                            "block": block.to_string(),
+                        }),
+                        None => json!({ "tx_hash": format_hash(tx_hash), "status": "pending" }),
+                    },
+                    None => json!({ "tx_hash": format_hash(tx_hash), "status": "unknown" }),
+                }),
+        )
+    } else if args.cmd_pending_withdrawals {
+        let from_block = parse_block(&args.arg_from_block)?;
+        let to_block = parse_block(&args.arg_to_block)?;
+        if args.flag_complete {
+            confirm(args, "Execute every signed pending withdrawal")?;
+            Box::new(
+                bridge
+                    .complete_pending_withdrawals(from_block, to_block, timeout)
+                    .map(|hashes| {
+                        let hashes: Vec<String> = hashes.into_iter().map(format_hash).collect();
+                        json!({ "completed": hashes })
+                    }),
+            )
+        } else {
+            Box::new(
+                bridge
+                    .get_pending_withdrawals(from_block, to_block)
+                    .map(|pending| json!({ "pending": pending })),
+            )
+        }
+    } else if args.cmd_run_scheduler {
+        let mut scheduler = Scheduler::new(job_queue(&config)?, timeout);
+        scheduler.add_account(bridge.clone());
+        if let (Some(twap), Some(check)) = (&config.twap_check, &bridge.twap_check) {
+            actix::spawn(
+                bridge
+                    .run_price_sampler(
+                        check.history.clone(),
+                        Duration::from_secs(twap.sample_interval),
+                        twap.sample_amount.clone(),
+                    )
+                    .map_err(|e| eprintln!("Price sampler stopped: {}", e)),
+            );
+        }
+        Box::new(
+            scheduler
+                .run(Duration::from_secs(args.flag_interval))
+                .map(|_| json!({})),
+        )
+    } else {
+        bail!("Unknown command");
+    };
+
+    let output = system.block_on(action)?;
+    print_output(args, &output)
+}
+
+fn job_queue(config: &TokenBridgeConfig) -> Result<JobQueue, Error> {
+    match config.job_queue_path {
+        Some(ref path) => Ok(JobQueue::new(path.clone())),
+        None => bail!("No job_queue_path set in the config file"),
+    }
+}
+
+/// Our own address unless another one was given
+fn parse_recipient(to: &Option<String>, bridge: &TokenBridge) -> Result<Address, Error> {
+    match to {
+        Some(to) => match to.parse() {
+            Ok(address) => Ok(address),
+            Err(_) => bail!("Invalid address {}", to),
+        },
+        None => Ok(bridge.own_address),
+    }
+}
+
+/// A decimal amount of whatever the command takes, like `1.5`
+fn parse_amount<D: Denomination>(amount: &Option<String>) -> Result<Amount<D>, Error> {
+    match amount {
+        Some(amount) => amount.parse(),
+        None => bail!("No amount given"),
+    }
+}
+
+fn parse_block(block: &Option<String>) -> Result<Uint256, Error> {
+    match block {
+        Some(block) => match block.parse() {
+            Ok(block) => Ok(block),
+            Err(_) => bail!("Invalid block number {}", block),
+        },
+        None => bail!("No block number given"),
+    }
+}
+
+fn parse_hash(hash: &Option<String>) -> Result<Uint256, Error> {
+    match hash {
+        Some(hash) => Ok(Uint256::from_bytes_be(&hex_str_to_bytes(
+            hash.trim_start_matches("0x"),
+        )?)),
+        None => bail!("No transaction hash given"),
+    }
+}
+
+fn format_hash(hash: Uint256) -> String {
+    let bytes: [u8; 32] = hash.into();
+    format!("0x{}", bytes_to_hex_str(&bytes))
+}
+
+/// Asks the user to confirm an operation that sends a transaction, unless `--yes` was passed
+fn confirm(args: &Args, description: &str) -> Result<(), Error> {
+    if args.flag_yes {
+        return Ok(());
+    }
+
+    eprint!("{}? [y/N] ", description);
+    io::stderr().flush()?;
+
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+    match answer.trim() {
+        "y" | "Y" | "yes" => Ok(()),
+        _ => bail!("Aborted"),
+    }
+}
+
+fn print_output(args: &Args, output: &Value) -> Result<(), Error> {
+    if args.flag_json {
+        println!("{}", serde_json::to_string_pretty(output)?);
+    } else if let Value::Object(fields) = output {
+        for (key, value) in fields {
+            match value {
+                Value::String(value) => println!("{}: {}", key, value),
+                value => println!("{}: {}", key, value),
+            }
+        }
+    }
+    Ok(())
+}
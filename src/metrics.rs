@@ -0,0 +1,379 @@
+//! Counters and histograms describing what the bridge is doing. Every operation that goes
+//! through the journal also reports to the bridge's `MetricsSink` if it has one, the
+//! `PrometheusSink` here keeps them in memory and renders them in the Prometheus text format for
+//! whatever is serving the scrape endpoint.
+
+use crate::journal::Operation;
+use crate::TokenBridge;
+use failure::Error;
+use futures::Future;
+use num256::Uint256;
+use std::collections::BTreeMap;
+use std::fmt::{self, Write};
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+pub const OPERATIONS_ATTEMPTED: &str = "bridge_operations_attempted_total";
+pub const OPERATIONS_SUCCEEDED: &str = "bridge_operations_succeeded_total";
+pub const OPERATIONS_FAILED: &str = "bridge_operations_failed_total";
+pub const CONFIRMATION_SECONDS: &str = "bridge_confirmation_seconds";
+pub const GAS_USED: &str = "bridge_gas_used";
+pub const SLIPPAGE_BASIS_POINTS: &str = "bridge_slippage_basis_points";
+
+/// Name, help text and histogram buckets of everything the bridge reports
+const DESCRIPTIONS: &[(&str, &str, &[f64])] = &[
+    (OPERATIONS_ATTEMPTED, "Operations started", &[]),
+    (OPERATIONS_SUCCEEDED, "Operations that completed", &[]),
+    (OPERATIONS_FAILED, "Operations that failed, by reason", &[]),
+    (
+        CONFIRMATION_SECONDS,
+        "Time from starting an operation to its transaction being confirmed",
+        &[5.0, 15.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1200.0],
+    ),
+    (
+        GAS_USED,
+        "Gas used by an operation's transaction",
+        &[
+            25_000.0, 50_000.0, 100_000.0, 150_000.0, 200_000.0, 300_000.0, 500_000.0,
+        ],
+    ),
+    (
+        SLIPPAGE_BASIS_POINTS,
+        "How much less a swap received than it was quoted, in basis points",
+        &[0.0, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 500.0],
+    ),
+];
+
+pub type Labels = Vec<(&'static str, String)>;
+
+/// Somewhere to send metrics, implement this to forward them to a different monitoring system
+pub trait MetricsSink: Send + Sync {
+    fn increment(&self, name: &'static str, labels: &Labels);
+    fn observe(&self, name: &'static str, labels: &Labels, value: f64);
+}
+
+/// Why an operation failed, kept coarse so the number of label values stays small
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailureReason {
+    /// Failed before a transaction was sent for a reason other than one of the checks below
+    NotSubmitted,
+    /// The quote was too far from the reference or average price or moved the price too much
+    PriceCheck,
+    /// Over a spending limit or the circuit breaker has tripped
+    SpendingLimit,
+    /// The recipient isn't on the allowlist
+    RecipientNotAllowed,
+    /// Gave up waiting for the transaction
+    Timeout,
+    /// The transaction was sent but reverted or its result couldn't be read
+    TransactionFailed,
+}
+
+impl FailureReason {
+    pub fn classify(error: &Error, submitted: bool) -> FailureReason {
+        if let Some(refusal) = error.downcast_ref::<Refusal>() {
+            return refusal.reason;
+        }
+        match error.downcast_ref::<io::Error>() {
+            Some(e) if e.kind() == io::ErrorKind::TimedOut => FailureReason::Timeout,
+            _ if submitted => FailureReason::TransactionFailed,
+            _ => FailureReason::NotSubmitted,
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            FailureReason::NotSubmitted => "not_submitted",
+            FailureReason::PriceCheck => "price_check",
+            FailureReason::SpendingLimit => "spending_limit",
+            FailureReason::RecipientNotAllowed => "recipient_not_allowed",
+            FailureReason::Timeout => "timeout",
+            FailureReason::TransactionFailed => "transaction_failed",
+        }
+    }
+
+    /// Marks `error` as one of the bridge's own checks refusing an operation for this reason
+    pub(crate) fn refuse(self, error: Error) -> Error {
+        Refusal {
+            reason: self,
+            error,
+        }
+        .into()
+    }
+}
+
+/// An error from one of the bridge's checks, it reads the same as the error it wraps
+#[derive(Debug)]
+pub struct Refusal {
+    pub reason: FailureReason,
+    error: Error,
+}
+
+impl fmt::Display for Refusal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.error)
+    }
+}
+
+impl std::error::Error for Refusal {}
+
+#[derive(Default)]
+struct Histogram {
+    buckets: Vec<(f64, u64)>,
+    sum: f64,
+    count: u64,
+}
+
+#[derive(Default)]
+struct PrometheusState {
+    counters: BTreeMap<(&'static str, Labels), u64>,
+    histograms: BTreeMap<(&'static str, Labels), Histogram>,
+}
+
+/// Keeps metrics in memory for a Prometheus scrape
+#[derive(Clone, Default)]
+pub struct PrometheusSink {
+    state: Arc<Mutex<PrometheusState>>,
+}
+
+impl PrometheusSink {
+    pub fn new() -> PrometheusSink {
+        PrometheusSink::default()
+    }
+
+    /// Everything recorded so far in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let state = self.state.lock().unwrap();
+        let mut out = String::new();
+
+        let mut last = None;
+        for ((name, labels), value) in state.counters.iter() {
+            if last != Some(*name) {
+                write_header(&mut out, name, "counter");
+                last = Some(*name);
+            }
+            writeln!(out, "{}{} {}", name, format_labels(labels, None), value).unwrap();
+        }
+
+        let mut last = None;
+        for ((name, labels), histogram) in state.histograms.iter() {
+            if last != Some(*name) {
+                write_header(&mut out, name, "histogram");
+                last = Some(*name);
+            }
+            for (bound, count) in histogram.buckets.iter() {
+                let le = format!("{}", bound);
+                writeln!(
+                    out,
+                    "{}_bucket{} {}",
+                    name,
+                    format_labels(labels, Some(&le)),
+                    count
+                )
+                .unwrap();
+            }
+            let labelled = format_labels(labels, None);
+            writeln!(
+                out,
+                "{}_bucket{} {}",
+                name,
+                format_labels(labels, Some("+Inf")),
+                histogram.count
+            )
+            .unwrap();
+            writeln!(out, "{}_sum{} {}", name, labelled, histogram.sum).unwrap();
+            writeln!(out, "{}_count{} {}", name, labelled, histogram.count).unwrap();
+        }
+
+        out
+    }
+}
+
+impl MetricsSink for PrometheusSink {
+    fn increment(&self, name: &'static str, labels: &Labels) {
+        let mut state = self.state.lock().unwrap();
+        *state.counters.entry((name, labels.clone())).or_insert(0) += 1;
+    }
+
+    fn observe(&self, name: &'static str, labels: &Labels, value: f64) {
+        let mut state = self.state.lock().unwrap();
+        let histogram = state
+            .histograms
+            .entry((name, labels.clone()))
+            .or_insert_with(|| Histogram {
+                buckets: describe(name).2.iter().map(|bound| (*bound, 0)).collect(),
+                ..Default::default()
+            });
+        for (bound, count) in histogram.buckets.iter_mut() {
+            if value <= *bound {
+                *count += 1;
+            }
+        }
+        histogram.sum += value;
+        histogram.count += 1;
+    }
+}
+
+fn describe(name: &str) -> (&'static str, &'static str, &'static [f64]) {
+    DESCRIPTIONS
+        .iter()
+        .find(|description| description.0 == name)
+        .cloned()
+        .unwrap_or(("", "", &[]))
+}
+
+fn write_header(out: &mut String, name: &str, kind: &str) {
+    let help = describe(name).1;
+    if !help.is_empty() {
+        writeln!(out, "# HELP {} {}", name, help).unwrap();
+    }
+    writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+}
+
+fn format_labels(labels: &[(&'static str, String)], le: Option<&str>) -> String {
+    let mut pairs: Vec<String> = labels
+        .iter()
+        .map(|(key, value)| format!("{}=\"{}\"", key, escape_label(value)))
+        .collect();
+    if let Some(le) = le {
+        pairs.push(format!("le=\"{}\"", le));
+    }
+    if pairs.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", pairs.join(","))
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn operation_labels(operation: Operation) -> Labels {
+    vec![("operation", format!("{:?}", operation))]
+}
+
+fn to_f64(value: &Uint256) -> f64 {
+    value.to_string().parse().unwrap_or(f64::MAX)
+}
+
+impl TokenBridge {
+    pub(crate) fn metrics_attempted(&self, operation: Operation) {
+        if let Some(ref sink) = self.metrics {
+            sink.increment(OPERATIONS_ATTEMPTED, &operation_labels(operation));
+        }
+    }
+
+    /// Records a finished operation, slippage is only known for swaps that had a quote
+    pub(crate) fn metrics_succeeded(
+        &self,
+        operation: Operation,
+        elapsed: Duration,
+        gas_used: Option<&Uint256>,
+        quote: Option<&Uint256>,
+        received: &Uint256,
+    ) {
+        let sink = match self.metrics {
+            Some(ref sink) => sink,
+            None => return,
+        };
+        let labels = operation_labels(operation);
+        sink.increment(OPERATIONS_SUCCEEDED, &labels);
+        sink.observe(CONFIRMATION_SECONDS, &labels, elapsed.as_secs_f64());
+        if let Some(gas_used) = gas_used {
+            sink.observe(GAS_USED, &labels, to_f64(gas_used));
+        }
+        if let Some(quote) = quote {
+            sink.observe(SLIPPAGE_BASIS_POINTS, &labels, slippage(quote, received));
+        }
+    }
+
+    /// An operation refused before it got as far as the journal still counts as attempted
+    pub(crate) fn refuse_operation<T: 'static>(
+        &self,
+        operation: Operation,
+        error: Error,
+    ) -> Box<dyn Future<Item = T, Error = Error>> {
+        self.metrics_attempted(operation);
+        self.metrics_failed(operation, FailureReason::classify(&error, false));
+        Box::new(futures::future::err(error))
+    }
+
+    pub(crate) fn metrics_failed(&self, operation: Operation, reason: FailureReason) {
+        if let Some(ref sink) = self.metrics {
+            let mut labels = operation_labels(operation);
+            labels.push(("reason", reason.label().to_string()));
+            sink.increment(OPERATIONS_FAILED, &labels);
+        }
+    }
+}
+
+/// How much less than `quote` we received in basis points, zero if we got at least the quote
+fn slippage(quote: &Uint256, received: &Uint256) -> f64 {
+    let quote = to_f64(quote);
+    if quote == 0.0 {
+        return 0.0;
+    }
+    ((quote - to_f64(received)) / quote * 10_000.0).max(0.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prometheus_render() {
+        let sink = PrometheusSink::new();
+        let labels = operation_labels(Operation::EthToDaiSwap);
+        sink.increment(OPERATIONS_ATTEMPTED, &labels);
+        sink.increment(OPERATIONS_ATTEMPTED, &labels);
+        sink.observe(GAS_USED, &labels, 60_000.0);
+        sink.observe(GAS_USED, &labels, 120_000.0);
+
+        let text = sink.render();
+        assert!(text.contains("# TYPE bridge_operations_attempted_total counter\n"));
+        assert!(text.contains("bridge_operations_attempted_total{operation=\"EthToDaiSwap\"} 2\n"));
+        assert!(text.contains("# TYPE bridge_gas_used histogram\n"));
+        assert!(
+            text.contains("bridge_gas_used_bucket{operation=\"EthToDaiSwap\",le=\"50000\"} 0\n")
+        );
+        assert!(
+            text.contains("bridge_gas_used_bucket{operation=\"EthToDaiSwap\",le=\"100000\"} 1\n")
+        );
+        assert!(text.contains("bridge_gas_used_bucket{operation=\"EthToDaiSwap\",le=\"+Inf\"} 2\n"));
+        assert!(text.contains("bridge_gas_used_sum{operation=\"EthToDaiSwap\"} 180000\n"));
+        assert!(text.contains("bridge_gas_used_count{operation=\"EthToDaiSwap\"} 2\n"));
+    }
+
+    #[test]
+    fn test_failure_reason() {
+        let timeout: Error = io::Error::new(io::ErrorKind::TimedOut, "timed out").into();
+        let other = failure::format_err!("Price moved too far");
+
+        assert_eq!(
+            FailureReason::classify(&timeout, true),
+            FailureReason::Timeout
+        );
+        assert_eq!(
+            FailureReason::classify(&other, true),
+            FailureReason::TransactionFailed
+        );
+        assert_eq!(
+            FailureReason::classify(&other, false),
+            FailureReason::NotSubmitted
+        );
+        let refused = FailureReason::PriceCheck.refuse(other);
+        assert_eq!(refused.to_string(), "Price moved too far");
+        assert_eq!(
+            FailureReason::classify(&refused, false),
+            FailureReason::PriceCheck
+        );
+        assert_eq!(escape_label("a\"b\\"), "a\\\"b\\\\");
+        assert_eq!(slippage(&10_000u64.into(), &9_950u64.into()), 50.0);
+        assert_eq!(slippage(&10_000u64.into(), &10_100u64.into()), 0.0);
+    }
+}
@@ -0,0 +1,495 @@
+//! Bridging ERC20 tokens other than Dai through the token bridge mediators (the OmniBridge).
+//! Tokens are deposited with the foreign mediator on Eth and the validators mint the bridged
+//! copy on xDai by themselves. Going back the bridged tokens are sent to the home mediator on
+//! xDai, but nobody executes that message on Eth for us, once the validators have signed it we
+//! have to claim the tokens with `claim_token_withdrawal`.
+
+use crate::history::decode_address;
+use crate::journal::{JournalRecorder, Operation};
+use crate::limits::Asset;
+use crate::recovery::{decode_signature, decode_uint, signature_indexes};
+use crate::token::Token;
+use crate::TokenBridge;
+use clarity::abi::{derive_signature, encode_call, Token as AbiToken};
+use clarity::Address;
+use failure::bail;
+use failure::Error;
+use futures::stream;
+use futures::{Future, Stream};
+use futures_timer::FutureExt;
+use num256::Uint256;
+use sha3::{Digest, Keccak256};
+use std::time::Duration;
+use web30::types::SendTxOption;
+
+const MESSAGE_EVENT: &str = "UserRequestForSignature(bytes32,bytes)";
+
+/// The contracts making up the token bridge, the mediators move the tokens and the arbitrary
+/// message bridges (AMB) carry messages between them
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct OmniBridge {
+    pub foreign_mediator: Address,
+    pub home_mediator: Address,
+    pub foreign_amb: Address,
+    pub home_amb: Address,
+}
+
+impl TokenBridge {
+    fn omnibridge(&self) -> Result<OmniBridge, Error> {
+        match self.omnibridge {
+            Some(omnibridge) => Ok(omnibridge),
+            None => bail!("No OmniBridge configured"),
+        }
+    }
+
+    /// Bridge `amount` of `token` to xDai, the mediator is approved to move exactly that much
+    /// first if it can't already
+    pub fn token_to_xdai_bridge(
+        &self,
+        token: &Token,
+        amount: Uint256,
+        timeout: u64,
+    ) -> Box<dyn Future<Item = Uint256, Error = Error>> {
+        self.token_to_xdai_bridge_to(token, self.own_address, amount, timeout)
+    }
+
+    /// Bridge `amount` of `token` to xDai to be credited to `recipient`
+    pub fn token_to_xdai_bridge_to(
+        &self,
+        token: &Token,
+        recipient: Address,
+        amount: Uint256,
+        timeout: u64,
+    ) -> Box<dyn Future<Item = Uint256, Error = Error>> {
+        let omnibridge = match self.omnibridge() {
+            Ok(omnibridge) => omnibridge,
+            Err(e) => return self.refuse_operation(Operation::TokenToXdaiDeposit, e),
+        };
+        if let Err(e) = self
+            .check_recipient(recipient)
+            .and_then(|_| self.authorize_spend(Asset::Token(token.address), &amount))
+        {
+            return self.refuse_operation(Operation::TokenToXdaiDeposit, e);
+        }
+        let eth_web3 = self.eth_web3.clone();
+        let own_address = self.own_address;
+        let secret = self.secret;
+        let token_address = token.address;
+        let recipient_entry = if recipient == own_address {
+            None
+        } else {
+            Some(recipient)
+        };
+        let record = JournalRecorder::new(
+            self.journal.clone(),
+            Operation::TokenToXdaiDeposit,
+            amount.clone(),
+            recipient_entry,
+            Some(token_address),
+        )
+        .with_events(self.events.clone());
+
+        let deposit = self
+            .get_token_allowance(token, omnibridge.foreign_mediator)
+            .and_then({
+                let salf = self.clone();
+                let token = token.clone();
+                let amount = amount.clone();
+                move |allowance| {
+                    if allowance >= amount {
+                        Box::new(futures::future::ok(()))
+                            as Box<dyn Future<Item = (), Error = Error>>
+                    } else {
+                        salf.set_token_allowance(
+                            &token,
+                            Operation::ApproveToken,
+                            omnibridge.foreign_mediator,
+                            amount,
+                            Duration::from_secs(timeout),
+                        )
+                    }
+                }
+            })
+            .and_then({
+                let salf = self.clone();
+                let record = record.clone();
+                move |_| {
+                    eth_web3
+                        .send_transaction(
+                            omnibridge.foreign_mediator,
+                            encode_call(
+                                "relayTokens(address,address,uint256)",
+                                &[
+                                    token_address.into(),
+                                    recipient.into(),
+                                    amount.clone().into(),
+                                ],
+                            ),
+                            0u32.into(),
+                            own_address,
+                            secret,
+                            vec![SendTxOption::GasLimit(300_000u64.into())],
+                        )
+                        .and_then(move |tx_hash| {
+                            record.submitted(tx_hash.clone());
+                            salf.wait_for(&eth_web3, tx_hash)
+                                .timeout(Duration::from_secs(timeout))
+                                .map(move |_| amount)
+                        })
+                }
+            });
+
+        self.finish_record(self.eth_web3.clone(), record, deposit)
+    }
+
+    /// Bridge `amount` of the xDai copy of `token` back to Eth. Returns the xDai tx hash, which
+    /// `claim_token_withdrawal` needs once the validators have signed the withdrawal.
+    pub fn xdai_to_token_bridge(
+        &self,
+        token: &Token,
+        amount: Uint256,
+        timeout: u64,
+    ) -> Box<dyn Future<Item = Uint256, Error = Error>> {
+        let omnibridge = match self.omnibridge() {
+            Ok(omnibridge) => omnibridge,
+            Err(e) => return self.refuse_operation(Operation::XdaiToTokenWithdrawal, e),
+        };
+        let xdai_token = match token.xdai_address {
+            Some(xdai_token) => xdai_token,
+            None => {
+                return self.refuse_operation(
+                    Operation::XdaiToTokenWithdrawal,
+                    failure::format_err!("{} has no xDai address configured", token.symbol),
+                )
+            }
+        };
+        if let Err(e) = self.authorize_spend(Asset::Token(token.address), &amount) {
+            return self.refuse_operation(Operation::XdaiToTokenWithdrawal, e);
+        }
+        let xdai_web3 = self.xdai_web3.clone();
+        let own_address = self.own_address;
+        let record = JournalRecorder::new(
+            self.journal.clone(),
+            Operation::XdaiToTokenWithdrawal,
+            amount.clone(),
+            None,
+            Some(token.address),
+        )
+        .with_events(self.events.clone());
+
+        // Bridged tokens are ERC677, sending them to the mediator with `transferAndCall` relays
+        // them to the address in the data without a separate approval
+        let withdrawal = xdai_web3
+            .send_transaction(
+                xdai_token,
+                encode_call(
+                    "transferAndCall(address,uint256,bytes)",
+                    &[
+                        omnibridge.home_mediator.into(),
+                        amount.clone().into(),
+                        AbiToken::UnboundedBytes(own_address.as_bytes().to_vec()),
+                    ],
+                ),
+                0u32.into(),
+                own_address,
+                self.secret,
+                vec![
+                    SendTxOption::GasPrice(10_000_000_000u128.into()),
+                    SendTxOption::NetworkId(100u64),
+                    SendTxOption::GasLimit(300_000u64.into()),
+                ],
+            )
+            .and_then({
+                let salf = self.clone();
+                let record = record.clone();
+                move |tx_hash| {
+                    record.submitted(tx_hash.clone());
+                    salf.wait_for(&xdai_web3, tx_hash)
+                        .timeout(Duration::from_secs(timeout))
+                        .map(move |_| amount)
+                }
+            });
+
+        Box::new(
+            self.finish_record(self.xdai_web3.clone(), record.clone(), withdrawal)
+                .and_then(move |_| match record.tx_hash() {
+                    Some(tx_hash) => Ok(tx_hash),
+                    None => bail!("Withdrawal finished without a tx hash"),
+                }),
+        )
+    }
+
+    /// Executes a token withdrawal made by `xdai_to_token_bridge` in the xDai tx `xdai_tx_hash`
+    /// on Eth, using the signatures the validators left on the home AMB. Returns the Eth tx hash.
+    pub fn claim_token_withdrawal(
+        &self,
+        xdai_tx_hash: Uint256,
+        timeout: u64,
+    ) -> Box<dyn Future<Item = Uint256, Error = Error>> {
+        let omnibridge = match self.omnibridge() {
+            Ok(omnibridge) => omnibridge,
+            Err(e) => return self.refuse_operation(Operation::ExecuteWithdrawal, e),
+        };
+        if let Err(e) = self.check_circuit_breaker() {
+            return self.refuse_operation(Operation::ExecuteWithdrawal, e);
+        }
+        let xdai_web3 = self.xdai_web3.clone();
+        let eth_web3 = self.eth_web3.clone();
+        let own_address = self.own_address;
+        let secret = self.secret;
+        let salf = self.clone();
+
+        Box::new(
+            self.retry_read("eth_getTransactionReceipt", {
+                let xdai_web3 = xdai_web3.clone();
+                let xdai_tx_hash = xdai_tx_hash.clone();
+                move || xdai_web3.eth_get_transaction_receipt(xdai_tx_hash.clone())
+            })
+            .and_then({
+                let xdai_tx_hash = xdai_tx_hash.clone();
+                move |receipt| {
+                    let receipt = match receipt {
+                        Some(receipt) => receipt,
+                        None => bail!("No receipt for xDai transaction {:?}", xdai_tx_hash),
+                    };
+                    let signature = derive_signature(MESSAGE_EVENT);
+                    match receipt.logs.iter().find(|log| {
+                        log.address == omnibridge.home_amb
+                            && log.topics.first().map(|topic| &topic[..]) == Some(&signature[..])
+                    }) {
+                        Some(log) => decode_bytes(&log.data),
+                        None => bail!(
+                            "xDai transaction {:?} did not send a message to Eth",
+                            xdai_tx_hash
+                        ),
+                    }
+                }
+            })
+            .and_then({
+                let salf = salf.clone();
+                let eth_web3 = eth_web3.clone();
+                move |message| {
+                    let (token, recipient, amount) = match decode_message_transfer(&message) {
+                        Ok(transfer) => transfer,
+                        Err(e) => {
+                            return Box::new(futures::future::err(e))
+                                as Box<dyn Future<Item = _, Error = Error>>
+                        }
+                    };
+                    if recipient != own_address {
+                        return Box::new(futures::future::err(failure::format_err!(
+                            "xDai transaction {:?} withdraws to {}, not to us",
+                            xdai_tx_hash,
+                            recipient
+                        )));
+                    }
+                    // AMB messages start with their id, executing one twice reverts so check first
+                    let message_id = Uint256::from_bytes_be(&message[..32]);
+                    Box::new(
+                        salf.read_call(
+                            &eth_web3,
+                            omnibridge.foreign_amb,
+                            "relayedMessages(bytes32)",
+                            &[message_id.into()],
+                        )
+                        .and_then(move |relayed| {
+                            if decode_uint(&relayed)? != 0u8.into() {
+                                bail!(
+                                    "Withdrawal in xDai transaction {:?} has already been executed",
+                                    xdai_tx_hash
+                                );
+                            }
+                            Ok((message, token, amount))
+                        }),
+                    )
+                }
+            })
+            .and_then({
+                let salf = salf.clone();
+                move |(message, token, amount)| {
+                    let message_hash = Uint256::from_bytes_be(&Keccak256::digest(&message));
+                    salf.read_call(&xdai_web3, omnibridge.home_amb, "requiredSignatures()", &[])
+                        .and_then(|required| decode_uint(&required))
+                        .and_then(|required| signature_indexes(&required))
+                        .and_then(move |indexes| {
+                            stream::iter_ok(indexes)
+                                .and_then(move |index| {
+                                    salf.read_call(
+                                        &xdai_web3,
+                                        omnibridge.home_amb,
+                                        "signature(bytes32,uint256)",
+                                        &[message_hash.clone().into(), index.into()],
+                                    )
+                                    .and_then(|signature| decode_signature(&signature))
+                                })
+                                .collect()
+                        })
+                        .map(move |signatures| (message, token, amount, signatures))
+                }
+            })
+            .and_then(move |(message, token, amount, signatures)| {
+                let record = JournalRecorder::new(
+                    salf.journal.clone(),
+                    Operation::ExecuteWithdrawal,
+                    amount.clone(),
+                    None,
+                    Some(token),
+                )
+                .with_events(salf.events.clone());
+
+                let payload = encode_call(
+                    "executeSignatures(bytes,bytes)",
+                    &[
+                        AbiToken::UnboundedBytes(message),
+                        AbiToken::UnboundedBytes(pack_signatures(&signatures)),
+                    ],
+                );
+
+                let execution = eth_web3
+                    .send_transaction(
+                        omnibridge.foreign_amb,
+                        payload,
+                        0u32.into(),
+                        own_address,
+                        secret,
+                        vec![SendTxOption::GasLimit(500_000u64.into())],
+                    )
+                    .and_then({
+                        let salf = salf.clone();
+                        let record = record.clone();
+                        move |tx_hash| {
+                            record.submitted(tx_hash.clone());
+                            salf.wait_for(&eth_web3, tx_hash)
+                                .timeout(Duration::from_secs(timeout))
+                                .map(move |_| amount)
+                        }
+                    });
+
+                Box::new(
+                    salf.finish_record(salf.eth_web3.clone(), record.clone(), execution)
+                        .and_then(move |_| match record.tx_hash() {
+                            Some(tx_hash) => Ok(tx_hash),
+                            None => bail!("Withdrawal finished without a tx hash"),
+                        }),
+                )
+            }),
+        )
+    }
+}
+
+/// The token, recipient and amount a withdrawal message pays out. The message ends with the
+/// call to the foreign mediator, `handleNativeTokens(address,address,uint256)` or the bridged
+/// equivalent, so they are the last three words.
+fn decode_message_transfer(message: &[u8]) -> Result<(Address, Address, Uint256), Error> {
+    if message.len() < 96 {
+        bail!("Withdrawal message too short {:?}", message);
+    }
+    let end = message.len();
+    let token = decode_address(&message[end - 96..end - 64])?;
+    let recipient = decode_address(&message[end - 64..end - 32])?;
+    let amount = Uint256::from_bytes_be(&message[end - 32..]);
+    Ok((token, recipient, amount))
+}
+
+/// Decodes log data holding a single ABI encoded `bytes`
+fn decode_bytes(data: &[u8]) -> Result<Vec<u8>, Error> {
+    let out_of_range = || failure::format_err!("Bytes offset or length out of range");
+    let word = |start: usize| -> Result<usize, Error> {
+        let end = start.checked_add(32).ok_or_else(out_of_range)?;
+        match data.get(start..end) {
+            Some(word) => match Uint256::from_bytes_be(word).to_string().parse() {
+                Ok(value) => Ok(value),
+                Err(_) => Err(out_of_range()),
+            },
+            None => bail!("Malformed bytes in log data {:?}", data),
+        }
+    };
+    let offset = word(0)?;
+    let length = word(offset)?;
+    let start = offset.checked_add(32).ok_or_else(out_of_range)?;
+    let end = start.checked_add(length).ok_or_else(out_of_range)?;
+    match data.get(start..end) {
+        Some(bytes) => Ok(bytes.to_vec()),
+        None => bail!("Malformed bytes in log data {:?}", data),
+    }
+}
+
+/// The AMB takes signatures packed as their count followed by every v, then every r, then
+/// every s
+fn pack_signatures(signatures: &[(Uint256, Uint256, Uint256)]) -> Vec<u8> {
+    let count = signatures.len();
+    let mut packed = vec![0u8; 1 + count * 65];
+    packed[0] = count as u8;
+    for (i, (v, r, s)) in signatures.iter().enumerate() {
+        let v: [u8; 32] = v.clone().into();
+        let r: [u8; 32] = r.clone().into();
+        let s: [u8; 32] = s.clone().into();
+        packed[1 + i] = v[31];
+        packed[1 + count + i * 32..1 + count + (i + 1) * 32].copy_from_slice(&r);
+        packed[1 + count * 33 + i * 32..1 + count * 33 + (i + 1) * 32].copy_from_slice(&s);
+    }
+    packed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_bytes() {
+        let mut data = vec![0u8; 96];
+        data[31] = 32;
+        data[63] = 3;
+        data[64..67].copy_from_slice(&[1, 2, 3]);
+
+        assert_eq!(decode_bytes(&data).unwrap(), vec![1, 2, 3]);
+        assert!(decode_bytes(&data[0..65]).is_err());
+
+        // An offset or length that overflows is an error rather than a panic
+        let word = |value: usize| -> [u8; 32] { Uint256::from(value as u64).into() };
+        let mut huge = data.clone();
+        huge[0..32].copy_from_slice(&word(usize::MAX));
+        assert!(decode_bytes(&huge).is_err());
+        huge[0..32].copy_from_slice(&word(32));
+        huge[32..64].copy_from_slice(&word(usize::MAX - 40));
+        assert!(decode_bytes(&huge).is_err());
+    }
+
+    #[test]
+    fn test_decode_message_transfer() {
+        let token: Address = "0x6B175474E89094C44Da98b954EedeAC495271d0F"
+            .parse()
+            .unwrap();
+        let recipient: Address = "0x79AE13432950bF5CDC3499f8d4Cf5963c3F0d42c"
+            .parse()
+            .unwrap();
+        let mut message = vec![7u8; 104];
+        message.extend_from_slice(&[0u8; 12]);
+        message.extend_from_slice(token.as_bytes());
+        message.extend_from_slice(&[0u8; 12]);
+        message.extend_from_slice(recipient.as_bytes());
+        let amount: [u8; 32] = Uint256::from(1000u64).into();
+        message.extend_from_slice(&amount);
+
+        assert_eq!(
+            decode_message_transfer(&message).unwrap(),
+            (token, recipient, 1000u64.into())
+        );
+        assert!(decode_message_transfer(&message[..95]).is_err());
+    }
+
+    #[test]
+    fn test_pack_signatures() {
+        let packed = pack_signatures(&[
+            (27u8.into(), 1u8.into(), 2u8.into()),
+            (28u8.into(), 3u8.into(), 4u8.into()),
+        ]);
+
+        assert_eq!(packed.len(), 131);
+        assert_eq!(&packed[0..3], &[2, 27, 28]);
+        assert_eq!(Uint256::from_bytes_be(&packed[3..35]), 1u8.into());
+        assert_eq!(Uint256::from_bytes_be(&packed[35..67]), 3u8.into());
+        assert_eq!(Uint256::from_bytes_be(&packed[67..99]), 2u8.into());
+        assert_eq!(Uint256::from_bytes_be(&packed[99..131]), 4u8.into());
+    }
+}
@@ -0,0 +1,302 @@
+//! Dai's `permit` lets the holder sign an approval offline instead of sending an `approve`
+//! transaction, anyone holding the signature can submit it. Dai permits are all or nothing, a
+//! permit either grants an unlimited allowance or takes it away.
+
+use crate::journal::{JournalRecorder, Operation};
+use crate::TokenBridge;
+use clarity::abi::{derive_signature, encode_call};
+use clarity::Address;
+use failure::bail;
+use failure::Error;
+use futures::Future;
+use futures_timer::FutureExt;
+use num::Bounded;
+use num256::Uint256;
+use sha3::{Digest, Keccak256};
+use std::time::Duration;
+use web30::types::SendTxOption;
+
+/// A sent permit's record and the wait for it to be mined
+type PermitSubmission = (JournalRecorder, Box<dyn Future<Item = (), Error = Error>>);
+
+const PERMIT_TYPE: &str =
+    "Permit(address holder,address spender,uint256 nonce,uint256 expiry,bool allowed)";
+
+/// A signed Dai permit, ready to be submitted by us or handed to a router that accepts permits
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DaiPermit {
+    pub holder: Address,
+    pub spender: Address,
+    pub nonce: Uint256,
+    /// Unix time after which the permit can't be used, zero for never
+    pub expiry: Uint256,
+    pub allowed: bool,
+    pub v: Uint256,
+    pub r: Uint256,
+    pub s: Uint256,
+}
+
+impl TokenBridge {
+    /// Signs a permit letting `spender` move all of our Dai, or none of it if `allowed` is false
+    pub fn sign_dai_permit(
+        &self,
+        spender: Address,
+        expiry: Uint256,
+        allowed: bool,
+    ) -> Box<dyn Future<Item = DaiPermit, Error = Error>> {
+        let dai_address = self.foreign_dai_contract_address;
+        let own_address = self.own_address;
+        let secret = self.secret;
+
+        let domain_separator = self
+            .read_call(&self.eth_web3, dai_address, "DOMAIN_SEPARATOR()", &[])
+            .and_then(|output| decode_word(&output));
+        let nonce = self
+            .read_call(
+                &self.eth_web3,
+                dai_address,
+                "nonces(address)",
+                &[own_address.into()],
+            )
+            .and_then(|output| decode_word(&output));
+
+        Box::new(
+            domain_separator
+                .join(nonce)
+                .map(move |(domain_separator, nonce)| {
+                    let nonce = Uint256::from_bytes_be(&nonce);
+                    let digest = permit_digest(
+                        domain_separator,
+                        own_address,
+                        spender,
+                        nonce.clone(),
+                        expiry.clone(),
+                        allowed,
+                    );
+                    let signature = secret.sign_hash(&digest);
+                    DaiPermit {
+                        holder: own_address,
+                        spender,
+                        nonce,
+                        expiry,
+                        allowed,
+                        v: signature.v,
+                        r: signature.r,
+                        s: signature.s,
+                    }
+                }),
+        )
+    }
+
+    /// Sends a signed permit to the Dai contract and waits for it to be mined
+    pub fn submit_dai_permit(
+        &self,
+        permit: &DaiPermit,
+        timeout: Duration,
+    ) -> Box<dyn Future<Item = (), Error = Error>> {
+        match self.send_dai_permit(permit, timeout) {
+            Ok((_, submission)) => submission,
+            Err(e) => Box::new(futures::future::err(e)),
+        }
+    }
+
+    /// `submit_dai_permit` along with the permit's record, which knows whether the permit was
+    /// sent before anything went wrong
+    fn send_dai_permit(
+        &self,
+        permit: &DaiPermit,
+        timeout: Duration,
+    ) -> Result<PermitSubmission, Error> {
+        self.check_circuit_breaker()?;
+        let web3 = self.eth_web3.clone();
+        let amount = if permit.allowed {
+            Uint256::max_value()
+        } else {
+            0u8.into()
+        };
+        let record = self.record(Operation::ApproveUniswapDai, amount.clone());
+
+        let payload = encode_call(
+            "permit(address,address,uint256,uint256,bool,uint8,bytes32,bytes32)",
+            &[
+                permit.holder.into(),
+                permit.spender.into(),
+                permit.nonce.clone().into(),
+                permit.expiry.clone().into(),
+                permit.allowed.into(),
+                permit.v.clone().into(),
+                permit.r.clone().into(),
+                permit.s.clone().into(),
+            ],
+        );
+
+        let submission = web3
+            .send_transaction(
+                self.foreign_dai_contract_address,
+                payload,
+                0u32.into(),
+                self.own_address,
+                self.secret,
+                vec![SendTxOption::GasLimit(100_000u64.into())],
+            )
+            .and_then({
+                let salf = self.clone();
+                let record = record.clone();
+                move |tx_hash| {
+                    record.submitted(tx_hash.clone());
+                    salf.wait_for(&web3, tx_hash)
+                        .timeout(timeout)
+                        .map(move |_| amount)
+                }
+            });
+
+        let submission = self
+            .finish_record(self.eth_web3.clone(), record.clone(), submission)
+            .map(|_| ());
+        Ok((record, Box::new(submission)))
+    }
+
+    /// Gives Uniswap an unlimited allowance with a permit, falling back to
+    /// `approve_uniswap_dai_transfers` if the permit can't be signed or sent. Once the permit
+    /// has been sent it may still be mined, so an error after that is returned instead.
+    pub fn permit_uniswap_dai_transfers(
+        &self,
+        timeout: Duration,
+    ) -> Box<dyn Future<Item = (), Error = Error>> {
+        let salf = self.clone();
+
+        Box::new(
+            self.read_latest_block(&self.eth_web3)
+                .and_then({
+                    let salf = self.clone();
+                    move |block| {
+                        let expiry = block.timestamp + timeout.as_secs().into();
+                        salf.sign_dai_permit(salf.uniswap_address, expiry, true)
+                    }
+                })
+                .then(move |permit| {
+                    match permit.and_then(|permit| salf.send_dai_permit(&permit, timeout)) {
+                        Ok((record, submission)) => Box::new(submission.or_else(move |e| {
+                            if record.tx_hash().is_some() {
+                                return Box::new(futures::future::err(e))
+                                    as Box<dyn Future<Item = (), Error = Error>>;
+                            }
+                            salf.approve_instead_of_permit(e, timeout)
+                        })),
+                        Err(e) => salf.approve_instead_of_permit(e, timeout),
+                    }
+                }),
+        )
+    }
+
+    fn approve_instead_of_permit(
+        &self,
+        e: Error,
+        timeout: Duration,
+    ) -> Box<dyn Future<Item = (), Error = Error>> {
+        warn!("Dai permit failed, approving Uniswap instead {:?}", e);
+        self.approve_uniswap_dai_transfers(timeout)
+    }
+}
+
+/// The EIP-712 digest the Dai contract checks permit signatures against
+fn permit_digest(
+    domain_separator: [u8; 32],
+    holder: Address,
+    spender: Address,
+    nonce: Uint256,
+    expiry: Uint256,
+    allowed: bool,
+) -> [u8; 32] {
+    let holder: [u8; 32] = holder.into();
+    let spender: [u8; 32] = spender.into();
+    let nonce: [u8; 32] = nonce.into();
+    let expiry: [u8; 32] = expiry.into();
+    let mut allowed_word = [0u8; 32];
+    allowed_word[31] = allowed as u8;
+
+    let mut permit = Vec::with_capacity(6 * 32);
+    permit.extend_from_slice(&derive_signature(PERMIT_TYPE));
+    permit.extend_from_slice(&holder);
+    permit.extend_from_slice(&spender);
+    permit.extend_from_slice(&nonce);
+    permit.extend_from_slice(&expiry);
+    permit.extend_from_slice(&allowed_word);
+
+    let mut message = Vec::with_capacity(66);
+    message.extend_from_slice(b"\x19\x01");
+    message.extend_from_slice(&domain_separator);
+    message.extend_from_slice(&Keccak256::digest(&permit));
+
+    let mut digest = [0u8; 32];
+    digest.copy_from_slice(&Keccak256::digest(&message));
+    digest
+}
+
+fn decode_word(output: &[u8]) -> Result<[u8; 32], Error> {
+    match output.get(0..32) {
+        Some(val) => {
+            let mut word = [0u8; 32];
+            word.copy_from_slice(val);
+            Ok(word)
+        }
+        None => bail!("Malformed output from the Dai contract {:?}", output),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clarity::utils::{bytes_to_hex_str, hex_str_to_bytes};
+    use std::str::FromStr;
+
+    #[test]
+    fn test_permit_digest() {
+        // The PERMIT_TYPEHASH constant in the Dai contract
+        assert_eq!(
+            bytes_to_hex_str(&derive_signature(PERMIT_TYPE)),
+            "ea2aa0a1be11a07ed86d755c93467f4f82362b452371d1ba94d1715123511acb"
+        );
+
+        let holder = Address::from_str("0x79AE13432950bF5CDC3499f8d4Cf5963c3F0d42c").unwrap();
+        let spender = Address::from_str("0x09cabEC1eAd1c0Ba254B09efb3EE13841712bE14").unwrap();
+        let digest = |nonce: u64, allowed: bool| {
+            permit_digest(
+                [1u8; 32],
+                holder,
+                spender,
+                nonce.into(),
+                0u8.into(),
+                allowed,
+            )
+        };
+
+        assert_ne!(digest(0, true), digest(1, true));
+        assert_ne!(digest(0, true), digest(0, false));
+    }
+
+    #[test]
+    fn test_permit_digest_known_answer() {
+        // Mainnet Dai's DOMAIN_SEPARATOR, the holder is the address of the key 0x1111...11 and
+        // the spender is the Uniswap Dai exchange. The expected digest was worked out with
+        // `openssl dgst -keccak-256` from the EIP-712 encoding, not with this code.
+        let mut domain_separator = [0u8; 32];
+        domain_separator.copy_from_slice(
+            &hex_str_to_bytes("dbb8cf42e1ecb028be3f3dbc922e1d878b963f411dc388ced501601c60f7c6f7")
+                .unwrap(),
+        );
+        let digest = permit_digest(
+            domain_separator,
+            Address::from_str("0x19E7E376E7C213B7E7e7e46cc70A5dD086DAff2A").unwrap(),
+            Address::from_str("0x2a1530C4C41db0B0b2bB646CB5Eb1A67b7158667").unwrap(),
+            3u8.into(),
+            1_600_000_000u64.into(),
+            true,
+        );
+
+        assert_eq!(
+            bytes_to_hex_str(&digest),
+            "5f024fb7c5ed5230f23d072f9d65d811e02417ea526f5ed55a733ff60b72c6ac"
+        );
+    }
+}
@@ -0,0 +1,300 @@
+//! Working backwards from how much xDai should arrive to how much ETH to spend. The target is
+//! grossed up for the bridge fee, checked against the bridge's limits and priced with
+//! `getEthToTokenOutputPrice`, so the plan says exactly how much Dai to buy rather than how much
+//! ETH to sell.
+
+use crate::amount::{DaiAmount, EthAmount, XdaiAmount};
+use crate::estimate::{StepEstimate, APPROVAL_GAS, RELAY_GAS, SWAP_GAS};
+use crate::journal::{in_span, Operation};
+use crate::recovery::decode_uint;
+use crate::TokenBridge;
+use clarity::abi::Token;
+use clarity::Address;
+use failure::bail;
+use failure::Error;
+use futures::Future;
+use num256::Uint256;
+use web30::client::Web3;
+
+const BASIS_POINTS: u64 = 10_000;
+
+/// How much one side of the bridge will accept, it refuses transfers outside these limits. The
+/// foreign bridge on Eth limits deposits and the home bridge on xDai limits withdrawals.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BridgeLimits {
+    pub min_per_tx: Uint256,
+    pub max_per_tx: Uint256,
+    /// What can still be transferred today, the bridge's day is a unix day
+    pub remaining_today: Uint256,
+}
+
+impl BridgeLimits {
+    pub fn check(&self, amount: &Uint256) -> Result<(), Error> {
+        if *amount < self.min_per_tx {
+            bail!(
+                "Transfers of less than {} are refused by the bridge, {} is too small",
+                self.min_per_tx,
+                amount
+            );
+        }
+        if *amount > self.max_per_tx {
+            bail!(
+                "Transfers of more than {} are refused by the bridge, {} is too large",
+                self.max_per_tx,
+                amount
+            );
+        }
+        if *amount > self.remaining_today {
+            bail!(
+                "Only {} more can be bridged today, {} is too much",
+                self.remaining_today,
+                amount
+            );
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TopUpPlan {
+    pub recipient: Address,
+    /// The xDai that should arrive
+    pub xdai_target: Uint256,
+    /// The Dai to buy and bridge, the target plus the bridge fee
+    pub dai_to_bridge: Uint256,
+    /// The ETH Uniswap wants for exactly `dai_to_bridge`
+    pub eth_to_sell: Uint256,
+    pub steps: Vec<StepEstimate>,
+    pub gas_cost_eth: Uint256,
+    /// Everything the plan spends, `eth_to_sell` plus gas
+    pub total_eth: Uint256,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TopUpResult {
+    pub plan: TopUpPlan,
+    pub dai_bought: Uint256,
+    pub dai_deposited: Uint256,
+}
+
+impl TokenBridge {
+    /// The ETH it takes to buy `dai_amount` Dai
+    pub fn eth_to_dai_output_price(
+        &self,
+        dai_amount: DaiAmount,
+    ) -> Box<dyn Future<Item = Uint256, Error = Error>> {
+        Box::new(
+            self.read_call(
+                &self.eth_web3,
+                self.uniswap_address,
+                "getEthToTokenOutputPrice(uint256)",
+                &[dai_amount.into_wei().into()],
+            )
+            .and_then(|eth_sold| decode_uint(&eth_sold)),
+        )
+    }
+
+    pub fn get_deposit_limits(&self) -> Box<dyn Future<Item = BridgeLimits, Error = Error>> {
+        self.get_bridge_limits(self.eth_web3.clone(), self.xdai_foreign_bridge_address)
+    }
+
+    pub fn get_withdrawal_limits(&self) -> Box<dyn Future<Item = BridgeLimits, Error = Error>> {
+        self.get_bridge_limits(self.xdai_web3.clone(), self.xdai_home_bridge_address)
+    }
+
+    /// Both sides of the bridge have the same limit functions
+    fn get_bridge_limits(
+        &self,
+        web3: Web3,
+        bridge_address: Address,
+    ) -> Box<dyn Future<Item = BridgeLimits, Error = Error>> {
+        let salf = self.clone();
+        let call = move |sig: &'static str, tokens: &[Token]| {
+            salf.read_call(&web3, bridge_address, sig, tokens)
+                .and_then(|output| decode_uint(&output))
+        };
+
+        let daily = call("getCurrentDay()", &[]).and_then({
+            let call = call.clone();
+            move |day| {
+                call("dailyLimit()", &[]).join(call("totalSpentPerDay(uint256)", &[day.into()]))
+            }
+        });
+
+        Box::new(
+            call("minPerTx()", &[])
+                .join3(call("maxPerTx()", &[]), daily)
+                .map(
+                    |(min_per_tx, max_per_tx, (daily_limit, spent))| BridgeLimits {
+                        min_per_tx,
+                        max_per_tx,
+                        remaining_today: if daily_limit > spent {
+                            daily_limit - spent
+                        } else {
+                            0u8.into()
+                        },
+                    },
+                ),
+        )
+    }
+
+    /// Works out how much ETH it takes for `xdai_amount` to arrive at `recipient` on xDai
+    pub fn plan_xdai_top_up(
+        &self,
+        recipient: Address,
+        xdai_amount: XdaiAmount,
+    ) -> Box<dyn Future<Item = TopUpPlan, Error = Error>> {
+        let xdai_amount = xdai_amount.into_wei();
+        if let Err(e) = self.check_recipient(recipient) {
+            return Box::new(futures::future::err(e));
+        }
+        let dai_to_bridge = match gross_up(&xdai_amount, self.bridge_fee_basis_points) {
+            Ok(dai) => dai,
+            Err(e) => return Box::new(futures::future::err(e)),
+        };
+        let salf = self.clone();
+
+        Box::new(
+            self.get_deposit_limits()
+                .and_then({
+                    let dai_to_bridge = dai_to_bridge.clone();
+                    move |limits| limits.check(&dai_to_bridge)
+                })
+                .and_then(move |_| {
+                    salf.eth_to_dai_output_price(DaiAmount::from_wei(dai_to_bridge.clone()))
+                        .join3(
+                            salf.read_gas_price(&salf.eth_web3),
+                            salf.get_dai_allowance(salf.xdai_foreign_bridge_address),
+                        )
+                        .map(move |(eth_to_sell, gas_price, allowance)| {
+                            let mut steps = vec![StepEstimate::new(
+                                Operation::EthToDaiSwap,
+                                SWAP_GAS,
+                                &gas_price,
+                            )];
+                            if allowance < dai_to_bridge {
+                                steps.push(StepEstimate::new(
+                                    Operation::ApproveBridgeDai,
+                                    APPROVAL_GAS,
+                                    &gas_price,
+                                ));
+                            }
+                            steps.push(StepEstimate::new(
+                                Operation::DaiToXdaiDeposit,
+                                RELAY_GAS,
+                                &gas_price,
+                            ));
+                            let gas_cost_eth = steps
+                                .iter()
+                                .fold(Uint256::from(0u8), |sum, step| sum + step.gas_cost.clone());
+
+                            TopUpPlan {
+                                recipient,
+                                xdai_target: xdai_amount,
+                                dai_to_bridge,
+                                total_eth: eth_to_sell.clone() + gas_cost_eth.clone(),
+                                eth_to_sell,
+                                steps,
+                                gas_cost_eth,
+                            }
+                        })
+                }),
+        )
+    }
+
+    /// Sells the plan's ETH for Dai and bridges it to the recipient. If the price moved and the
+    /// swap bought less than planned, what it bought is bridged and less xDai arrives.
+    pub fn execute_top_up(
+        &self,
+        plan: TopUpPlan,
+        timeout: u64,
+    ) -> Box<dyn Future<Item = TopUpResult, Error = Error>> {
+        let salf = self.clone();
+        let span = tracing::info_span!(
+            "top_up_xdai",
+            recipient = %plan.recipient,
+            xdai_target = %plan.xdai_target,
+        );
+
+        in_span(span, move || {
+            Box::new(
+                salf.eth_to_dai_swap(EthAmount::from_wei(plan.eth_to_sell.clone()), timeout)
+                    .and_then(move |dai_bought| {
+                        let dai_deposited = if dai_bought < plan.dai_to_bridge {
+                            warn!(
+                                "Bought {} Dai when the plan needed {}, bridging what we have",
+                                dai_bought, plan.dai_to_bridge
+                            );
+                            dai_bought.clone()
+                        } else {
+                            plan.dai_to_bridge.clone()
+                        };
+                        salf.dai_to_xdai_bridge_to(
+                            plan.recipient,
+                            DaiAmount::from_wei(dai_deposited),
+                            timeout,
+                        )
+                        .map(move |dai_deposited| TopUpResult {
+                            plan,
+                            dai_bought,
+                            dai_deposited,
+                        })
+                    }),
+            )
+        })
+    }
+
+    /// Plans and executes a top up in one go
+    pub fn top_up_xdai(
+        &self,
+        recipient: Address,
+        xdai_amount: XdaiAmount,
+        timeout: u64,
+    ) -> Box<dyn Future<Item = TopUpResult, Error = Error>> {
+        let salf = self.clone();
+        Box::new(
+            self.plan_xdai_top_up(recipient, xdai_amount)
+                .and_then(move |plan| salf.execute_top_up(plan, timeout)),
+        )
+    }
+}
+
+/// The amount that leaves `net` after the bridge takes `fee_basis_points`, rounded up
+fn gross_up(net: &Uint256, fee_basis_points: u64) -> Result<Uint256, Error> {
+    if fee_basis_points >= BASIS_POINTS {
+        bail!(
+            "A bridge fee of {} basis points leaves nothing",
+            fee_basis_points
+        );
+    }
+    let keep: Uint256 = (BASIS_POINTS - fee_basis_points).into();
+    let scaled = net.clone() * BASIS_POINTS.into();
+    Ok((scaled + keep.clone() - 1u8.into()) / keep)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gross_up() {
+        assert_eq!(gross_up(&1000u64.into(), 0).unwrap(), 1000u64.into());
+        // 1% fee, 1010.1 rounds up so at least 1000 arrives
+        assert_eq!(gross_up(&1000u64.into(), 100).unwrap(), 1011u64.into());
+        assert_eq!(gross_up(&9900u64.into(), 100).unwrap(), 10_000u64.into());
+        assert!(gross_up(&1000u64.into(), 10_000).is_err());
+    }
+
+    #[test]
+    fn test_bridge_limits() {
+        let limits = BridgeLimits {
+            min_per_tx: 10u64.into(),
+            max_per_tx: 1000u64.into(),
+            remaining_today: 500u64.into(),
+        };
+        assert!(limits.check(&100u64.into()).is_ok());
+        assert!(limits.check(&9u64.into()).is_err());
+        assert!(limits.check(&501u64.into()).is_err());
+        assert!(limits.check(&1001u64.into()).is_err());
+    }
+}
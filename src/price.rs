@@ -0,0 +1,363 @@
+//! Price impact of a trade against the Uniswap pool, computed from the pool reserves with the
+//! same constant product formula the exchange contract uses, and checks of Uniswap quotes against
+//! an independent price source.
+
+use crate::metrics::FailureReason;
+use crate::TokenBridge;
+use clarity::Address;
+use failure::bail;
+use failure::format_err;
+use failure::Error;
+use futures::Future;
+use num256::Uint256;
+
+/// Prices are Dai per ETH or ETH per Dai, scaled by this so they can be kept as integers
+pub const PRICE_SCALE: u64 = 1_000_000_000_000_000_000;
+const BASIS_POINTS: u64 = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SwapDirection {
+    EthToDai,
+    DaiToEth,
+    /// ETH for a token other than Dai on that token's own exchange, the Dai price checks don't
+    /// apply to these
+    EthToToken,
+    TokenToEth,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PoolReserves {
+    /// The exchange's ETH balance
+    pub eth: Uint256,
+    /// The exchange's Dai balance
+    pub dai: Uint256,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PriceImpact {
+    pub direction: SwapDirection,
+    pub amount_in: Uint256,
+    pub amount_out: Uint256,
+    /// Output per unit of input before the trade, scaled by `PRICE_SCALE`
+    pub spot_price: Uint256,
+    /// Output per unit of input the trade actually gets, scaled by `PRICE_SCALE`
+    pub execution_price: Uint256,
+    /// How much worse the execution price is than the spot price in basis points, this includes
+    /// the 0.3% Uniswap fee
+    pub impact_basis_points: u64,
+}
+
+/// Somewhere to get the price of ETH in Dai that doesn't depend on the Uniswap pool we trade with
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum PriceReference {
+    /// A Chainlink style aggregator for ETH/DAI or ETH/USD, read through `latestAnswer()`
+    Aggregator { address: Address, decimals: u32 },
+    /// Another Uniswap style exchange trading ETH for Dai, quoted for the same amount
+    UniswapExchange { address: Address },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PriceSanityCheck {
+    pub reference: PriceReference,
+    /// Swaps are aborted if the Uniswap quote is further than this many basis points from the
+    /// reference price, in either direction. Remember the quote includes the 0.3% fee and the
+    /// price impact of the trade.
+    pub max_deviation: u64,
+}
+
+impl PriceImpact {
+    pub fn percent(&self) -> f64 {
+        self.impact_basis_points as f64 / 100.0
+    }
+}
+
+impl TokenBridge {
+    pub fn get_uniswap_reserves(&self) -> Box<dyn Future<Item = PoolReserves, Error = Error>> {
+        let web3 = self.eth_web3.clone();
+        let uniswap_address = self.uniswap_address;
+        Box::new(
+            self.retry_read("eth_getBalance", move || {
+                web3.eth_get_balance(uniswap_address)
+            })
+            .join(self.get_dai_balance(self.uniswap_address))
+            .map(|(eth, dai)| PoolReserves { eth, dai }),
+        )
+    }
+
+    /// Spot price, execution price and price impact of selling `amount` ETH or Dai
+    pub fn get_price_impact(
+        &self,
+        direction: SwapDirection,
+        amount: Uint256,
+    ) -> Box<dyn Future<Item = PriceImpact, Error = Error>> {
+        Box::new(
+            self.get_uniswap_reserves()
+                .and_then(move |reserves| price_impact(direction, amount, &reserves)),
+        )
+    }
+
+    /// The price of ETH in Dai according to `reference`, scaled by `PRICE_SCALE`. `amount` is
+    /// only used by references that quote for a trade size.
+    pub fn get_reference_price(
+        &self,
+        reference: &PriceReference,
+        amount: Uint256,
+    ) -> Box<dyn Future<Item = Uint256, Error = Error>> {
+        match *reference {
+            PriceReference::Aggregator { address, decimals } => Box::new(
+                self.read_call(&self.eth_web3, address, "latestAnswer()", &[])
+                    .and_then(move |answer| {
+                        let answer = match answer.get(0..32) {
+                            Some(val) => val,
+                            None => bail!("Malformed output from aggregator {:?}", answer),
+                        };
+                        // Answers are signed, a negative price is never valid
+                        if answer[0] & 0x80 != 0 {
+                            bail!("Aggregator reported a negative price");
+                        }
+                        Ok(Uint256::from_bytes_be(answer) * PRICE_SCALE.into() / pow10(decimals))
+                    }),
+            ),
+            PriceReference::UniswapExchange { .. } if amount == 0u8.into() => {
+                Box::new(futures::future::err(failure::err_msg(
+                    "Can't price ETH from a reference quote for selling nothing",
+                )))
+            }
+            PriceReference::UniswapExchange { address } => Box::new(
+                self.read_call(
+                    &self.eth_web3,
+                    address,
+                    "getEthToTokenInputPrice(uint256)",
+                    &[amount.clone().into()],
+                )
+                .and_then(move |tokens_bought| {
+                    let tokens_bought = Uint256::from_bytes_be(match tokens_bought.get(0..32) {
+                        Some(val) => val,
+                        None => bail!(
+                            "Malformed output from reference getEthToTokenInputPrice call {:?}",
+                            tokens_bought
+                        ),
+                    });
+                    Ok(tokens_bought * PRICE_SCALE.into() / amount)
+                }),
+            ),
+        }
+    }
+
+    /// Fails if a Uniswap quote of `quote` for selling `amount` is too far from the reference
+    /// price or the time weighted average price, if either is configured
+    pub(crate) fn check_quote(
+        &self,
+        direction: SwapDirection,
+        amount: Uint256,
+        quote: Uint256,
+    ) -> Box<dyn Future<Item = (), Error = Error>> {
+        if let Err(e) = self.check_twap(direction, &amount, &quote) {
+            return Box::new(futures::future::err(e));
+        }
+        let check = match self.price_check {
+            Some(ref check) => check.clone(),
+            None => return Box::new(futures::future::ok(())),
+        };
+        // References quote ETH for Dai, so size Dai trades by the ETH they are quoted at
+        let eth_amount = match direction {
+            SwapDirection::EthToDai | SwapDirection::EthToToken => amount.clone(),
+            SwapDirection::DaiToEth | SwapDirection::TokenToEth => quote.clone(),
+        };
+
+        Box::new(
+            self.get_reference_price(&check.reference, eth_amount)
+                .and_then(move |reference| {
+                    let deviation = quote_deviation(direction, &amount, &quote, &reference)?;
+                    if deviation > check.max_deviation {
+                        return Err(FailureReason::PriceCheck.refuse(format_err!(
+                            "Uniswap quote is {}% away from the reference price, the limit is {}%",
+                            deviation as f64 / 100.0,
+                            check.max_deviation as f64 / 100.0
+                        )));
+                    }
+                    Ok(())
+                }),
+        )
+    }
+
+    /// Fails if a swap breaks any of the limits set on the bridge, run before a swap is sent
+    pub(crate) fn check_swap(
+        &self,
+        direction: SwapDirection,
+        amount: Uint256,
+    ) -> Box<dyn Future<Item = (), Error = Error>> {
+        let max_impact = match self.max_price_impact {
+            Some(max_impact) => max_impact,
+            None => return Box::new(futures::future::ok(())),
+        };
+
+        Box::new(
+            self.get_price_impact(direction, amount)
+                .and_then(move |impact| {
+                    if impact.impact_basis_points > max_impact {
+                        return Err(FailureReason::PriceCheck.refuse(format_err!(
+                            "Price impact of {}% is over the limit of {}%",
+                            impact.percent(),
+                            max_impact as f64 / 100.0
+                        )));
+                    }
+                    Ok(())
+                }),
+        )
+    }
+}
+
+pub fn price_impact(
+    direction: SwapDirection,
+    amount_in: Uint256,
+    reserves: &PoolReserves,
+) -> Result<PriceImpact, Error> {
+    let (reserve_in, reserve_out) = match direction {
+        SwapDirection::EthToDai | SwapDirection::EthToToken => {
+            (reserves.eth.clone(), reserves.dai.clone())
+        }
+        SwapDirection::DaiToEth | SwapDirection::TokenToEth => {
+            (reserves.dai.clone(), reserves.eth.clone())
+        }
+    };
+    let zero: Uint256 = 0u8.into();
+    if reserve_in == zero || reserve_out == zero {
+        bail!("The Uniswap pool is empty");
+    }
+    if amount_in == zero {
+        bail!("Can't compute the price impact of an empty trade");
+    }
+
+    let amount_out = get_input_price(amount_in.clone(), reserve_in.clone(), reserve_out.clone());
+    let scale: Uint256 = PRICE_SCALE.into();
+    let spot_price = reserve_out.clone() * scale.clone() / reserve_in.clone();
+    let execution_price = amount_out.clone() * scale / amount_in.clone();
+
+    // execution / spot, worked out from the amounts directly to avoid rounding twice
+    let basis_points: Uint256 = BASIS_POINTS.into();
+    let ratio =
+        amount_out.clone() * reserve_in * basis_points.clone() / (amount_in.clone() * reserve_out);
+    let impact_basis_points = if ratio >= basis_points {
+        0
+    } else {
+        let impact = basis_points - ratio;
+        // Less than 10000 so it always fits
+        impact.to_string().parse().unwrap_or(BASIS_POINTS)
+    };
+
+    Ok(PriceImpact {
+        direction,
+        amount_in,
+        amount_out,
+        spot_price,
+        execution_price,
+        impact_basis_points,
+    })
+}
+
+/// How far the Dai per ETH price implied by a quote is from the reference price, in basis points
+pub(crate) fn quote_deviation(
+    direction: SwapDirection,
+    amount: &Uint256,
+    quote: &Uint256,
+    reference: &Uint256,
+) -> Result<u64, Error> {
+    let zero: Uint256 = 0u8.into();
+    if *amount == zero || *quote == zero || *reference == zero {
+        bail!("Can't compare an empty quote or reference price");
+    }
+
+    let scale: Uint256 = PRICE_SCALE.into();
+    let quoted_price = match direction {
+        SwapDirection::EthToDai | SwapDirection::EthToToken => {
+            quote.clone() * scale / amount.clone()
+        }
+        SwapDirection::DaiToEth | SwapDirection::TokenToEth => {
+            amount.clone() * scale / quote.clone()
+        }
+    };
+    let difference = if quoted_price > *reference {
+        quoted_price - reference.clone()
+    } else {
+        reference.clone() - quoted_price
+    };
+    let deviation = difference * BASIS_POINTS.into() / reference.clone();
+    Ok(deviation.to_string().parse().unwrap_or(u64::MAX))
+}
+
+fn pow10(exponent: u32) -> Uint256 {
+    (0..exponent).fold(1u8.into(), |result: Uint256, _| result * 10u8.into())
+}
+
+/// Uniswap's `getInputPrice`, the output of a trade including the 0.3% fee
+fn get_input_price(amount_in: Uint256, reserve_in: Uint256, reserve_out: Uint256) -> Uint256 {
+    let amount_in_with_fee = amount_in * 997u64.into();
+    let numerator = amount_in_with_fee.clone() * reserve_out;
+    let denominator = reserve_in * 1000u64.into() + amount_in_with_fee;
+    numerator / denominator
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eth(amount: u64) -> Uint256 {
+        Uint256::from(amount) * PRICE_SCALE.into()
+    }
+
+    #[test]
+    fn test_price_impact() {
+        // 100 ETH and 20000 Dai, 200 Dai per ETH
+        let reserves = PoolReserves {
+            eth: eth(100),
+            dai: eth(20_000),
+        };
+
+        let small =
+            price_impact(SwapDirection::EthToDai, eth(1) / 100u64.into(), &reserves).unwrap();
+        assert_eq!(small.spot_price, eth(200));
+        // Only a little over the 0.3% fee
+        assert_eq!(small.impact_basis_points, 31);
+
+        let large = price_impact(SwapDirection::EthToDai, eth(10), &reserves).unwrap();
+        // 10 * 0.997 * 20000 / (100 + 10 * 0.997) = 1813.22 Dai
+        assert!(large.amount_out > eth(1813) && large.amount_out < eth(1814));
+        assert_eq!(large.impact_basis_points, 934);
+        assert!(large.execution_price < large.spot_price);
+
+        let reverse = price_impact(SwapDirection::DaiToEth, eth(200), &reserves).unwrap();
+        assert_eq!(reverse.spot_price, eth(1) / 200u64.into());
+        assert_eq!(reverse.impact_basis_points, 129);
+
+        let empty = PoolReserves {
+            eth: 0u64.into(),
+            dai: eth(1),
+        };
+        assert!(price_impact(SwapDirection::EthToDai, eth(1), &empty).is_err());
+    }
+
+    #[test]
+    fn test_quote_deviation() {
+        let reference = eth(200);
+
+        // Selling 2 ETH for 396 Dai is 1% under the reference
+        let deviation =
+            quote_deviation(SwapDirection::EthToDai, &eth(2), &eth(396), &reference).unwrap();
+        assert_eq!(deviation, 100);
+
+        // Selling 200 Dai for 1.1 ETH is a Dai price of 181.81 per ETH, 9.09% off
+        let deviation = quote_deviation(
+            SwapDirection::DaiToEth,
+            &eth(200),
+            &(eth(11) / 10u64.into()),
+            &reference,
+        )
+        .unwrap();
+        assert_eq!(deviation, 909);
+
+        assert_eq!(pow10(8), 100_000_000u64.into());
+        assert!(quote_deviation(SwapDirection::EthToDai, &eth(1), &eth(0), &reference).is_err());
+    }
+}
@@ -0,0 +1,197 @@
+//! Reads the outcome of a swap from the receipt of the transaction that made it. Uniswap logs a
+//! purchase event for every swap, but waiting for the next one from our address can pick up a
+//! different swap of ours, so only the logs of the swap's own transaction are trusted.
+
+use crate::price::SwapDirection;
+use crate::TokenBridge;
+use clarity::abi::derive_signature;
+use clarity::Address;
+use failure::bail;
+use failure::Error;
+use futures::Future;
+use futures_timer::FutureExt;
+use num256::Uint256;
+use std::time::Duration;
+use web30::types::TransactionReceipt;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SwapResult {
+    pub direction: SwapDirection,
+    pub tx_hash: Uint256,
+    /// Wei of ETH or Dai that went into the swap
+    pub sold: Uint256,
+    /// Wei of Dai or ETH that came out of the swap
+    pub bought: Uint256,
+}
+
+impl TokenBridge {
+    /// Looks up what a swap made by `own_address` sold and bought
+    pub fn get_swap_result(
+        &self,
+        direction: SwapDirection,
+        tx_hash: Uint256,
+    ) -> Box<dyn Future<Item = SwapResult, Error = Error>> {
+        self.get_exchange_swap_result(direction, self.uniswap_address, tx_hash)
+    }
+
+    /// `get_swap_result` for a swap on any Uniswap exchange, for tokens other than Dai
+    /// `direction` is `EthToToken` or `TokenToEth`
+    pub fn get_exchange_swap_result(
+        &self,
+        direction: SwapDirection,
+        uniswap_address: Address,
+        tx_hash: Uint256,
+    ) -> Box<dyn Future<Item = SwapResult, Error = Error>> {
+        let own_address = self.own_address;
+        let web3 = self.eth_web3.clone();
+
+        Box::new(
+            self.retry_read("eth_getTransactionReceipt", {
+                let tx_hash = tx_hash.clone();
+                move || web3.eth_get_transaction_receipt(tx_hash.clone())
+            })
+            .and_then(move |receipt| match receipt {
+                Some(receipt) => decode_swap(direction, &receipt, uniswap_address, own_address),
+                None => bail!("No receipt for swap transaction {:?}", tx_hash),
+            }),
+        )
+    }
+
+    /// Waits for a swap transaction to be mined and then reads its result
+    pub(crate) fn wait_for_swap(
+        &self,
+        direction: SwapDirection,
+        tx_hash: Uint256,
+        timeout: u64,
+    ) -> Box<dyn Future<Item = SwapResult, Error = Error>> {
+        self.wait_for_exchange_swap(direction, self.uniswap_address, tx_hash, timeout)
+    }
+
+    pub(crate) fn wait_for_exchange_swap(
+        &self,
+        direction: SwapDirection,
+        exchange: Address,
+        tx_hash: Uint256,
+        timeout: u64,
+    ) -> Box<dyn Future<Item = SwapResult, Error = Error>> {
+        let salf = self.clone();
+
+        Box::new(
+            self.wait_for(&self.eth_web3, tx_hash.clone())
+                .timeout(Duration::from_secs(timeout))
+                .and_then(move |_| salf.get_exchange_swap_result(direction, exchange, tx_hash))
+                .map(|result| {
+                    info!(
+                        "Swap {:?} sold {} and bought {}",
+                        result.tx_hash, result.sold, result.bought
+                    );
+                    result
+                }),
+        )
+    }
+}
+
+fn event_signature(direction: SwapDirection) -> &'static str {
+    match direction {
+        SwapDirection::EthToDai | SwapDirection::EthToToken => {
+            "TokenPurchase(address,uint256,uint256)"
+        }
+        SwapDirection::DaiToEth | SwapDirection::TokenToEth => {
+            "EthPurchase(address,uint256,uint256)"
+        }
+    }
+}
+
+fn decode_swap(
+    direction: SwapDirection,
+    receipt: &TransactionReceipt,
+    uniswap_address: Address,
+    buyer: Address,
+) -> Result<SwapResult, Error> {
+    if receipt.status == Some(0u8.into()) {
+        bail!("Swap transaction {:?} reverted", receipt.transaction_hash);
+    }
+
+    let purchase = receipt.logs.iter().find_map(|log| {
+        let topics: Vec<&[u8]> = log.topics.iter().map(|topic| &topic[..]).collect();
+        decode_purchase(direction, uniswap_address, buyer, log.address, &topics)
+    });
+    match purchase {
+        Some((sold, bought)) => Ok(SwapResult {
+            direction,
+            tx_hash: receipt.transaction_hash.clone(),
+            sold,
+            bought,
+        }),
+        None => bail!(
+            "Transaction {:?} has no {} event from Uniswap",
+            receipt.transaction_hash,
+            event_signature(direction)
+        ),
+    }
+}
+
+/// Both purchase events index the buyer, the amount sold and the amount bought in that order.
+/// Returns the amounts sold and bought if the log is a purchase by `buyer` from Uniswap.
+fn decode_purchase(
+    direction: SwapDirection,
+    uniswap_address: Address,
+    buyer: Address,
+    log_address: Address,
+    topics: &[&[u8]],
+) -> Option<(Uint256, Uint256)> {
+    let signature = derive_signature(event_signature(direction));
+    let buyer: [u8; 32] = buyer.into();
+    if log_address != uniswap_address
+        || topics.len() != 4
+        || topics[0] != &signature[..]
+        || topics[1] != &buyer[..]
+    {
+        return None;
+    }
+    Some((
+        Uint256::from_bytes_be(topics[2]),
+        Uint256::from_bytes_be(topics[3]),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn word(value: u64) -> [u8; 32] {
+        Uint256::from(value).into()
+    }
+
+    #[test]
+    fn test_decode_purchase() {
+        let uniswap = Address::from_str("0x09cabEC1eAd1c0Ba254B09efb3EE13841712bE14").unwrap();
+        let dai = Address::from_str("0x89d24A6b4CcB1B6fAA2625fE562bDD9a23260359").unwrap();
+        let buyer = Address::from_str("0x79AE13432950bF5CDC3499f8d4Cf5963c3F0d42c").unwrap();
+        let other = Address::from_str("0x4aa42145Aa6Ebf72e164C9bBC74fbD3788045016").unwrap();
+        let purchase = derive_signature(event_signature(SwapDirection::EthToDai));
+        let (ours, theirs): ([u8; 32], [u8; 32]) = (buyer.into(), other.into());
+        let (sold, bought) = (word(100), word(200));
+        let topics: Vec<&[u8]> = vec![&purchase, &ours, &sold, &bought];
+
+        assert_eq!(
+            decode_purchase(SwapDirection::EthToDai, uniswap, buyer, uniswap, &topics),
+            Some((100u64.into(), 200u64.into()))
+        );
+        // Wrong event, wrong contract and someone else's purchase
+        assert_eq!(
+            decode_purchase(SwapDirection::DaiToEth, uniswap, buyer, uniswap, &topics),
+            None
+        );
+        assert_eq!(
+            decode_purchase(SwapDirection::EthToDai, uniswap, buyer, dai, &topics),
+            None
+        );
+        let topics: Vec<&[u8]> = vec![&purchase, &theirs, &sold, &bought];
+        assert_eq!(
+            decode_purchase(SwapDirection::EthToDai, uniswap, buyer, uniswap, &topics),
+            None
+        );
+    }
+}
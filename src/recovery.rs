@@ -0,0 +1,352 @@
+//! Finds xDai to Dai withdrawals that were sent to the home bridge but never paid out on Eth,
+//! for example because we restarted before seeing them through. Once the bridge validators have
+//! signed a withdrawal anyone can execute it on the foreign bridge, so we can finish it ourselves.
+
+use crate::history::BridgeEvent;
+use crate::journal::Operation;
+use crate::TokenBridge;
+use clarity::abi::{encode_call, Token};
+use clarity::Address;
+use failure::bail;
+use failure::Error;
+use futures::stream;
+use futures::{Future, Stream};
+use futures_timer::FutureExt;
+use num256::Uint256;
+use sha3::{Digest, Keccak256};
+use std::time::Duration;
+use web30::types::SendTxOption;
+
+/// Bridges are run by a handful of validators, a contract asking for more signatures than
+/// this is broken and we would only be sending it a flood of reads
+const MAX_REQUIRED_SIGNATURES: u64 = 50;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PendingWithdrawal {
+    pub amount: Uint256,
+    /// The xDai transaction that sent the funds to the home bridge
+    pub tx_hash: Uint256,
+    pub block_number: Uint256,
+    /// True once the validators have collected enough signatures for the withdrawal to be
+    /// executed on Eth
+    pub signatures_collected: bool,
+}
+
+impl TokenBridge {
+    /// Withdrawals from `own_address` sent to the home bridge in the given range of xDai blocks
+    /// that have not been relayed by the foreign bridge yet
+    pub fn get_pending_withdrawals(
+        &self,
+        xdai_from_block: Uint256,
+        xdai_to_block: Uint256,
+    ) -> Box<dyn Future<Item = Vec<PendingWithdrawal>, Error = Error>> {
+        let salf = self.clone();
+
+        Box::new(
+            self.get_withdrawal_requests(xdai_from_block, xdai_to_block)
+                .and_then(move |requests| {
+                    stream::iter_ok(requests)
+                        .and_then(move |request| salf.check_withdrawal(request))
+                        .filter_map(|withdrawal| withdrawal)
+                        .collect()
+                }),
+        )
+    }
+
+    /// Executes a withdrawal on the foreign bridge using the signatures the validators left on
+    /// the home bridge. Returns the Eth tx hash.
+    pub fn complete_withdrawal(
+        &self,
+        withdrawal: &PendingWithdrawal,
+        timeout: u64,
+    ) -> Box<dyn Future<Item = Uint256, Error = Error>> {
+        if !withdrawal.signatures_collected {
+            return self.refuse_operation(
+                Operation::ExecuteWithdrawal,
+                failure::err_msg("The bridge validators have not finished signing this withdrawal"),
+            );
+        }
+        if let Err(e) = self.check_circuit_breaker() {
+            return self.refuse_operation(Operation::ExecuteWithdrawal, e);
+        }
+
+        let xdai_web3 = self.xdai_web3.clone();
+        let eth_web3 = self.eth_web3.clone();
+        let xdai_home_bridge_address = self.xdai_home_bridge_address;
+        let xdai_foreign_bridge_address = self.xdai_foreign_bridge_address;
+        let own_address = self.own_address;
+        let secret = self.secret;
+
+        let message = withdrawal_message(
+            own_address,
+            withdrawal.amount.clone(),
+            withdrawal.tx_hash.clone(),
+            xdai_foreign_bridge_address,
+        );
+        let message_hash = Uint256::from_bytes_be(&Keccak256::digest(&message));
+        let amount = withdrawal.amount.clone();
+        let record = self.record(Operation::ExecuteWithdrawal, amount.clone());
+
+        let execution = self
+            .read_call(
+                &xdai_web3,
+                xdai_home_bridge_address,
+                "requiredSignatures()",
+                &[],
+            )
+            .and_then(|required| decode_uint(&required))
+            .and_then(|required| signature_indexes(&required))
+            .and_then({
+                let salf = self.clone();
+                move |indexes| {
+                    stream::iter_ok(indexes)
+                        .and_then(move |index| {
+                            salf.read_call(
+                                &xdai_web3,
+                                xdai_home_bridge_address,
+                                "signature(bytes32,uint256)",
+                                &[message_hash.clone().into(), index.into()],
+                            )
+                            .and_then(|signature| decode_signature(&signature))
+                        })
+                        .collect()
+                }
+            })
+            .and_then({
+                let salf = self.clone();
+                let record = record.clone();
+                move |signatures| {
+                    let mut vs = Vec::new();
+                    let mut rs = Vec::new();
+                    let mut ss = Vec::new();
+                    for (v, r, s) in signatures {
+                        vs.push(Token::Uint(v));
+                        rs.push(Token::Uint(r));
+                        ss.push(Token::Uint(s));
+                    }
+                    let payload = encode_call(
+                        "executeSignatures(uint8[],bytes32[],bytes32[],bytes)",
+                        &[
+                            Token::Dynamic(vs),
+                            Token::Dynamic(rs),
+                            Token::Dynamic(ss),
+                            Token::UnboundedBytes(message),
+                        ],
+                    );
+
+                    eth_web3
+                        .send_transaction(
+                            xdai_foreign_bridge_address,
+                            payload,
+                            0u32.into(),
+                            own_address,
+                            secret,
+                            vec![SendTxOption::GasLimit(250_000u64.into())],
+                        )
+                        .and_then(move |tx_hash| {
+                            record.submitted(tx_hash.clone());
+                            salf.wait_for(&eth_web3, tx_hash)
+                                .timeout(Duration::from_secs(timeout))
+                                .map(move |_| amount)
+                        })
+                }
+            });
+
+        Box::new(
+            self.finish_record(self.eth_web3.clone(), record.clone(), execution)
+                .and_then(move |_| match record.tx_hash() {
+                    Some(tx_hash) => Ok(tx_hash),
+                    None => bail!("Withdrawal finished without a tx hash"),
+                }),
+        )
+    }
+
+    /// Finds withdrawals that were never relayed and executes the ones that are ready, one at a
+    /// time. Returns the Eth tx hashes of the withdrawals that were completed, ones that are still
+    /// waiting on the validators are left for a later call.
+    pub fn complete_pending_withdrawals(
+        &self,
+        xdai_from_block: Uint256,
+        xdai_to_block: Uint256,
+        timeout: u64,
+    ) -> Box<dyn Future<Item = Vec<Uint256>, Error = Error>> {
+        let salf = self.clone();
+
+        Box::new(
+            self.get_pending_withdrawals(xdai_from_block, xdai_to_block)
+                .and_then(move |pending| {
+                    let ready: Vec<PendingWithdrawal> = pending
+                        .into_iter()
+                        .filter(|withdrawal| withdrawal.signatures_collected)
+                        .collect();
+                    stream::iter_ok(ready)
+                        .and_then(move |withdrawal| {
+                            info!(
+                                "Completing withdrawal of {} from xDai tx {:?}",
+                                withdrawal.amount, withdrawal.tx_hash
+                            );
+                            salf.complete_withdrawal(&withdrawal, timeout)
+                        })
+                        .collect()
+                }),
+        )
+    }
+
+    /// Returns `None` if the withdrawal has already been relayed
+    fn check_withdrawal(
+        &self,
+        request: BridgeEvent,
+    ) -> Box<dyn Future<Item = Option<PendingWithdrawal>, Error = Error>> {
+        let message = withdrawal_message(
+            self.own_address,
+            request.amount.clone(),
+            request.tx_hash.clone(),
+            self.xdai_foreign_bridge_address,
+        );
+        let message_hash = Uint256::from_bytes_be(&Keccak256::digest(&message));
+
+        let relayed = self
+            .read_call(
+                &self.eth_web3,
+                self.xdai_foreign_bridge_address,
+                "relayedMessages(bytes32)",
+                &[request.tx_hash.clone().into()],
+            )
+            .and_then(|relayed| Ok(decode_uint(&relayed)? != 0u8.into()));
+        let signed = self
+            .read_call(
+                &self.xdai_web3,
+                self.xdai_home_bridge_address,
+                "numMessagesSigned(bytes32)",
+                &[message_hash.into()],
+            )
+            .and_then(|signed| decode_uint(&signed));
+
+        Box::new(relayed.join(signed).map(move |(relayed, signed)| {
+            if relayed {
+                None
+            } else {
+                Some(PendingWithdrawal {
+                    amount: request.amount,
+                    tx_hash: request.tx_hash,
+                    block_number: request.block_number,
+                    signatures_collected: is_already_processed(&signed),
+                })
+            }
+        }))
+    }
+}
+
+/// The message the validators sign for a withdrawal, the same packed encoding the bridge
+/// contracts use: recipient, amount, the xDai tx hash and the foreign bridge address
+fn withdrawal_message(
+    recipient: Address,
+    amount: Uint256,
+    tx_hash: Uint256,
+    foreign_bridge: Address,
+) -> Vec<u8> {
+    let amount: [u8; 32] = amount.into();
+    let tx_hash: [u8; 32] = tx_hash.into();
+
+    let mut message = Vec::with_capacity(104);
+    message.extend_from_slice(recipient.as_bytes());
+    message.extend_from_slice(&amount);
+    message.extend_from_slice(&tx_hash);
+    message.extend_from_slice(foreign_bridge.as_bytes());
+    message
+}
+
+/// The home bridge sets the top bit of `numMessagesSigned` once enough validators have signed
+fn is_already_processed(num_messages_signed: &Uint256) -> bool {
+    let bytes: [u8; 32] = num_messages_signed.clone().into();
+    bytes[0] & 0x80 != 0
+}
+
+/// The index of every signature to read from the home bridge
+pub(crate) fn signature_indexes(required: &Uint256) -> Result<Vec<Uint256>, Error> {
+    if *required > MAX_REQUIRED_SIGNATURES.into() {
+        bail!(
+            "The home bridge requires {} signatures, more than the {} we accept",
+            required,
+            MAX_REQUIRED_SIGNATURES
+        );
+    }
+    let mut indexes = Vec::new();
+    let mut index = 0u64;
+    while Uint256::from(index) < *required {
+        indexes.push(index.into());
+        index += 1;
+    }
+    Ok(indexes)
+}
+
+pub(crate) fn decode_uint(output: &[u8]) -> Result<Uint256, Error> {
+    match output.get(0..32) {
+        Some(val) => Ok(Uint256::from_bytes_be(val)),
+        None => bail!("Malformed output from the bridge contract {:?}", output),
+    }
+}
+
+/// Validator signatures are stored as ABI encoded `bytes` holding r, s and v packed together
+pub(crate) fn decode_signature(output: &[u8]) -> Result<(Uint256, Uint256, Uint256), Error> {
+    match output.get(64..129) {
+        Some(signature) => Ok((
+            signature[64].into(),
+            Uint256::from_bytes_be(&signature[0..32]),
+            Uint256::from_bytes_be(&signature[32..64]),
+        )),
+        None => bail!("Malformed signature from the home bridge {:?}", output),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_withdrawal_message() {
+        let recipient = Address::from_str("0x79AE13432950bF5CDC3499f8d4Cf5963c3F0d42c").unwrap();
+        let bridge = Address::from_str("0x4aa42145Aa6Ebf72e164C9bBC74fbD3788045016").unwrap();
+
+        let message = withdrawal_message(recipient, 1000u64.into(), 7u64.into(), bridge);
+        assert_eq!(message.len(), 104);
+        assert_eq!(&message[0..20], recipient.as_bytes());
+        assert_eq!(Uint256::from_bytes_be(&message[20..52]), 1000u64.into());
+        assert_eq!(Uint256::from_bytes_be(&message[52..84]), 7u64.into());
+        assert_eq!(&message[84..104], bridge.as_bytes());
+    }
+
+    #[test]
+    fn test_decode_signature() {
+        let mut output = vec![0u8; 160];
+        output[31] = 32;
+        output[63] = 65;
+        output[95] = 1;
+        output[127] = 2;
+        output[128] = 27;
+
+        let (v, r, s) = decode_signature(&output).unwrap();
+        assert_eq!(v, 27u8.into());
+        assert_eq!(r, 1u8.into());
+        assert_eq!(s, 2u8.into());
+        assert!(decode_signature(&output[0..100]).is_err());
+
+        let mut signed = [0u8; 32];
+        signed[31] = 2;
+        assert!(!is_already_processed(&Uint256::from_bytes_be(&signed)));
+        signed[0] = 0x80;
+        assert!(is_already_processed(&Uint256::from_bytes_be(&signed)));
+    }
+
+    #[test]
+    fn test_signature_indexes() {
+        assert_eq!(
+            signature_indexes(&3u64.into()).unwrap(),
+            vec![0u64.into(), 1u64.into(), 2u64.into()]
+        );
+        assert_eq!(signature_indexes(&50u64.into()).unwrap().len(), 50);
+        assert!(signature_indexes(&51u64.into()).is_err());
+        assert!(signature_indexes(&Uint256::from_bytes_be(&[0xff; 32])).is_err());
+    }
+}
@@ -0,0 +1,250 @@
+//! Retrying requests to the full nodes that failed for reasons that are likely to go away, like a
+//! dropped connection or a slow node. Only reads and waits for transactions are retried, asking
+//! again for something we already asked for can't change anything. Sending a transaction is
+//! never retried here, a send that looked like it failed may still have reached the node and a
+//! second one would move the funds twice.
+
+use crate::TokenBridge;
+use clarity::abi::Token;
+use clarity::Address;
+use failure::Error;
+use futures::future::{loop_fn, Loop};
+use futures::Future;
+use futures_timer::Delay;
+use num256::Uint256;
+use rand::Rng;
+use std::io;
+use std::time::Duration;
+use web30::client::Web3;
+use web30::types::{Block, TransactionResponse};
+
+/// What kind of failure a request ran into, for deciding whether to try it again
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ErrorClass {
+    /// The node took too long to answer
+    Timeout,
+    /// We couldn't reach the node or it hung up
+    Connection,
+    /// Everything else, including errors the node answered with
+    Other,
+}
+
+impl ErrorClass {
+    /// Looks for an io error anywhere in the chain of causes. The node client doesn't always
+    /// keep the io error around so its message is checked too.
+    pub fn classify(error: &Error) -> ErrorClass {
+        for cause in error.iter_chain() {
+            if let Some(e) = cause.downcast_ref::<io::Error>() {
+                match e.kind() {
+                    io::ErrorKind::TimedOut => return ErrorClass::Timeout,
+                    io::ErrorKind::ConnectionRefused
+                    | io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::NotConnected
+                    | io::ErrorKind::BrokenPipe
+                    | io::ErrorKind::UnexpectedEof => return ErrorClass::Connection,
+                    _ => {}
+                }
+            }
+        }
+
+        let message = error.to_string().to_lowercase();
+        if message.contains("timed out") || message.contains("timeout") {
+            ErrorClass::Timeout
+        } else if message.contains("connect") || message.contains("disconnected") {
+            ErrorClass::Connection
+        } else {
+            ErrorClass::Other
+        }
+    }
+}
+
+/// How to retry reads and waits, the wait before each retry doubles up to `max_backoff_ms`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetryPolicy {
+    /// Attempts including the first one
+    pub max_attempts: u32,
+    /// Milliseconds to wait before the first retry
+    pub initial_backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    /// Each wait is moved by up to this percentage either way at random, so clients that failed
+    /// together don't all retry together
+    pub jitter_percent: u64,
+    /// The kinds of failure worth retrying
+    pub retry_on: Vec<ErrorClass>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_backoff_ms: 500,
+            max_backoff_ms: 10_000,
+            jitter_percent: 20,
+            retry_on: vec![ErrorClass::Timeout, ErrorClass::Connection],
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Whether to try again after `attempt` attempts have failed, the last with `error`
+    pub fn should_retry(&self, error: &Error, attempt: u32) -> bool {
+        attempt < self.max_attempts && self.retry_on.contains(&ErrorClass::classify(error))
+    }
+
+    /// The wait after `attempt` failed attempts. `jitter` between -1 and 1 picks where in the
+    /// jitter range the wait lands.
+    pub fn backoff(&self, attempt: u32, jitter: f64) -> Duration {
+        let doublings = attempt.saturating_sub(1).min(32);
+        let base = self
+            .initial_backoff_ms
+            .saturating_mul(1u64 << doublings)
+            .min(self.max_backoff_ms) as f64;
+        let spread = base * self.jitter_percent.min(100) as f64 / 100.0;
+        Duration::from_millis((base + spread * jitter.clamp(-1.0, 1.0)) as u64)
+    }
+
+    /// Runs the request `make` builds until it succeeds or fails in a way this policy doesn't
+    /// retry. `make` is called again for every attempt so it must only read.
+    pub fn retry<T, F>(
+        &self,
+        description: &'static str,
+        make: F,
+    ) -> Box<dyn Future<Item = T, Error = Error>>
+    where
+        T: 'static,
+        F: Fn() -> Box<dyn Future<Item = T, Error = Error>> + 'static,
+    {
+        let policy = self.clone();
+
+        Box::new(loop_fn(1u32, move |attempt| {
+            let policy = policy.clone();
+            make().then(
+                move |res| -> Box<dyn Future<Item = Loop<T, u32>, Error = Error>> {
+                    match res {
+                        Ok(value) => Box::new(futures::future::ok(Loop::Break(value))),
+                        Err(e) if policy.should_retry(&e, attempt) => {
+                            let wait =
+                                policy.backoff(attempt, rand::thread_rng().gen_range(-1.0, 1.0));
+                            warn!(
+                                "{} failed on attempt {}, retrying in {:?} {:?}",
+                                description, attempt, wait, e
+                            );
+                            tracing::Span::current().record("attempt", attempt + 1);
+                            Box::new(
+                                Delay::new(wait)
+                                    .from_err()
+                                    .map(move |_| Loop::Continue(attempt + 1)),
+                            )
+                        }
+                        Err(e) => Box::new(futures::future::err(e)),
+                    }
+                },
+            )
+        }))
+    }
+}
+
+impl TokenBridge {
+    /// Runs a read through the bridge's retry policy, or just once if it has none
+    pub(crate) fn retry_read<T, F>(
+        &self,
+        description: &'static str,
+        make: F,
+    ) -> Box<dyn Future<Item = T, Error = Error>>
+    where
+        T: 'static,
+        F: Fn() -> Box<dyn Future<Item = T, Error = Error>> + 'static,
+    {
+        match self.retry_policy {
+            Some(ref policy) => policy.retry(description, make),
+            None => make(),
+        }
+    }
+
+    /// A read only contract call, retried under the bridge's retry policy
+    pub(crate) fn read_call(
+        &self,
+        web3: &Web3,
+        contract: Address,
+        sig: &'static str,
+        tokens: &[Token],
+    ) -> Box<dyn Future<Item = Vec<u8>, Error = Error>> {
+        let web3 = web3.clone();
+        let tokens = tokens.to_vec();
+        let own_address = self.own_address;
+        self.retry_read(sig, move || {
+            web3.contract_call(contract, sig, &tokens, own_address)
+        })
+    }
+
+    pub(crate) fn read_latest_block(
+        &self,
+        web3: &Web3,
+    ) -> Box<dyn Future<Item = Block, Error = Error>> {
+        let web3 = web3.clone();
+        self.retry_read("eth_getBlockByNumber", move || web3.eth_get_latest_block())
+    }
+
+    pub(crate) fn read_gas_price(
+        &self,
+        web3: &Web3,
+    ) -> Box<dyn Future<Item = Uint256, Error = Error>> {
+        let web3 = web3.clone();
+        self.retry_read("eth_gasPrice", move || web3.eth_gas_price())
+    }
+
+    /// Waits for a transaction that was already sent to be mined. Only the wait is retried, the
+    /// transaction itself is never sent again.
+    pub(crate) fn wait_for(
+        &self,
+        web3: &Web3,
+        tx_hash: Uint256,
+    ) -> Box<dyn Future<Item = TransactionResponse, Error = Error>> {
+        let web3 = web3.clone();
+        self.retry_read("wait_for_transaction", move || {
+            web3.wait_for_transaction(tx_hash.clone().into())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_class() {
+        let timeout: Error = io::Error::new(io::ErrorKind::TimedOut, "timed out").into();
+        let reset: Error = io::Error::new(io::ErrorKind::ConnectionReset, "reset").into();
+        let reverted = failure::format_err!("execution reverted");
+        let refused = failure::format_err!("Failed to connect to host: Connection refused");
+
+        assert_eq!(ErrorClass::classify(&timeout), ErrorClass::Timeout);
+        assert_eq!(ErrorClass::classify(&reset), ErrorClass::Connection);
+        assert_eq!(ErrorClass::classify(&refused), ErrorClass::Connection);
+        assert_eq!(ErrorClass::classify(&reverted), ErrorClass::Other);
+
+        let policy = RetryPolicy::default();
+        assert!(policy.should_retry(&timeout, 1));
+        assert!(policy.should_retry(&reset, 2));
+        assert!(!policy.should_retry(&reset, 3));
+        assert!(!policy.should_retry(&reverted, 1));
+    }
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy {
+            initial_backoff_ms: 100,
+            max_backoff_ms: 1000,
+            jitter_percent: 10,
+            ..Default::default()
+        };
+        assert_eq!(policy.backoff(1, 0.0), Duration::from_millis(100));
+        assert_eq!(policy.backoff(2, 0.0), Duration::from_millis(200));
+        assert_eq!(policy.backoff(3, 1.0), Duration::from_millis(440));
+        assert_eq!(policy.backoff(4, -1.0), Duration::from_millis(720));
+        // Capped at the max before jitter
+        assert_eq!(policy.backoff(10, 0.0), Duration::from_millis(1000));
+    }
+}
@@ -0,0 +1,688 @@
+//! Conversions queued to run later or on a repeating schedule. Jobs are kept in a JSON file that
+//! is read and rewritten on every change under a lock on the file, so a job cancelled or
+//! started from another process is not run.
+//! The scheduler runs due jobs with at most one in flight per account, since two operations
+//! sending from the same account at once would race for its nonce.
+//!
+//! A failed job is only retried if it never got as far as submitting a transaction. Once a
+//! transaction was sent there is no telling from the error whether it will still be mined, so
+//! sending it again could move the funds twice.
+
+use crate::amount::{DaiAmount, EthAmount, XdaiAmount};
+use crate::events::Event;
+use crate::TokenBridge;
+use clarity::Address;
+use failure::bail;
+use failure::Error;
+use fs2::FileExt;
+use futures::{Future, Stream};
+use futures_timer::Interval;
+use num256::Uint256;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum JobAction {
+    EthToDaiSwap {
+        amount: EthAmount,
+    },
+    DaiToEthSwap {
+        amount: DaiAmount,
+    },
+    /// Deposits to `recipient` on xDai if set, otherwise to the account itself
+    DaiToXdaiDeposit {
+        amount: DaiAmount,
+        #[serde(default)]
+        recipient: Option<Address>,
+    },
+    XdaiToDaiWithdrawal {
+        amount: XdaiAmount,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Schedule {
+    /// Run once at this unix time
+    Once { at: u64 },
+    /// Run at `start` and every `interval` seconds after it, a week for "every Sunday"
+    Every { start: u64, interval: u64 },
+}
+
+impl Schedule {
+    pub fn first_run(self) -> u64 {
+        match self {
+            Schedule::Once { at } => at,
+            Schedule::Every { start, .. } => start,
+        }
+    }
+
+    /// The first time the job should run after `now`, if it runs again at all
+    pub fn next_run_after(self, now: u64) -> Option<u64> {
+        match self {
+            Schedule::Once { .. } => None,
+            Schedule::Every { start, .. } if now < start => Some(start),
+            Schedule::Every { start, interval } => {
+                let interval = interval.max(1);
+                Some(start + ((now - start) / interval + 1) * interval)
+            }
+        }
+    }
+}
+
+/// How often a job that failed without submitting anything is tried again. The wait doubles
+/// after each attempt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JobRetry {
+    pub max_attempts: u32,
+    /// Seconds to wait before the first retry
+    pub backoff: u64,
+}
+
+impl Default for JobRetry {
+    fn default() -> JobRetry {
+        JobRetry {
+            max_attempts: 3,
+            backoff: 60,
+        }
+    }
+}
+
+impl JobRetry {
+    /// Seconds to wait after the `attempts`th failed attempt
+    pub fn delay(self, attempts: u32) -> u64 {
+        let doublings = attempts.saturating_sub(1).min(32);
+        self.backoff.saturating_mul(1u64 << doublings)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+    Cancelled,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Job {
+    pub id: u64,
+    /// The account that sends the job's transactions
+    pub account: Address,
+    pub action: JobAction,
+    pub schedule: Schedule,
+    #[serde(default)]
+    pub retry: JobRetry,
+    pub status: JobStatus,
+    /// Attempts at the current run, reset once a run finishes
+    pub attempts: u32,
+    /// Unix time the job is due
+    pub next_run: u64,
+    /// What the last successful run received
+    pub last_received: Option<Uint256>,
+    pub last_error: Option<String>,
+    /// Unix timestamp in seconds
+    pub created_at: u64,
+    /// Unix timestamp in seconds
+    pub updated_at: u64,
+}
+
+impl Job {
+    fn is_due(&self, now: u64) -> bool {
+        self.status == JobStatus::Queued && self.next_run <= now
+    }
+
+    /// Moves the job on after a run. `submitted` says whether the run sent any transactions, a
+    /// failure is only retried if it didn't.
+    fn finish(&mut self, result: Result<Uint256, String>, submitted: bool, now: u64) {
+        self.updated_at = now;
+        let retry = match result {
+            Ok(received) => {
+                self.last_received = Some(received);
+                self.last_error = None;
+                false
+            }
+            Err(error) => {
+                self.last_error = Some(error);
+                !submitted && self.attempts < self.retry.max_attempts
+            }
+        };
+
+        if retry {
+            self.status = JobStatus::Queued;
+            self.next_run = now + self.retry.delay(self.attempts);
+            return;
+        }
+        self.attempts = 0;
+        match self.schedule.next_run_after(now) {
+            Some(next_run) => {
+                self.status = JobStatus::Queued;
+                self.next_run = next_run;
+            }
+            None if self.last_error.is_some() => self.status = JobStatus::Failed,
+            None => self.status = JobStatus::Succeeded,
+        }
+    }
+}
+
+/// The persistent list of jobs
+#[derive(Clone)]
+pub struct JobQueue {
+    path: PathBuf,
+    lock: Arc<Mutex<()>>,
+}
+
+impl JobQueue {
+    /// The file is created the first time a job is queued
+    pub fn new<P: Into<PathBuf>>(path: P) -> JobQueue {
+        JobQueue {
+            path: path.into(),
+            lock: Arc::new(Mutex::new(())),
+        }
+    }
+
+    pub fn jobs(&self) -> Result<Vec<Job>, Error> {
+        let _lock = self.lock_file()?;
+        self.load()
+    }
+
+    pub fn enqueue(
+        &self,
+        account: Address,
+        action: JobAction,
+        schedule: Schedule,
+        retry: JobRetry,
+    ) -> Result<Job, Error> {
+        let now = unix_now();
+        self.modify(|jobs| {
+            let job = Job {
+                id: jobs.iter().map(|job| job.id + 1).max().unwrap_or(1),
+                account,
+                action,
+                schedule,
+                retry,
+                status: JobStatus::Queued,
+                attempts: 0,
+                next_run: schedule.first_run(),
+                last_received: None,
+                last_error: None,
+                created_at: now,
+                updated_at: now,
+            };
+            jobs.push(job.clone());
+            Ok(job)
+        })
+    }
+
+    /// Only queued jobs can be cancelled, a running job may already have sent its transaction
+    pub fn cancel(&self, id: u64) -> Result<Job, Error> {
+        self.update(id, |job| {
+            if job.status != JobStatus::Queued {
+                bail!("Job {} is {:?} and can't be cancelled", job.id, job.status);
+            }
+            job.status = JobStatus::Cancelled;
+            job.updated_at = unix_now();
+            Ok(())
+        })
+    }
+
+    /// Marks a due job as running, `None` if it isn't queued and due anymore, for example
+    /// because another scheduler on the same queue started it first, or if another job for the
+    /// same account is running. Two jobs sending from one account at once would race for its
+    /// nonces.
+    pub fn start(&self, id: u64, now: u64) -> Result<Option<Job>, Error> {
+        self.modify(|jobs| {
+            let index = match jobs.iter().position(|job| job.id == id) {
+                Some(index) if jobs[index].is_due(now) => index,
+                _ => return Ok(None),
+            };
+            let account = jobs[index].account;
+            if jobs
+                .iter()
+                .any(|job| job.account == account && job.status == JobStatus::Running)
+            {
+                return Ok(None);
+            }
+
+            let job = &mut jobs[index];
+            job.status = JobStatus::Running;
+            job.attempts += 1;
+            job.updated_at = now;
+            Ok(Some(job.clone()))
+        })
+    }
+
+    pub fn update<F>(&self, id: u64, change: F) -> Result<Job, Error>
+    where
+        F: FnOnce(&mut Job) -> Result<(), Error>,
+    {
+        self.modify(|jobs| match jobs.iter_mut().find(|job| job.id == id) {
+            Some(job) => {
+                change(job)?;
+                Ok(job.clone())
+            }
+            None => bail!("There is no job {}", id),
+        })
+    }
+
+    fn modify<T, F>(&self, change: F) -> Result<T, Error>
+    where
+        F: FnOnce(&mut Vec<Job>) -> Result<T, Error>,
+    {
+        let _lock = self.lock_file()?;
+        let mut jobs = self.load()?;
+        let result = change(&mut jobs)?;
+        self.save(&jobs)?;
+        Ok(result)
+    }
+
+    /// Blocks until no other process or thread is using the queue, the lock is held until what
+    /// this returns is dropped
+    fn lock_file(&self) -> Result<(MutexGuard<'_, ()>, File), Error> {
+        let guard = self.lock.lock().unwrap();
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(self.path.with_extension("lock"))?;
+        file.lock_exclusive()?;
+        Ok((guard, file))
+    }
+
+    /// Held by a running scheduler until it stops, fails if another scheduler already holds it
+    fn lock_runner(&self) -> Result<File, Error> {
+        let path = self.path.with_extension("run.lock");
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)?;
+        if file.try_lock_exclusive().is_err() {
+            bail!(
+                "Another scheduler is already running the jobs in {}",
+                self.path.display()
+            );
+        }
+        Ok(file)
+    }
+
+    fn load(&self) -> Result<Vec<Job>, Error> {
+        match File::open(&self.path) {
+            Ok(file) => Ok(serde_json::from_reader(file)?),
+            Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Writes to a temporary file first so a crash can't leave a half written queue
+    fn save(&self, jobs: &[Job]) -> Result<(), Error> {
+        let temporary = self.path.with_extension("tmp");
+        fs::write(&temporary, serde_json::to_vec_pretty(jobs)?)?;
+        fs::rename(&temporary, &self.path)?;
+        Ok(())
+    }
+}
+
+/// Runs the jobs in a `JobQueue` on the bridges of the accounts they belong to
+#[derive(Clone)]
+pub struct Scheduler {
+    queue: JobQueue,
+    accounts: HashMap<Address, TokenBridge>,
+    in_flight: Arc<Mutex<HashSet<Address>>>,
+    /// How long to wait for each transaction, in seconds
+    timeout: u64,
+}
+
+impl Scheduler {
+    pub fn new(queue: JobQueue, timeout: u64) -> Scheduler {
+        Scheduler {
+            queue,
+            accounts: HashMap::new(),
+            in_flight: Arc::new(Mutex::new(HashSet::new())),
+            timeout,
+        }
+    }
+
+    /// Jobs for `bridge.own_address` are run on `bridge`, jobs for accounts that weren't added
+    /// stay queued
+    pub fn add_account(&mut self, bridge: TokenBridge) {
+        self.accounts.insert(bridge.own_address, bridge);
+    }
+
+    /// Jobs left running by a scheduler that stopped part way through. Whether their
+    /// transactions went out is unknown, so they are failed rather than run again and the
+    /// journal has to be checked by hand. Only called while holding the queue's run lock, any
+    /// job still running then belongs to a scheduler that is gone.
+    fn fail_interrupted(&self) -> Result<Vec<Job>, Error> {
+        let now = unix_now();
+        let interrupted: Vec<u64> = self
+            .queue
+            .jobs()?
+            .into_iter()
+            .filter(|job| job.status == JobStatus::Running)
+            .map(|job| job.id)
+            .collect();
+        interrupted
+            .into_iter()
+            .map(|id| {
+                self.queue.update(id, |job| {
+                    job.status = JobStatus::Failed;
+                    job.last_error = Some("Interrupted while running".to_string());
+                    job.updated_at = now;
+                    Ok(())
+                })
+            })
+            .collect()
+    }
+
+    /// Starts every job that is due at `now` whose account has nothing in flight, oldest first.
+    /// Resolves to the jobs' states once all of them have finished.
+    pub fn run_due(&self, now: u64) -> Box<dyn Future<Item = Vec<Job>, Error = Error>> {
+        let mut jobs = match self.queue.jobs() {
+            Ok(jobs) => jobs,
+            Err(e) => return Box::new(futures::future::err(e)),
+        };
+        jobs.sort_by_key(|job| (job.next_run, job.id));
+
+        // Every job is marked as running before any of them is, so a failure part way through
+        // can put back the ones already marked without anything having been sent
+        let mut started = Vec::new();
+        for job in jobs.into_iter().filter(|job| job.is_due(now)) {
+            let bridge = match self.accounts.get(&job.account) {
+                Some(bridge) => bridge.clone(),
+                None => continue,
+            };
+            if !self.in_flight.lock().unwrap().insert(job.account) {
+                continue;
+            }
+            match self.queue.start(job.id, now) {
+                Ok(Some(job)) => started.push((bridge, job)),
+                Ok(None) => {
+                    self.in_flight.lock().unwrap().remove(&job.account);
+                }
+                Err(e) => {
+                    self.in_flight.lock().unwrap().remove(&job.account);
+                    self.unstart(started.into_iter().map(|(_, job)| job), now);
+                    return Box::new(futures::future::err(e));
+                }
+            }
+        }
+
+        Box::new(futures::future::join_all(
+            started
+                .into_iter()
+                .map(|(bridge, job)| self.run_job(bridge, job))
+                .collect::<Vec<_>>(),
+        ))
+    }
+
+    /// Puts jobs that were marked as running but never run back in the queue
+    fn unstart<I: Iterator<Item = Job>>(&self, jobs: I, now: u64) {
+        for job in jobs {
+            self.in_flight.lock().unwrap().remove(&job.account);
+            let requeued = self.queue.update(job.id, |job| {
+                job.status = JobStatus::Queued;
+                job.attempts = job.attempts.saturating_sub(1);
+                job.updated_at = now;
+                Ok(())
+            });
+            if let Err(e) = requeued {
+                error!("Failed to put job {} back in the queue {:?}", job.id, e);
+            }
+        }
+    }
+
+    /// Checks for due jobs every `interval` until an error reading or writing the queue. Each job
+    /// is spawned on the current actix system so a slow job doesn't hold up other accounts.
+    /// Only one scheduler can run a queue at a time, the queue is locked until this resolves.
+    pub fn run(&self, interval: Duration) -> Box<dyn Future<Item = (), Error = Error>> {
+        let runner = match self.queue.lock_runner() {
+            Ok(runner) => runner,
+            Err(e) => return Box::new(futures::future::err(e)),
+        };
+        if let Err(e) = self.fail_interrupted() {
+            return Box::new(futures::future::err(e));
+        }
+        let salf = self.clone();
+
+        Box::new(
+            Interval::new(interval)
+                .map_err(Error::from)
+                .for_each(move |_| {
+                    let _runner = &runner;
+                    actix::spawn(salf.run_due(unix_now()).then(|res| {
+                        if let Err(e) = res {
+                            error!("Failed to update the job queue {:?}", e);
+                        }
+                        Ok(())
+                    }));
+                    Ok(())
+                }),
+        )
+    }
+
+    fn run_job(&self, bridge: TokenBridge, job: Job) -> Box<dyn Future<Item = Job, Error = Error>> {
+        let queue = self.queue.clone();
+        let in_flight = self.in_flight.clone();
+        let account = job.account;
+        let id = job.id;
+        info!("Running job {} {:?}", id, job.action);
+
+        // Anything submitted while the job runs is the job's, nothing else sends from this
+        // account while it is in flight
+        let mut events = bridge.subscribe();
+        let operation = match job.action {
+            JobAction::EthToDaiSwap { amount } => bridge.eth_to_dai_swap(amount, self.timeout),
+            JobAction::DaiToEthSwap { amount } => bridge.dai_to_eth_swap(amount, self.timeout),
+            JobAction::DaiToXdaiDeposit {
+                amount,
+                recipient: Some(recipient),
+            } => bridge.dai_to_xdai_bridge_to(recipient, amount, self.timeout),
+            JobAction::DaiToXdaiDeposit {
+                amount,
+                recipient: None,
+            } => bridge.dai_to_xdai_bridge(amount, self.timeout),
+            // Resolves once the withdrawal is mined on xDai, the Dai is paid out later
+            JobAction::XdaiToDaiWithdrawal { amount } => Box::new(
+                bridge
+                    .xdai_to_dai_bridge(amount.clone(), self.timeout)
+                    .map(move |_| amount.into_wei()),
+            ),
+        };
+
+        Box::new(operation.then(move |result| {
+            events.close();
+            events.collect().then(move |received| {
+                let submitted = received
+                    .unwrap_or_default()
+                    .iter()
+                    .any(|event| matches!(event, Event::TxSubmitted { .. }));
+                let result = result.map_err(|e| e.to_string());
+                if let Err(ref e) = result {
+                    warn!("Job {} failed {}", id, e);
+                }
+                let job = queue.update(id, |job| {
+                    job.finish(result, submitted, unix_now());
+                    Ok(())
+                });
+                in_flight.lock().unwrap().remove(&account);
+                job
+            })
+        }))
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    const WEEK: u64 = 7 * 24 * 60 * 60;
+
+    #[test]
+    fn test_job_queue() {
+        let path = env::temp_dir().join(format!("auto_bridge_jobs_{}", rand::random::<u64>()));
+        let queue = JobQueue::new(path.clone());
+        assert!(queue.jobs().unwrap().is_empty());
+
+        let action = JobAction::DaiToXdaiDeposit {
+            amount: DaiAmount::from_wei(100u64.into()),
+            recipient: None,
+        };
+        let first = queue
+            .enqueue(
+                Address::default(),
+                action.clone(),
+                Schedule::Once { at: 1000 },
+                JobRetry::default(),
+            )
+            .unwrap();
+        let second = queue
+            .enqueue(
+                Address::default(),
+                action,
+                Schedule::Every {
+                    start: 2000,
+                    interval: WEEK,
+                },
+                JobRetry::default(),
+            )
+            .unwrap();
+        assert_eq!((first.id, second.id), (1, 2));
+        assert_eq!(second.next_run, 2000);
+
+        queue.cancel(1).unwrap();
+        assert!(queue.cancel(1).is_err());
+        assert!(queue.cancel(3).is_err());
+
+        // A new queue on the same file sees the same jobs
+        let jobs = JobQueue::new(path.clone()).jobs().unwrap();
+        assert_eq!(jobs.len(), 2);
+        assert_eq!(jobs[0].status, JobStatus::Cancelled);
+        assert_eq!(jobs[1].status, JobStatus::Queued);
+
+        // Only a queued job that is due can be started, and only once
+        assert_eq!(queue.start(2, 1999).unwrap(), None);
+        assert_eq!(
+            queue.start(2, 2000).unwrap().map(|job| job.status),
+            Some(JobStatus::Running)
+        );
+        assert_eq!(queue.start(2, 2000).unwrap(), None);
+        assert_eq!(queue.start(1, 2000).unwrap(), None);
+
+        // Nothing else starts for the account while its job is running
+        let third = queue
+            .enqueue(
+                Address::default(),
+                JobAction::EthToDaiSwap {
+                    amount: EthAmount::from_wei(100u64.into()),
+                },
+                Schedule::Once { at: 2000 },
+                JobRetry::default(),
+            )
+            .unwrap();
+        assert_eq!(queue.start(third.id, 2000).unwrap(), None);
+        queue
+            .update(2, |job| {
+                job.finish(Ok(100u64.into()), true, 2000);
+                Ok(())
+            })
+            .unwrap();
+        assert!(queue.start(third.id, 2000).unwrap().is_some());
+
+        fs::remove_file(path.with_extension("lock")).unwrap();
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_one_scheduler_per_queue() {
+        let path = env::temp_dir().join(format!("auto_bridge_jobs_{}", rand::random::<u64>()));
+        let queue = JobQueue::new(path.clone());
+        queue
+            .enqueue(
+                Address::default(),
+                JobAction::EthToDaiSwap {
+                    amount: EthAmount::from_wei(100u64.into()),
+                },
+                Schedule::Once { at: 1000 },
+                JobRetry::default(),
+            )
+            .unwrap();
+
+        // The job is running under the first scheduler, a second one must leave it alone
+        let first = Scheduler::new(queue.clone(), 600).run(Duration::from_secs(60));
+        queue.start(1, 1000).unwrap();
+        let second = Scheduler::new(JobQueue::new(path.clone()), 600).run(Duration::from_secs(60));
+        assert!(second.wait().is_err());
+        assert_eq!(queue.jobs().unwrap()[0].status, JobStatus::Running);
+
+        // Once the first stops the job is known to be interrupted
+        drop(first);
+        let third = Scheduler::new(queue.clone(), 600).run(Duration::from_secs(60));
+        assert_eq!(queue.jobs().unwrap()[0].status, JobStatus::Failed);
+        drop(third);
+
+        fs::remove_file(path.with_extension("run.lock")).unwrap();
+        fs::remove_file(path.with_extension("lock")).unwrap();
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_job_finish() {
+        let mut job = Job {
+            id: 1,
+            account: Address::default(),
+            action: JobAction::XdaiToDaiWithdrawal {
+                amount: XdaiAmount::from_wei(100u64.into()),
+            },
+            schedule: Schedule::Once { at: 1000 },
+            retry: JobRetry {
+                max_attempts: 2,
+                backoff: 60,
+            },
+            status: JobStatus::Running,
+            attempts: 1,
+            next_run: 1000,
+            last_received: None,
+            last_error: None,
+            created_at: 0,
+            updated_at: 0,
+        };
+
+        // Nothing was sent, so it is tried again after the backoff
+        job.finish(Err("node unreachable".to_string()), false, 1000);
+        assert_eq!(job.status, JobStatus::Queued);
+        assert_eq!(job.next_run, 1060);
+
+        // A transaction went out, trying again could send the funds twice
+        job.attempts = 1;
+        job.finish(Err("timed out".to_string()), true, 1060);
+        assert_eq!(job.status, JobStatus::Failed);
+
+        // A recurring job moves on to its next run whatever happens
+        job.schedule = Schedule::Every {
+            start: 1000,
+            interval: WEEK,
+        };
+        job.attempts = 1;
+        job.finish(Ok(100u64.into()), true, 1060);
+        assert_eq!(job.status, JobStatus::Queued);
+        assert_eq!(job.next_run, 1000 + WEEK);
+        assert_eq!(job.attempts, 0);
+
+        assert_eq!(JobRetry::default().delay(1), 60);
+        assert_eq!(JobRetry::default().delay(3), 240);
+        assert_eq!(Schedule::Once { at: 5 }.next_run_after(10), None);
+    }
+}
@@ -0,0 +1,315 @@
+//! Moving more than the bridge takes at once. An amount over the bridge's max per transaction is
+//! sent as several evenly sized chunks, and whatever is over today's limit waits for the bridge's
+//! day to roll over. The progress of a split transfer can be read while it runs.
+
+use crate::amount::{DaiAmount, XdaiAmount};
+use crate::journal::{in_span, Operation};
+use crate::plan::BridgeLimits;
+use crate::TokenBridge;
+use failure::bail;
+use failure::Error;
+use futures::{stream, Future, Stream};
+use futures_timer::Delay;
+use num256::Uint256;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const DAY_SECONDS: u64 = 24 * 60 * 60;
+
+/// How long to wait past midnight before trying again, so our clock and the chain's agree it is
+/// a new day
+const DAY_ROLLOVER_MARGIN: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SplitProgress {
+    /// `DaiToXdaiDeposit` or `XdaiToDaiWithdrawal`
+    pub operation: Operation,
+    pub total: Uint256,
+    pub sent: Uint256,
+    /// Every chunk sent so far in order
+    pub chunks: Vec<Uint256>,
+    /// When the rest will be sent as a unix time, set while waiting for the daily limit to reset
+    pub resumes_at: Option<u64>,
+}
+
+impl SplitProgress {
+    pub fn remaining(&self) -> Uint256 {
+        self.total.clone() - self.sent.clone()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.sent >= self.total
+    }
+}
+
+/// A view of a split transfer that is still running
+#[derive(Debug, Clone)]
+pub struct SplitHandle {
+    progress: Arc<Mutex<SplitProgress>>,
+}
+
+impl SplitHandle {
+    pub fn progress(&self) -> SplitProgress {
+        self.progress.lock().unwrap().clone()
+    }
+}
+
+/// The chunks of `amount` to send today. The whole amount is split into chunks as even as
+/// `limits.max_per_tx` allows and today's chunks are as many of those as fit under today's limit,
+/// so what is left for later can always be split too. Fails before anything is sent if the
+/// amount can't be split into chunks within the limits. An empty result means nothing can go
+/// until the daily limit resets.
+pub fn split_amount(amount: &Uint256, limits: &BridgeLimits) -> Result<Vec<Uint256>, Error> {
+    let zero: Uint256 = 0u8.into();
+    if *amount == zero {
+        return Ok(Vec::new());
+    }
+    if limits.max_per_tx < limits.min_per_tx || limits.max_per_tx == zero {
+        bail!(
+            "The bridge's max per transaction of {} is below its minimum of {}",
+            limits.max_per_tx,
+            limits.min_per_tx
+        );
+    }
+
+    let one: Uint256 = 1u8.into();
+    let count =
+        (amount.clone() + limits.max_per_tx.clone() - one.clone()) / limits.max_per_tx.clone();
+    let size = amount.clone() / count.clone();
+    if size < limits.min_per_tx {
+        bail!(
+            "{} can't be split into transfers of between {} and {}",
+            amount,
+            limits.min_per_tx,
+            limits.max_per_tx
+        );
+    }
+
+    // The first `extra` chunks take one more wei each so the chunks add up to `amount`
+    let extra = amount.clone() - size.clone() * count.clone();
+    let mut left_today = limits.remaining_today.clone();
+    Ok((0u64..)
+        .map(Uint256::from)
+        .take_while(|index| *index < count)
+        .map(|index| {
+            if index < extra {
+                size.clone() + one.clone()
+            } else {
+                size.clone()
+            }
+        })
+        .take_while(|chunk| {
+            if *chunk > left_today {
+                return false;
+            }
+            left_today = left_today.clone() - chunk.clone();
+            true
+        })
+        .collect())
+}
+
+/// Unix time at which the bridge's current day ends
+fn next_day_start(now: u64) -> u64 {
+    (now / DAY_SECONDS + 1) * DAY_SECONDS
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
+
+impl TokenBridge {
+    /// Bridges `dai_amount` Dai to xDai in as many deposits as the bridge's limits need, waiting
+    /// for the daily limit to reset when it runs out. The returned future resolves once
+    /// everything has been deposited, which may take days.
+    pub fn dai_to_xdai_bridge_split(
+        &self,
+        dai_amount: DaiAmount,
+        timeout: u64,
+    ) -> (
+        SplitHandle,
+        Box<dyn Future<Item = SplitProgress, Error = Error>>,
+    ) {
+        self.split_transfer(Operation::DaiToXdaiDeposit, dai_amount.into_wei(), timeout)
+    }
+
+    /// Bridges `xdai_amount` xDai to Dai in as many withdrawals as the bridge's limits need,
+    /// waiting for the daily limit to reset when it runs out
+    pub fn xdai_to_dai_bridge_split(
+        &self,
+        xdai_amount: XdaiAmount,
+        timeout: u64,
+    ) -> (
+        SplitHandle,
+        Box<dyn Future<Item = SplitProgress, Error = Error>>,
+    ) {
+        self.split_transfer(
+            Operation::XdaiToDaiWithdrawal,
+            xdai_amount.into_wei(),
+            timeout,
+        )
+    }
+
+    fn split_transfer(
+        &self,
+        operation: Operation,
+        amount: Uint256,
+        timeout: u64,
+    ) -> (
+        SplitHandle,
+        Box<dyn Future<Item = SplitProgress, Error = Error>>,
+    ) {
+        let handle = SplitHandle {
+            progress: Arc::new(Mutex::new(SplitProgress {
+                operation,
+                total: amount,
+                sent: 0u8.into(),
+                chunks: Vec::new(),
+                resumes_at: None,
+            })),
+        };
+        let span = tracing::info_span!(
+            "split_transfer",
+            operation = ?operation,
+            total = %handle.progress().total,
+        );
+        let transfer = in_span(span, || self.continue_split(handle.clone(), timeout));
+        (handle, transfer)
+    }
+
+    /// Sends what today's limits allow, then waits for tomorrow and does it again until nothing
+    /// is left
+    fn continue_split(
+        &self,
+        handle: SplitHandle,
+        timeout: u64,
+    ) -> Box<dyn Future<Item = SplitProgress, Error = Error>> {
+        let progress = handle.progress();
+        if progress.is_complete() {
+            return Box::new(futures::future::ok(progress));
+        }
+        let operation = progress.operation;
+        let limits = match operation {
+            Operation::DaiToXdaiDeposit => self.get_deposit_limits(),
+            _ => self.get_withdrawal_limits(),
+        };
+        let salf = self.clone();
+
+        Box::new(
+            limits
+                .and_then(move |limits| split_amount(&progress.remaining(), &limits))
+                .and_then(move |chunks| {
+                    if chunks.is_empty() {
+                        let resumes_at = next_day_start(unix_now());
+                        handle.progress.lock().unwrap().resumes_at = Some(resumes_at);
+                        info!(
+                            "Bridge's daily limit reached, sending the rest of the {:?} after {}",
+                            operation, resumes_at
+                        );
+                        let wait = Duration::from_secs(resumes_at.saturating_sub(unix_now()))
+                            + DAY_ROLLOVER_MARGIN;
+                        return Box::new(
+                            Delay::new(wait)
+                                .from_err()
+                                .and_then(move |_| salf.continue_split(handle, timeout)),
+                        )
+                            as Box<dyn Future<Item = SplitProgress, Error = Error>>;
+                    }
+
+                    handle.progress.lock().unwrap().resumes_at = None;
+                    Box::new(
+                        stream::iter_ok(chunks)
+                            .for_each({
+                                let salf = salf.clone();
+                                let handle = handle.clone();
+                                move |chunk| {
+                                    let handle = handle.clone();
+                                    salf.send_chunk(operation, chunk, timeout)
+                                        .map(move |chunk| {
+                                            let mut progress = handle.progress.lock().unwrap();
+                                            progress.sent = progress.sent.clone() + chunk.clone();
+                                            progress.chunks.push(chunk);
+                                            info!(
+                                                "Sent {} of {} in {} chunks",
+                                                progress.sent,
+                                                progress.total,
+                                                progress.chunks.len()
+                                            );
+                                        })
+                                }
+                            })
+                            .and_then(move |_| salf.continue_split(handle, timeout)),
+                    )
+                }),
+        )
+    }
+
+    /// Sends one chunk and waits for it to be mined, so the next chunk doesn't race it for the
+    /// account's nonce
+    fn send_chunk(
+        &self,
+        operation: Operation,
+        chunk: Uint256,
+        timeout: u64,
+    ) -> Box<dyn Future<Item = Uint256, Error = Error>> {
+        match operation {
+            Operation::DaiToXdaiDeposit => {
+                self.dai_to_xdai_bridge(DaiAmount::from_wei(chunk), timeout)
+            }
+            _ => Box::new(
+                self.xdai_to_dai_bridge(XdaiAmount::from_wei(chunk.clone()), timeout)
+                    .map(move |_| chunk),
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(min: u64, max: u64, remaining: u64) -> BridgeLimits {
+        BridgeLimits {
+            min_per_tx: min.into(),
+            max_per_tx: max.into(),
+            remaining_today: remaining.into(),
+        }
+    }
+
+    fn chunks(amount: u64, limits: &BridgeLimits) -> Vec<u64> {
+        split_amount(&amount.into(), limits)
+            .unwrap()
+            .iter()
+            .map(|chunk| chunk.to_string().parse().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_split_amount() {
+        let plenty = limits(10, 100, 10_000);
+        assert_eq!(chunks(50, &plenty), vec![50]);
+        assert_eq!(chunks(100, &plenty), vec![100]);
+        // Even chunks rather than 100 and 1
+        assert_eq!(chunks(101, &plenty), vec![51, 50]);
+        assert_eq!(chunks(250, &plenty), vec![84, 83, 83]);
+        assert!(split_amount(&9u64.into(), &plenty).is_err());
+
+        // Only 150 left today, one chunk goes now and the rest on later days
+        assert_eq!(chunks(500, &limits(10, 100, 150)), vec![100]);
+        assert_eq!(chunks(155, &limits(10, 100, 150)), vec![78]);
+        // Nothing fits under today's limit
+        assert!(chunks(500, &limits(10, 100, 5)).is_empty());
+        // Two chunks of 55 are too small and one of 110 too large, nothing is sent
+        assert!(split_amount(&110u64.into(), &limits(60, 100, 1000)).is_err());
+        assert!(split_amount(&110u64.into(), &limits(60, 100, 100)).is_err());
+    }
+
+    #[test]
+    fn test_next_day_start() {
+        assert_eq!(next_day_start(0), DAY_SECONDS);
+        assert_eq!(next_day_start(DAY_SECONDS - 1), DAY_SECONDS);
+        assert_eq!(next_day_start(DAY_SECONDS), 2 * DAY_SECONDS);
+    }
+}
@@ -0,0 +1,514 @@
+//! ERC20 tokens other than Dai. Dai is still the only token the xDai bridge moves, but balances,
+//! allowances, approvals and Uniswap swaps work the same way for any token with a Uniswap
+//! exchange. The Dai specific checks (price impact, reference price and TWAP) only apply to Dai.
+
+use crate::allowance::AllowancePolicy;
+use crate::amount::{DaiAmount, EthAmount};
+use crate::journal::{JournalRecorder, Operation};
+use crate::limits::Asset;
+use crate::price::SwapDirection;
+use crate::TokenBridge;
+use clarity::abi::encode_call;
+use clarity::Address;
+use failure::bail;
+use failure::Error;
+use futures::Future;
+use futures_timer::FutureExt;
+use num256::Uint256;
+use std::time::Duration;
+use web30::types::SendTxOption;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Token {
+    pub address: Address,
+    pub symbol: String,
+    pub decimals: u8,
+    /// The Uniswap exchange for this token, it can't be swapped without one
+    #[serde(default)]
+    pub uniswap_address: Option<Address>,
+    /// The bridged copy of this token on xDai, it can't be bridged back without one
+    #[serde(default)]
+    pub xdai_address: Option<Address>,
+    /// How much of this token its Uniswap exchange is approved to spend, unlimited if not set
+    #[serde(default)]
+    pub uniswap_allowance: AllowancePolicy,
+}
+
+impl TokenBridge {
+    /// Dai as configured on the bridge
+    pub fn dai_token(&self) -> Token {
+        Token {
+            address: self.foreign_dai_contract_address,
+            symbol: "DAI".to_string(),
+            decimals: 18,
+            uniswap_address: Some(self.uniswap_address),
+            xdai_address: None,
+            uniswap_allowance: self.uniswap_allowance.clone(),
+        }
+    }
+
+    /// Looks a token up by symbol among Dai and the configured `tokens`, ignoring case
+    pub fn token(&self, symbol: &str) -> Option<Token> {
+        let dai = self.dai_token();
+        if dai.symbol.eq_ignore_ascii_case(symbol) {
+            return Some(dai);
+        }
+        self.tokens
+            .iter()
+            .find(|token| token.symbol.eq_ignore_ascii_case(symbol))
+            .cloned()
+    }
+
+    fn is_dai(&self, token: &Token) -> bool {
+        token.address == self.foreign_dai_contract_address
+    }
+
+    fn record_token(
+        &self,
+        operation: Operation,
+        amount: Uint256,
+        token: &Token,
+    ) -> JournalRecorder {
+        let token = if self.is_dai(token) {
+            None
+        } else {
+            Some(token.address)
+        };
+        JournalRecorder::new(self.journal.clone(), operation, amount, None, token)
+            .with_events(self.events.clone())
+    }
+
+    pub fn get_token_balance(
+        &self,
+        token: &Token,
+        address: Address,
+    ) -> Box<dyn Future<Item = Uint256, Error = Error>> {
+        let symbol = token.symbol.clone();
+
+        Box::new(
+            self.read_call(
+                &self.eth_web3,
+                token.address,
+                "balanceOf(address)",
+                &[address.into()],
+            )
+            .and_then(move |balance| {
+                Ok(Uint256::from_bytes_be(match balance.get(0..32) {
+                    Some(val) => val,
+                    None => bail!(
+                        "Got bad output for {} balance from the full node {:?}",
+                        symbol,
+                        balance
+                    ),
+                }))
+            }),
+        )
+    }
+
+    /// How much of `token` `spender` is allowed to move out of our account
+    pub fn get_token_allowance(
+        &self,
+        token: &Token,
+        spender: Address,
+    ) -> Box<dyn Future<Item = Uint256, Error = Error>> {
+        let symbol = token.symbol.clone();
+
+        Box::new(
+            self.read_call(
+                &self.eth_web3,
+                token.address,
+                "allowance(address,address)",
+                &[self.own_address.into(), spender.into()],
+            )
+            .and_then(move |allowance| {
+                Ok(Uint256::from_bytes_be(match allowance.get(0..32) {
+                    Some(val) => val,
+                    None => bail!(
+                        "Malformed output from {} allowance call {:?}",
+                        symbol,
+                        allowance
+                    ),
+                }))
+            }),
+        )
+    }
+
+    /// Sets the allowance of `spender` to exactly `amount`
+    pub fn approve_token_transfers(
+        &self,
+        token: &Token,
+        spender: Address,
+        amount: Uint256,
+        timeout: Duration,
+    ) -> Box<dyn Future<Item = (), Error = Error>> {
+        self.set_token_allowance(token, Operation::ApproveToken, spender, amount, timeout)
+    }
+
+    /// Sets the allowance of `spender` to `amount`. Some tokens refuse to change an allowance
+    /// from one non zero value to another, so a non zero allowance is set to zero first.
+    pub(crate) fn set_token_allowance(
+        &self,
+        token: &Token,
+        operation: Operation,
+        spender: Address,
+        amount: Uint256,
+        timeout: Duration,
+    ) -> Box<dyn Future<Item = (), Error = Error>> {
+        let salf = self.clone();
+        let token = token.clone();
+
+        Box::new(
+            self.get_token_allowance(&token, spender)
+                .and_then(move |allowance| {
+                    let zero: Uint256 = 0u8.into();
+                    if allowance == amount {
+                        Box::new(futures::future::ok(()))
+                            as Box<dyn Future<Item = (), Error = Error>>
+                    } else if allowance == zero || amount == zero {
+                        salf.send_approval(&token, operation, spender, amount, timeout)
+                    } else {
+                        Box::new(
+                            salf.send_approval(&token, operation, spender, zero, timeout)
+                                .and_then(move |_| {
+                                    salf.send_approval(&token, operation, spender, amount, timeout)
+                                }),
+                        )
+                    }
+                }),
+        )
+    }
+
+    /// Sends a single approval and waits for it to be mined, failing if it reverted. Approvals
+    /// of zero only take access away, so they are still sent once the circuit breaker has
+    /// tripped.
+    pub(crate) fn send_approval(
+        &self,
+        token: &Token,
+        operation: Operation,
+        spender: Address,
+        amount: Uint256,
+        timeout: Duration,
+    ) -> Box<dyn Future<Item = (), Error = Error>> {
+        if amount != 0u8.into() {
+            if let Err(e) = self.check_circuit_breaker() {
+                return self.refuse_operation(operation, e);
+            }
+        }
+        let token_address = token.address;
+        let own_address = self.own_address;
+        let web3 = self.eth_web3.clone();
+        let record = self.record_token(operation, amount.clone(), token);
+
+        let payload = encode_call(
+            "approve(address,uint256)",
+            &[spender.into(), amount.clone().into()],
+        );
+
+        let approval = web3
+            .send_transaction(
+                token_address,
+                payload,
+                0u32.into(),
+                own_address,
+                self.secret,
+                vec![],
+            )
+            .and_then({
+                let salf = self.clone();
+                let record = record.clone();
+                move |tx_hash| {
+                    record.submitted(tx_hash.clone());
+                    salf.wait_for(&web3, tx_hash.clone())
+                        .timeout(timeout)
+                        .and_then(move |_| {
+                            salf.retry_read("eth_getTransactionReceipt", move || {
+                                web3.eth_get_transaction_receipt(tx_hash.clone())
+                            })
+                        })
+                }
+            })
+            .and_then(move |receipt| match receipt {
+                Some(ref receipt) if receipt.status == Some(0u8.into()) => bail!(
+                    "Approval transaction {:?} reverted",
+                    receipt.transaction_hash
+                ),
+                Some(_) => Ok(amount),
+                None => bail!("No receipt for mined approval transaction"),
+            });
+
+        Box::new(
+            self.finish_record(self.eth_web3.clone(), record, approval)
+                .map(|_| ()),
+        )
+    }
+
+    /// Price of `amount` ETH in `token`
+    pub fn eth_to_token_price(
+        &self,
+        token: &Token,
+        amount: EthAmount,
+    ) -> Box<dyn Future<Item = Uint256, Error = Error>> {
+        self.uniswap_price(token, "getEthToTokenInputPrice(uint256)", amount.into_wei())
+    }
+
+    /// Price of `amount` of `token` in ETH
+    pub fn token_to_eth_price(
+        &self,
+        token: &Token,
+        amount: Uint256,
+    ) -> Box<dyn Future<Item = Uint256, Error = Error>> {
+        self.uniswap_price(token, "getTokenToEthInputPrice(uint256)", amount)
+    }
+
+    fn uniswap_price(
+        &self,
+        token: &Token,
+        call: &'static str,
+        amount: Uint256,
+    ) -> Box<dyn Future<Item = Uint256, Error = Error>> {
+        let exchange = match uniswap_exchange(token) {
+            Ok(exchange) => exchange,
+            Err(e) => return Box::new(futures::future::err(e)),
+        };
+
+        Box::new(
+            self.read_call(&self.eth_web3, exchange, call, &[amount.into()])
+                .and_then(move |output| {
+                    Ok(Uint256::from_bytes_be(match output.get(0..32) {
+                        Some(val) => val,
+                        None => bail!("Malformed output from uniswap {} call {:?}", call, output),
+                    }))
+                }),
+        )
+    }
+
+    /// Sell `eth_amount` ETH for `token`, returns how much of the token was bought. For Dai this
+    /// is `eth_to_dai_swap`.
+    pub fn eth_to_token_swap(
+        &self,
+        token: &Token,
+        eth_amount: EthAmount,
+        timeout: u64,
+    ) -> Box<dyn Future<Item = Uint256, Error = Error>> {
+        if self.is_dai(token) {
+            return self.eth_to_dai_swap(eth_amount, timeout);
+        }
+        let eth_amount = eth_amount.into_wei();
+        let exchange = match uniswap_exchange(token) {
+            Ok(exchange) => exchange,
+            Err(e) => return self.refuse_operation(Operation::EthToTokenSwap, e),
+        };
+        if let Err(e) = self.authorize_spend(Asset::Eth, &eth_amount) {
+            return self.refuse_operation(Operation::EthToTokenSwap, e);
+        }
+        let salf = self.clone();
+        let record = self.record_token(Operation::EthToTokenSwap, eth_amount.clone(), token);
+
+        let swap = self
+            .read_latest_block(&self.eth_web3)
+            .join(self.eth_to_token_price(token, EthAmount::from_wei(eth_amount.clone())))
+            .and_then({
+                let record = record.clone();
+                move |(block, expected)| {
+                    record.quoted(expected.clone());
+                    let payload = encode_call(
+                        "ethToTokenSwapInput(uint256,uint256)",
+                        &[
+                            minimum_output(expected).into(),
+                            (block.timestamp + timeout.into()).into(),
+                        ],
+                    );
+                    salf.send_swap(
+                        SwapDirection::EthToToken,
+                        exchange,
+                        payload,
+                        eth_amount,
+                        timeout,
+                        record,
+                    )
+                }
+            });
+
+        self.finish_record(self.eth_web3.clone(), record, swap)
+    }
+
+    /// Sell `amount` of `token` for ETH, returns how much ETH was bought. Uniswap is approved
+    /// to move the token according to the token's `uniswap_allowance` first if it needs to be. For Dai this
+    /// is `dai_to_eth_swap`.
+    pub fn token_to_eth_swap(
+        &self,
+        token: &Token,
+        amount: Uint256,
+        timeout: u64,
+    ) -> Box<dyn Future<Item = Uint256, Error = Error>> {
+        if self.is_dai(token) {
+            return self.dai_to_eth_swap(DaiAmount::from_wei(amount), timeout);
+        }
+        let exchange = match uniswap_exchange(token) {
+            Ok(exchange) => exchange,
+            Err(e) => return self.refuse_operation(Operation::TokenToEthSwap, e),
+        };
+        if let Err(e) = self.authorize_spend(Asset::Token(token.address), &amount) {
+            return self.refuse_operation(Operation::TokenToEthSwap, e);
+        }
+        let salf = self.clone();
+        let token = token.clone();
+        let record = self.record_token(Operation::TokenToEthSwap, amount.clone(), &token);
+
+        let swap = self
+            .ensure_token_allowance(&token, exchange, amount.clone(), Duration::from_secs(600))
+            .and_then({
+                let salf = self.clone();
+                let amount = amount.clone();
+                move |_| {
+                    salf.read_latest_block(&salf.eth_web3)
+                        .join(salf.token_to_eth_price(&token, amount))
+                }
+            })
+            .and_then({
+                let record = record.clone();
+                move |(block, expected)| {
+                    record.quoted(expected.clone());
+                    let payload = encode_call(
+                        "tokenToEthSwapInput(uint256,uint256,uint256)",
+                        &[
+                            amount.into(),
+                            minimum_output(expected).into(),
+                            (block.timestamp + timeout.into()).into(),
+                        ],
+                    );
+                    salf.send_swap(
+                        SwapDirection::TokenToEth,
+                        exchange,
+                        payload,
+                        0u8.into(),
+                        timeout,
+                        record,
+                    )
+                }
+            });
+
+        self.finish_record(self.eth_web3.clone(), record, swap)
+    }
+
+    /// Makes sure `spender` can move `amount` of `token`, approving more according to the
+    /// token's `uniswap_allowance` if it can't
+    pub(crate) fn ensure_token_allowance(
+        &self,
+        token: &Token,
+        spender: Address,
+        amount: Uint256,
+        timeout: Duration,
+    ) -> Box<dyn Future<Item = (), Error = Error>> {
+        let target = match token.uniswap_allowance.allowance_for(&amount) {
+            Ok(target) => target,
+            Err(e) => return Box::new(futures::future::err(e)),
+        };
+        let salf = self.clone();
+        let token = token.clone();
+
+        Box::new(
+            self.get_token_allowance(&token, spender)
+                .and_then(move |allowance| {
+                    if allowance >= amount {
+                        Box::new(futures::future::ok(()))
+                            as Box<dyn Future<Item = (), Error = Error>>
+                    } else {
+                        salf.set_token_allowance(
+                            &token,
+                            Operation::ApproveToken,
+                            spender,
+                            target,
+                            timeout,
+                        )
+                    }
+                }),
+        )
+    }
+
+    fn send_swap(
+        &self,
+        direction: SwapDirection,
+        exchange: Address,
+        payload: Vec<u8>,
+        value: Uint256,
+        timeout: u64,
+        record: JournalRecorder,
+    ) -> Box<dyn Future<Item = Uint256, Error = Error>> {
+        let salf = self.clone();
+
+        Box::new(
+            self.eth_web3
+                .send_transaction(
+                    exchange,
+                    payload,
+                    value,
+                    self.own_address,
+                    self.secret,
+                    vec![SendTxOption::GasLimit(150_000u64.into())],
+                )
+                .and_then(move |tx_hash| {
+                    record.submitted(tx_hash.clone());
+                    salf.wait_for_exchange_swap(direction, exchange, tx_hash, timeout)
+                })
+                .map(|result| result.bought),
+        )
+    }
+}
+
+fn uniswap_exchange(token: &Token) -> Result<Address, Error> {
+    match token.uniswap_address {
+        Some(exchange) => Ok(exchange),
+        None => bail!("{} has no Uniswap exchange configured", token.symbol),
+    }
+}
+
+/// Equivalent to `amount * (1 - 0.025)` without using decimals
+fn minimum_output(expected: Uint256) -> Uint256 {
+    (expected / 40u64.into()) * 39u64.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clarity::PrivateKey;
+    use std::str::FromStr;
+
+    #[test]
+    fn test_token_lookup() {
+        let dai = Address::from_str("0x89d24A6b4CcB1B6fAA2625fE562bDD9a23260359").unwrap();
+        let usdc = Address::from_str("0xA0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48").unwrap();
+        let mut bridge = TokenBridge::new(
+            Address::from_str("0x09cabEC1eAd1c0Ba254B09efb3EE13841712bE14").unwrap(),
+            Address::from_str("0x7301CFA0e1756B71869E93d4e4Dca5c7d0eb0AA6").unwrap(),
+            Address::from_str("0x4aa42145Aa6Ebf72e164C9bBC74fbD3788045016").unwrap(),
+            dai,
+            Address::from_str("0x79AE13432950bF5CDC3499f8d4Cf5963c3F0d42c").unwrap(),
+            PrivateKey::from_str(&"11".repeat(32)).unwrap(),
+            "http://localhost:8545".to_string(),
+            "http://localhost:8546".to_string(),
+        );
+        bridge.tokens.push(Token {
+            address: usdc,
+            symbol: "USDC".to_string(),
+            decimals: 6,
+            uniswap_address: None,
+            xdai_address: None,
+            uniswap_allowance: AllowancePolicy::Exact,
+        });
+
+        assert_eq!(bridge.token("dai").unwrap().address, dai);
+        assert_eq!(bridge.token("usdc").unwrap().decimals, 6);
+        assert_eq!(
+            bridge.token("usdc").unwrap().uniswap_allowance,
+            AllowancePolicy::Exact
+        );
+        assert_eq!(
+            bridge.token("dai").unwrap().uniswap_allowance,
+            AllowancePolicy::Unlimited
+        );
+        assert_eq!(bridge.token("USDT"), None);
+        assert!(uniswap_exchange(&bridge.token("USDC").unwrap()).is_err());
+        assert_eq!(minimum_output(4000u64.into()), 3900u64.into());
+    }
+}
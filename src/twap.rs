@@ -0,0 +1,351 @@
+//! Keeps a bounded history of Uniswap quotes so decisions can be made on a time weighted average
+//! price instead of a single, possibly noisy, quote.
+
+use crate::amount::EthAmount;
+use crate::metrics::FailureReason;
+use crate::price::{quote_deviation, SwapDirection, PRICE_SCALE};
+use crate::TokenBridge;
+use failure::bail;
+use failure::Error;
+use futures::{Future, Stream};
+use futures_timer::Interval;
+use num256::Uint256;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PriceSample {
+    pub block_number: Uint256,
+    /// Timestamp of the block the quote was taken at
+    pub timestamp: u64,
+    /// Dai per ETH, scaled by `PRICE_SCALE`
+    pub price: Uint256,
+}
+
+/// The most recent price samples, shared between the sampler and whatever reads them
+#[derive(Clone)]
+pub struct PriceHistory {
+    samples: Arc<Mutex<VecDeque<PriceSample>>>,
+    capacity: usize,
+}
+
+impl PriceHistory {
+    /// Keeps at most `capacity` samples, dropping the oldest first
+    pub fn new(capacity: usize) -> PriceHistory {
+        PriceHistory {
+            samples: Arc::new(Mutex::new(VecDeque::with_capacity(capacity))),
+            capacity,
+        }
+    }
+
+    pub fn record(&self, sample: PriceSample) {
+        let mut samples = self.samples.lock().unwrap();
+        // The same block can be sampled twice if blocks are slower than the sample interval
+        if let Some(last) = samples.back() {
+            if last.block_number >= sample.block_number {
+                return;
+            }
+        }
+        if samples.len() >= self.capacity {
+            samples.pop_front();
+        }
+        samples.push_back(sample);
+    }
+
+    /// Every sample in the history, oldest first
+    pub fn samples(&self) -> Vec<PriceSample> {
+        self.samples.lock().unwrap().iter().cloned().collect()
+    }
+
+    pub fn latest(&self) -> Option<PriceSample> {
+        self.samples.lock().unwrap().back().cloned()
+    }
+
+    /// Samples taken within `window` seconds of the latest one
+    pub fn window(&self, window: Duration) -> Vec<PriceSample> {
+        let samples = self.samples.lock().unwrap();
+        let latest = match samples.back() {
+            Some(latest) => latest.timestamp,
+            None => return Vec::new(),
+        };
+        let start = latest.saturating_sub(window.as_secs());
+        samples
+            .iter()
+            .filter(|sample| sample.timestamp >= start)
+            .cloned()
+            .collect()
+    }
+
+    /// Time weighted average price over the window, each sample counts for as long as it was
+    /// the latest price. `None` if there are no samples in the window.
+    pub fn twap(&self, window: Duration) -> Option<Uint256> {
+        let samples = self.window(window);
+        let first = samples.first()?;
+        let last = samples.last()?;
+        let total_time = last.timestamp - first.timestamp;
+        if total_time == 0 {
+            return Some(last.price.clone());
+        }
+
+        let weighted = samples
+            .windows(2)
+            .fold(0u8.into(), |weighted: Uint256, pair| {
+                let duration = pair[1].timestamp - pair[0].timestamp;
+                weighted + pair[0].price.clone() * duration.into()
+            });
+        Some(weighted / total_time.into())
+    }
+
+    /// Standard deviation of the relative change between consecutive samples in the window, as
+    /// a fraction. `None` if there are fewer than two changes to compare.
+    pub fn volatility(&self, window: Duration) -> Option<f64> {
+        let prices: Vec<f64> = self
+            .window(window)
+            .iter()
+            .filter_map(|sample| sample.price.to_string().parse().ok())
+            .collect();
+        let returns: Vec<f64> = prices
+            .windows(2)
+            .filter(|pair| pair[0] > 0.0)
+            .map(|pair| pair[1] / pair[0] - 1.0)
+            .collect();
+        if returns.len() < 2 {
+            return None;
+        }
+
+        let mean = returns.iter().sum::<f64>() / returns.len() as f64;
+        let variance =
+            returns.iter().map(|r| (r - mean).powi(2)).sum::<f64>() / (returns.len() - 1) as f64;
+        Some(variance.sqrt())
+    }
+}
+
+/// Refuse swaps whose quote is too far from the recent time weighted average price
+#[derive(Clone)]
+pub struct TwapCheck {
+    pub history: PriceHistory,
+    pub window: Duration,
+    /// In basis points, either direction
+    pub max_deviation: u64,
+}
+
+impl TwapCheck {
+    /// Fails if a quote of `quote` for selling `amount` is too far from the TWAP, or if the
+    /// newest sample is more than `window` older than `now`, a unix timestamp. A sampler that
+    /// stopped would otherwise leave swaps checked against however old a price it last saw.
+    pub fn check(
+        &self,
+        direction: SwapDirection,
+        amount: &Uint256,
+        quote: &Uint256,
+        now: u64,
+    ) -> Result<(), Error> {
+        let latest = match self.history.latest() {
+            Some(latest) => latest,
+            None => bail!("No price samples to check the quote against"),
+        };
+        let age = now.saturating_sub(latest.timestamp);
+        if age > self.window.as_secs() {
+            bail!(
+                "The newest price sample is {}s old, older than the {}s TWAP window",
+                age,
+                self.window.as_secs()
+            );
+        }
+        let twap = match self.history.twap(self.window) {
+            Some(twap) => twap,
+            None => bail!("No price samples to check the quote against"),
+        };
+
+        let deviation = quote_deviation(direction, amount, quote, &twap)?;
+        if deviation > self.max_deviation {
+            bail!(
+                "Uniswap quote is {}% away from the time weighted average price, the limit is {}%",
+                deviation as f64 / 100.0,
+                self.max_deviation as f64 / 100.0
+            );
+        }
+        Ok(())
+    }
+}
+
+/// A `TwapCheck` as it is set in the config file. Its history is filled by sampling the price
+/// while the scheduler runs, so the check is only useful there.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TwapConfig {
+    /// Seconds of samples the average is taken over
+    pub window: u64,
+    /// In basis points, either direction
+    pub max_deviation: u64,
+    /// Seconds between samples
+    pub sample_interval: u64,
+    /// Wei of ETH each sample quotes the price of selling
+    pub sample_amount: Uint256,
+}
+
+impl TwapConfig {
+    /// A check with an empty history big enough to hold a full window of samples
+    pub fn check(&self) -> TwapCheck {
+        let capacity = self.window / self.sample_interval.max(1) + 1;
+        TwapCheck {
+            history: PriceHistory::new(capacity as usize),
+            window: Duration::from_secs(self.window),
+            max_deviation: self.max_deviation,
+        }
+    }
+}
+
+impl TokenBridge {
+    /// Takes one sample of the price of selling `eth_amount` ETH for Dai and adds it to `history`
+    pub fn sample_price(
+        &self,
+        history: &PriceHistory,
+        eth_amount: Uint256,
+    ) -> Box<dyn Future<Item = PriceSample, Error = Error>> {
+        if eth_amount == 0u8.into() {
+            return Box::new(futures::future::err(failure::err_msg(
+                "Can't sample the price of selling nothing",
+            )));
+        }
+        let history = history.clone();
+
+        Box::new(
+            self.read_latest_block(&self.eth_web3)
+                .join(self.eth_to_dai_price(EthAmount::from_wei(eth_amount.clone())))
+                .and_then(move |(block, dai)| {
+                    let timestamp = match block.timestamp.to_string().parse() {
+                        Ok(timestamp) => timestamp,
+                        Err(_) => bail!("Block timestamp out of range {}", block.timestamp),
+                    };
+                    let sample = PriceSample {
+                        block_number: block.number,
+                        timestamp,
+                        price: dai * PRICE_SCALE.into() / eth_amount,
+                    };
+                    history.record(sample.clone());
+                    Ok(sample)
+                }),
+        )
+    }
+
+    /// Samples the price every `interval` forever. Failed samples are logged and skipped so a
+    /// flaky node only leaves gaps in the history.
+    pub fn run_price_sampler(
+        &self,
+        history: PriceHistory,
+        interval: Duration,
+        eth_amount: Uint256,
+    ) -> Box<dyn Future<Item = (), Error = Error>> {
+        let salf = self.clone();
+
+        Box::new(
+            Interval::new(interval)
+                .map_err(Error::from)
+                .for_each(move |_| {
+                    salf.sample_price(&history, eth_amount.clone()).then(|res| {
+                        if let Err(e) = res {
+                            warn!("Failed to sample the Uniswap price {:?}", e);
+                        }
+                        Ok(())
+                    })
+                }),
+        )
+    }
+
+    /// Fails if a quote of `quote` for selling `amount` is too far from the TWAP, does nothing
+    /// if there is no TWAP check configured
+    pub(crate) fn check_twap(
+        &self,
+        direction: SwapDirection,
+        amount: &Uint256,
+        quote: &Uint256,
+    ) -> Result<(), Error> {
+        let check = match self.twap_check {
+            Some(ref check) => check,
+            None => return Ok(()),
+        };
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        check
+            .check(direction, amount, quote, now)
+            .map_err(|e| FailureReason::PriceCheck.refuse(e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(block_number: u64, timestamp: u64, price: u64) -> PriceSample {
+        PriceSample {
+            block_number: block_number.into(),
+            timestamp,
+            price: price.into(),
+        }
+    }
+
+    #[test]
+    fn test_twap() {
+        let history = PriceHistory::new(4);
+        assert_eq!(history.twap(Duration::from_secs(60)), None);
+
+        history.record(sample(1, 0, 1000));
+        history.record(sample(2, 10, 100));
+        history.record(sample(3, 40, 200));
+        // Already have this block
+        history.record(sample(3, 40, 5000));
+        history.record(sample(4, 50, 300));
+        history.record(sample(5, 60, 400));
+
+        // The first sample fell out of the history
+        assert_eq!(history.samples().len(), 4);
+        assert_eq!(history.samples()[0], sample(2, 10, 100));
+
+        // 100 for 30 seconds, 200 for 10 and 300 for 10
+        assert_eq!(history.twap(Duration::from_secs(50)), Some(160u64.into()));
+        // Only the last two samples
+        assert_eq!(history.twap(Duration::from_secs(10)), Some(300u64.into()));
+        assert_eq!(history.twap(Duration::from_secs(0)), Some(400u64.into()));
+    }
+
+    #[test]
+    fn test_stale_twap() {
+        let check = TwapConfig {
+            window: 60,
+            max_deviation: 100,
+            sample_interval: 10,
+            sample_amount: PRICE_SCALE.into(),
+        }
+        .check();
+        let amount: Uint256 = PRICE_SCALE.into();
+        let quote: Uint256 = 200u64.into();
+        assert!(check
+            .check(SwapDirection::EthToDai, &amount, &quote, 1000)
+            .is_err());
+
+        check.history.record(sample(1, 900, 200));
+        check.history.record(sample(2, 930, 200));
+        // The sampler stopped, the newest sample is older than the window
+        assert!(check
+            .check(SwapDirection::EthToDai, &amount, &quote, 1000)
+            .is_err());
+        assert!(check
+            .check(SwapDirection::EthToDai, &amount, &quote, 990)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_volatility() {
+        let history = PriceHistory::new(10);
+        history.record(sample(1, 0, 100));
+        history.record(sample(2, 10, 110));
+        assert_eq!(history.volatility(Duration::from_secs(60)), None);
+
+        history.record(sample(3, 20, 121));
+        // Two identical 10% moves
+        assert!(history.volatility(Duration::from_secs(60)).unwrap() < 1e-9);
+
+        history.record(sample(4, 30, 100));
+        assert!(history.volatility(Duration::from_secs(60)).unwrap() > 0.1);
+    }
+}